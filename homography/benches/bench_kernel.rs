@@ -25,5 +25,106 @@ pub fn criterion_benchmark(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, criterion_benchmark);
+pub fn residuals_benchmark(c: &mut Criterion) {
+    use homography::{FeatureMatchSoa, HomographyMatrix};
+    use sample_consensus::Model;
+
+    let TestData { matches, h } = TestData::with_outliers(50_000, 0.5);
+    let h = HomographyMatrix(h);
+    let soa = FeatureMatchSoa::from(&matches[..]);
+
+    let mut group = c.benchmark_group("count_inliers");
+    group.throughput(Throughput::Elements(matches.len() as u64));
+    group.bench_function("scalar", |b| {
+        b.iter(|| matches.iter().filter(|m| h.residual(*m) < 0.1).count())
+    });
+    group.bench_function("soa", |b| b.iter(|| soa.count_inliers(&h, 0.1)));
+    group.finish();
+}
+
+#[cfg(feature = "ransac")]
+pub fn ransac_benchmark(c: &mut Criterion) {
+    use homography::{FeatureMatchSoa, HomographyEstimator, Ransac};
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+
+    let mut group = c.benchmark_group("ransac");
+    group.sample_size(10);
+    for matches in [1_000, 10_000, 100_000].iter() {
+        let TestData { matches: data, .. } = TestData::with_outliers(*matches, 0.5);
+        group.throughput(Throughput::Elements(*matches as u64));
+        group.bench_with_input(BenchmarkId::new("slice", matches), &data, |b, data| {
+            b.iter(|| {
+                Ransac::new(0.1, Pcg64::seed_from_u64(1))
+                    .model_inliers_slice(&HomographyEstimator::default(), data)
+                    .unwrap()
+            });
+        });
+        let soa = FeatureMatchSoa::from(&data[..]);
+        group.bench_with_input(BenchmarkId::new("soa", matches), &soa, |b, soa| {
+            b.iter(|| {
+                Ransac::new(0.1, Pcg64::seed_from_u64(1))
+                    .estimate(&HomographyEstimator::default(), soa)
+                    .unwrap()
+            });
+        });
+    }
+    group.finish();
+}
+
+/// Float against fixed-point coordinates of a remap table. Empty without the `image` feature.
+pub fn remap_benchmark(c: &mut Criterion) {
+    #[cfg(feature = "image")]
+    {
+        use homography::{
+            BorderMode, HomographyMatrix, Interpolation, RemapOptions, RemapPrecision, RemapTable,
+        };
+        use image::{GrayImage, Luma};
+        use nalgebra::Matrix3;
+
+        let (width, height) = (1280, 720);
+        let image =
+            GrayImage::from_fn(width, height, |x, y| Luma([((x * 7 + y * 13) % 251) as u8]));
+        #[rustfmt::skip]
+        let h = HomographyMatrix(Matrix3::new(
+            0.9, -0.2, 12.0,
+            0.15, 1.1, -3.0,
+            0.0001, 0.0002, 1.0,
+        ));
+        let mut group = c.benchmark_group("remap");
+        group.throughput(Throughput::Elements(width as u64 * height as u64));
+        for interpolation in [Interpolation::Bilinear, Interpolation::Bicubic] {
+            for precision in [RemapPrecision::Float, RemapPrecision::Fixed] {
+                let options = RemapOptions {
+                    precision,
+                    ..Default::default()
+                };
+                let table = RemapTable::new(&h, width, height, options).unwrap();
+                let id = format!("{:?}/{:?}", interpolation, precision);
+                group.bench_function(id, |b| {
+                    b.iter(|| table.remap(&image, interpolation, BorderMode::Replicate))
+                });
+            }
+        }
+        group.finish();
+    }
+    #[cfg(not(feature = "image"))]
+    let _ = c;
+}
+
+#[cfg(feature = "ransac")]
+criterion_group!(
+    benches,
+    criterion_benchmark,
+    residuals_benchmark,
+    ransac_benchmark,
+    remap_benchmark
+);
+#[cfg(not(feature = "ransac"))]
+criterion_group!(
+    benches,
+    criterion_benchmark,
+    residuals_benchmark,
+    remap_benchmark
+);
 criterion_main!(benches);
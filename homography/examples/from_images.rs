@@ -1,7 +1,6 @@
 use akaze::Akaze;
-use bitarray::{BitArray, Hamming};
-use cv_core::FeatureMatch;
-use space::Knn;
+use bitarray::BitArray;
+use homography::{feature_matches, match_descriptors, MatchingOptions};
 use std::path::Path;
 extern crate clap;
 use clap::{App, Arg};
@@ -52,14 +51,18 @@ fn main() {
         ds1.len(),
         ds2.len()
     );
-    let matches: Vec<_> = match_descriptors(&ds1, &ds2)
-        .into_iter()
-        .map(|(ix1, ix2)| {
-            let a = nalgebra::Point2::new(kps1[ix1].point.0 as f64, kps1[ix1].point.1 as f64);
-            let b = nalgebra::Point2::new(kps2[ix2].point.0 as f64, kps2[ix2].point.1 as f64);
-            FeatureMatch(a, b)
-        })
-        .collect();
+    let options = MatchingOptions {
+        ratio: Some(LOWES_RATIO),
+        ..Default::default()
+    };
+    let descriptor_matches = match_descriptors(&ds1, &ds2, options);
+    let to_points = |kps: &[akaze::KeyPoint]| {
+        kps.iter()
+            .map(|kp| nalgebra::Point2::new(kp.point.0 as f64, kp.point.1 as f64))
+            .collect::<Vec<_>>()
+    };
+    let (points1, points2) = (to_points(&kps1), to_points(&kps2));
+    let matches = feature_matches(&descriptor_matches, &points1, &points2);
     println!("Finished matching with {} matches", matches.len());
 
     // Estimate homography
@@ -67,27 +70,24 @@ fn main() {
         .expect("Failed to find homography transform");
     println!("Result of find_homography_with_arrsac: {}", h.0);
 
+    // Recover the matches rejected by the ratio test around the estimated transform
+    let guided = homography::guided_matching(
+        &points1,
+        &ds1,
+        &points2,
+        &ds2,
+        &h,
+        |a, b| a.distance(b) as f64,
+        homography::GuidedMatchingOptions::default(),
+    )
+    .expect("Guided matching failed");
+    println!(
+        "Guided matching found {} matches in {} iterations: {}",
+        guided.matches.len(),
+        guided.iterations,
+        guided.model.0
+    );
+
     let h = homography::find_homography(matches).expect("Failed to find homography transform");
     println!("Result of find_homography {}", h);
 }
-
-fn match_descriptors(ds1: &[Descriptor], ds2: &[Descriptor]) -> Vec<(usize, usize)> {
-    let two_neighbors = ds1
-        .iter()
-        .map(|d1| {
-            let neighbors = space::LinearKnn {
-                metric: Hamming,
-                iter: ds2.iter(),
-            }
-            .knn(d1, 2);
-            assert_eq!(neighbors.len(), 2, "there should be at least two matches");
-            neighbors
-        })
-        .enumerate();
-    let satisfies_lowes_ratio = two_neighbors.filter(|(_, neighbors)| {
-        (neighbors[0].distance as f32) < neighbors[1].distance as f32 * LOWES_RATIO
-    });
-    satisfies_lowes_ratio
-        .map(|(ix1, neighbors)| (ix1, neighbors[0].index))
-        .collect()
-}
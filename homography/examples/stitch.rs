@@ -0,0 +1,73 @@
+use homography::{stitch_images, Blending, CompositionOptions, StitchOptions};
+extern crate clap;
+use clap::{App, Arg};
+
+fn main() {
+    let matches = App::new("Stitching example")
+        .about("Stitches a sequence of overlapping images into a panorama")
+        .arg(
+            Arg::with_name("images")
+                .help("Paths of the images, in the order they overlap")
+                .required(true)
+                .min_values(2),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .help("Path of the panorama")
+                .default_value("panorama.png")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("blending")
+                .long("blending")
+                .help("How the overlapping images are blended")
+                .possible_values(&["feather", "multi-band"])
+                .default_value("multi-band")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bands")
+                .long("bands")
+                .help("Number of bands of the multi-band blending")
+                .default_value("5")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    pretty_env_logger::init_timed();
+
+    let images = matches
+        .values_of("images")
+        .unwrap()
+        .map(|path| image::open(path).unwrap())
+        .collect::<Vec<_>>();
+    let blending = match matches.value_of("blending").unwrap() {
+        "feather" => Blending::Feather,
+        _ => Blending::MultiBand {
+            bands: matches.value_of("bands").unwrap().parse().unwrap(),
+        },
+    };
+    let options = StitchOptions {
+        composition: CompositionOptions {
+            blending,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    println!("Stitching {} images", images.len());
+    let panorama = stitch_images(&images, options).unwrap();
+    for (ix, transform) in panorama.transforms.iter().enumerate() {
+        println!("Image {} to panorama: {}", ix, transform.0);
+    }
+
+    let output = matches.value_of("output").unwrap();
+    panorama.image.save(output).unwrap();
+    println!(
+        "Saved the {}x{} panorama to {}",
+        panorama.image.width(),
+        panorama.image.height(),
+        output
+    );
+}
@@ -0,0 +1,196 @@
+use cv_core::FeatureMatch;
+use eyre::{eyre, Result};
+use nalgebra::{Matrix3, SMatrix};
+
+use crate::homography::{denormalize, dlt_rows};
+use crate::{NormalizationTransform, WeightedFeatureMatch};
+
+/// Accumulates the 9×9 normal equations (LᵀL) of the DLT.
+///
+/// This is what [`find_homography`](crate::find_homography) uses internally. The normalization
+/// transforms are fixed when the accumulator is created, so matches can be added and removed in
+/// any order, partial sums from different threads can be merged, and the system can be solved at
+/// any time.
+///
+/// ```
+/// # use nalgebra::Point2;
+/// # use cv_core::FeatureMatch;
+/// # use homography::{DltAccumulator, NormalizationTransform};
+/// let norm = NormalizationTransform::from_image_size(10.0, 10.0);
+/// let mut left = DltAccumulator::new(norm, norm);
+/// let mut right = DltAccumulator::new(norm, norm);
+/// left.add(FeatureMatch(Point2::new(0.0, 0.0), Point2::new(0.0, 2.0)));
+/// left.add(FeatureMatch(Point2::new(1.0, 1.0), Point2::new(1.0, 3.0)));
+/// right.add(FeatureMatch(Point2::new(2.0, 4.0), Point2::new(2.0, 6.0)));
+/// right.add(FeatureMatch(Point2::new(7.0, 3.0), Point2::new(7.0, 5.0)));
+///
+/// left.merge(&right).unwrap();
+/// let h = left.solve().unwrap();
+/// assert!((h[(1, 2)] - 2.0).abs() < 1e-9);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct DltAccumulator {
+    norm1: NormalizationTransform,
+    norm2: NormalizationTransform,
+    /// Upper triangle of LᵀL
+    ltl: SMatrix<f64, 9, 9>,
+    count: usize,
+}
+
+impl DltAccumulator {
+    /// Creates an empty accumulator normalizing the source points with `norm1` and
+    /// the destination points with `norm2`.
+    pub fn new(norm1: NormalizationTransform, norm2: NormalizationTransform) -> Self {
+        Self {
+            norm1,
+            norm2,
+            ltl: SMatrix::zeros(),
+            count: 0,
+        }
+    }
+
+    /// Adds a (weighted) match to the system.
+    pub fn add(&mut self, feature_match: impl Into<WeightedFeatureMatch>) {
+        if self.update(feature_match.into(), 1.0) {
+            self.count += 1;
+        }
+    }
+
+    /// Removes a match that was added before.
+    ///
+    /// The match has to be the same (with the same weight) as the added one,
+    /// otherwise the accumulated system becomes meaningless.
+    pub fn remove(&mut self, feature_match: impl Into<WeightedFeatureMatch>) {
+        if self.update(feature_match.into(), -1.0) {
+            self.count = self.count.saturating_sub(1);
+        }
+    }
+
+    /// Adds the matches of `other` to this accumulator.
+    ///
+    /// Fails if the two accumulators use different normalization transforms.
+    pub fn merge(&mut self, other: &DltAccumulator) -> Result<()> {
+        if self.norm1 != other.norm1 || self.norm2 != other.norm2 {
+            return Err(eyre!(
+                "Can't merge accumulators with different normalization transforms"
+            ));
+        }
+        self.ltl += other.ltl;
+        self.count += other.count;
+        Ok(())
+    }
+
+    /// Number of matches in the system.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Solves the accumulated system for the homography.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(matches = self.count))
+    )]
+    pub fn solve(&self) -> Result<Matrix3<f64>> {
+        if self.count < 4 {
+            return Err(eyre!("At least 4 matches are needed, got {}", self.count));
+        }
+
+        let mut ltl = self.ltl;
+        ltl.fill_lower_triangle_with_upper_triangle();
+        let eigen = ltl.symmetric_eigen();
+
+        let (eigen_vector_idx, _) = eigen.eigenvalues.argmin();
+        let h0 = eigen.eigenvectors.column(eigen_vector_idx).clone_owned();
+        Ok(denormalize(h0, &self.norm1, &self.norm2))
+    }
+
+    /// Adds `sign` times the rows of the match to LᵀL. Returns false if the match was skipped.
+    fn update(&mut self, feature_match: WeightedFeatureMatch, sign: f64) -> bool {
+        let WeightedFeatureMatch(FeatureMatch(m1, m2), w) = feature_match;
+        if w <= 0.0 {
+            return false;
+        }
+        let (lx, ly) = dlt_rows(&self.norm1.apply(&m1), &self.norm2.apply(&m2));
+        let w = sign * w;
+        for j in 0..9 {
+            for k in j..9 {
+                self.ltl[(j, k)] += w * (lx[j] * lx[k] + ly[j] * ly[k]);
+            }
+        }
+        true
+    }
+}
+
+impl<M> Extend<M> for DltAccumulator
+where
+    M: Into<WeightedFeatureMatch>,
+{
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "accumulate", level = "trace", skip_all)
+    )]
+    fn extend<T: IntoIterator<Item = M>>(&mut self, iter: T) {
+        for feature_match in iter {
+            self.add(feature_match);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DltAccumulator, NormalizationTransform};
+    use approx::AbsDiffEq;
+    use cv_core::FeatureMatch;
+    use nalgebra::Point2;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+    use test_utils::TestData;
+
+    #[test]
+    fn chunks_add_remove_and_merge() {
+        let mut rng = Pcg64::seed_from_u64(0);
+        for _ in 0..24 {
+            let TestData { matches, h: h_src } = TestData::from_rng(&mut rng, 48, 100.0, 0.0);
+            let norm = NormalizationTransform::from_image_size(100.0, 100.0);
+
+            let mut accumulators = matches
+                .chunks(16)
+                .map(|chunk| {
+                    let mut accumulator = DltAccumulator::new(norm, norm);
+                    accumulator.extend(chunk.iter().copied());
+                    accumulator
+                })
+                .collect::<Vec<_>>();
+            let mut accumulator = accumulators.pop().unwrap();
+            for other in &accumulators {
+                accumulator.merge(other).unwrap();
+            }
+            assert_eq!(accumulator.len(), 48);
+
+            let outlier = FeatureMatch(Point2::new(10.0, 10.0), Point2::new(90.0, -40.0));
+            accumulator.add(outlier);
+            accumulator.remove(outlier);
+
+            let h = accumulator.solve().unwrap();
+            assert!(h_src.abs_diff_eq(&h, 0.000001));
+        }
+    }
+
+    #[test]
+    fn merge_requires_same_normalization() {
+        let mut a = DltAccumulator::new(
+            NormalizationTransform::from_image_size(100.0, 100.0),
+            NormalizationTransform::from_image_size(100.0, 100.0),
+        );
+        let b = DltAccumulator::new(
+            NormalizationTransform::from_image_size(100.0, 100.0),
+            NormalizationTransform::from_image_size(200.0, 100.0),
+        );
+        assert!(a.merge(&b).is_err());
+        assert!(a.solve().is_err());
+    }
+}
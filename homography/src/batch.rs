@@ -0,0 +1,135 @@
+use cv_core::FeatureMatch;
+use eyre::Result;
+use nalgebra::Matrix3;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use crate::{find_homography_iter, HomographyOptions};
+#[cfg(feature = "ransac")]
+use crate::{HomographyEstimator, HomographyMatrix, HomographyWorkspace, Ransac};
+
+type Point2 = nalgebra::Point2<f64>;
+
+/// Estimates a homography for each of the match sets with the DLT.
+///
+/// Runs in parallel with the `rayon` feature. A failing match set doesn't stop the batch,
+/// its error is returned at its position in the result.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(sets = match_sets.len()))
+)]
+pub fn find_homography_batch<S>(
+    match_sets: &[S],
+    options: HomographyOptions,
+) -> Vec<Result<Matrix3<f64>>>
+where
+    S: AsRef<[FeatureMatch<Point2>]> + Sync,
+{
+    let estimate = |matches: &S| find_homography_iter(matches.as_ref().iter().copied(), options);
+    #[cfg(feature = "rayon")]
+    return match_sets.par_iter().map(estimate).collect();
+    #[cfg(not(feature = "rayon"))]
+    return match_sets.iter().map(estimate).collect();
+}
+
+/// Robustly estimates a homography for each of the match sets.
+/// *This is supported on **crate feature `ransac`** only.*
+///
+/// Every set is processed with a [`Ransac`] seeded with `seed` plus the index of the set, then the
+/// model is re-fitted on its inliers. The results don't depend on the number of threads. Workspaces
+/// are shared between the sets processed on the same thread.
+#[cfg(feature = "ransac")]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(sets = match_sets.len()))
+)]
+pub fn find_homography_batch_with_ransac<S>(
+    match_sets: &[S],
+    inlier_threshold: f64,
+    seed: u64,
+    options: HomographyOptions,
+) -> Vec<Result<HomographyMatrix>>
+where
+    S: AsRef<[FeatureMatch<Point2>]> + Sync,
+{
+    use eyre::eyre;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+
+    let estimate = |workspace: &mut HomographyWorkspace, (ix, matches): (usize, &S)| {
+        let matches = matches.as_ref();
+        let rng = Pcg64::seed_from_u64(seed.wrapping_add(ix as u64));
+        let estimator = HomographyEstimator::with_options(options);
+        let (model, _) = Ransac::new(inlier_threshold, rng)
+            .model_inliers_slice(&estimator, matches)
+            .ok_or_else(|| eyre!("Sample consensus failed on match set {}", ix))?;
+        workspace.refit(&model, matches, inlier_threshold)
+    };
+    #[cfg(feature = "rayon")]
+    return match_sets
+        .par_iter()
+        .enumerate()
+        .map_init(|| HomographyWorkspace::new(options), estimate)
+        .collect();
+    #[cfg(not(feature = "rayon"))]
+    {
+        let mut workspace = HomographyWorkspace::new(options);
+        match_sets
+            .iter()
+            .enumerate()
+            .map(|item| estimate(&mut workspace, item))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{find_homography_batch, HomographyOptions};
+    use approx::AbsDiffEq;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+    use test_utils::TestData;
+
+    #[test]
+    fn failures_are_reported_per_set() {
+        let mut rng = Pcg64::seed_from_u64(0);
+        let data = (0..8)
+            .map(|_| TestData::from_rng(&mut rng, 32, 100.0, 0.0))
+            .collect::<Vec<_>>();
+        let mut match_sets = data.iter().map(|d| d.matches.clone()).collect::<Vec<_>>();
+        match_sets[3].truncate(3);
+
+        let results = find_homography_batch(&match_sets, HomographyOptions::default());
+        assert_eq!(results.len(), 8);
+        for (ix, (result, d)) in results.iter().zip(&data).enumerate() {
+            if ix == 3 {
+                assert!(result.is_err());
+            } else {
+                assert!(d.h.abs_diff_eq(result.as_ref().unwrap(), 0.000001));
+            }
+        }
+    }
+
+    #[cfg(feature = "ransac")]
+    #[test]
+    fn robust_batch() {
+        use crate::find_homography_batch_with_ransac;
+
+        let mut rng = Pcg64::seed_from_u64(1);
+        let data = (0..8)
+            .map(|_| TestData::with_outliers_from_rng(&mut rng, 100, 0.3))
+            .collect::<Vec<_>>();
+        let mut match_sets = data.iter().map(|d| d.matches.clone()).collect::<Vec<_>>();
+        match_sets[5].clear();
+
+        let results =
+            find_homography_batch_with_ransac(&match_sets, 0.01, 1, HomographyOptions::default());
+        for (ix, (result, d)) in results.iter().zip(&data).enumerate() {
+            if ix == 5 {
+                assert!(result.is_err());
+            } else {
+                assert!(d.h.abs_diff_eq(&result.as_ref().unwrap().0, 0.000001));
+            }
+        }
+    }
+}
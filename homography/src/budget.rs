@@ -0,0 +1,173 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use sample_consensus::Estimator;
+
+/// Flag to stop a running robust estimation, possibly from another thread.
+///
+/// Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Limits of a robust estimation run. Unlimited by default.
+#[derive(Debug, Clone, Default)]
+pub struct Budget {
+    time_limit: Option<Duration>,
+    max_evaluations: Option<usize>,
+    cancellation: Option<CancellationToken>,
+}
+
+impl Budget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop after the given wall-clock time.
+    pub fn time_limit(self, time_limit: Duration) -> Self {
+        Self {
+            time_limit: Some(time_limit),
+            ..self
+        }
+    }
+
+    /// Stop after evaluating the given number of model hypotheses.
+    pub fn max_evaluations(self, max_evaluations: usize) -> Self {
+        Self {
+            max_evaluations: Some(max_evaluations),
+            ..self
+        }
+    }
+
+    /// Stop when the token gets cancelled.
+    pub fn cancellation(self, cancellation: CancellationToken) -> Self {
+        Self {
+            cancellation: Some(cancellation),
+            ..self
+        }
+    }
+}
+
+/// Result of a robust estimation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RobustEstimate<M> {
+    /// The best model found
+    pub model: M,
+    /// Indices of the inliers of the model
+    pub inliers: Vec<usize>,
+    /// Whether the estimation stopped early because the [`Budget`] ran out
+    pub terminated_early: bool,
+}
+
+/// Tracks the consumption of a [`Budget`] during one estimation run.
+#[derive(Debug)]
+pub(crate) struct BudgetTracker<'a> {
+    budget: &'a Budget,
+    start: Instant,
+    evaluations: AtomicUsize,
+    exhausted: AtomicBool,
+}
+
+impl<'a> BudgetTracker<'a> {
+    pub(crate) fn new(budget: &'a Budget) -> Self {
+        Self {
+            budget,
+            start: Instant::now(),
+            evaluations: AtomicUsize::new(0),
+            exhausted: AtomicBool::new(false),
+        }
+    }
+
+    /// Number of hypotheses that can still be evaluated according to `max_evaluations`.
+    pub(crate) fn remaining_evaluations(&self) -> usize {
+        self.budget.max_evaluations.map_or(usize::MAX, |max| {
+            max.saturating_sub(self.evaluations.load(Ordering::Relaxed))
+        })
+    }
+
+    /// Returns false if the budget ran out, otherwise counts one evaluation.
+    pub(crate) fn try_evaluate(&self) -> bool {
+        let out_of_time = self
+            .budget
+            .time_limit
+            .is_some_and(|limit| self.start.elapsed() >= limit);
+        let cancelled = self
+            .budget
+            .cancellation
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled);
+        if out_of_time || cancelled || self.remaining_evaluations() == 0 {
+            self.mark_exhausted();
+            return false;
+        }
+        self.evaluations.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    pub(crate) fn mark_exhausted(&self) {
+        self.exhausted.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether any limit was hit during the run
+    pub(crate) fn exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+}
+
+/// Wraps an [`Estimator`] so it stops producing hypotheses once the [`Budget`] runs out.
+///
+/// This is how the budget is enforced on sample consensus algorithms from other crates, like
+/// ARRSAC. They still finish scoring the hypotheses produced before that point.
+pub struct BudgetedEstimator<'a, E> {
+    estimator: &'a E,
+    tracker: BudgetTracker<'a>,
+}
+
+impl<'a, E> BudgetedEstimator<'a, E> {
+    /// Starts the clock of the time limit.
+    pub fn new(estimator: &'a E, budget: &'a Budget) -> Self {
+        Self {
+            estimator,
+            tracker: BudgetTracker::new(budget),
+        }
+    }
+
+    /// Whether any hypotheses were refused because the budget ran out.
+    pub fn terminated_early(&self) -> bool {
+        self.tracker.exhausted()
+    }
+}
+
+impl<'a, E, Data> Estimator<Data> for BudgetedEstimator<'a, E>
+where
+    E: Estimator<Data>,
+{
+    type Model = E::Model;
+    type ModelIter = std::iter::Flatten<std::option::IntoIter<E::ModelIter>>;
+    const MIN_SAMPLES: usize = E::MIN_SAMPLES;
+
+    fn estimate<I>(&self, data: I) -> Self::ModelIter
+    where
+        I: Iterator<Item = Data> + Clone,
+    {
+        self.tracker
+            .try_evaluate()
+            .then(|| self.estimator.estimate(data))
+            .into_iter()
+            .flatten()
+    }
+}
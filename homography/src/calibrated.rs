@@ -0,0 +1,272 @@
+use cv_core::{Bearing, CameraModel, FeatureMatch, KeyPoint};
+use eyre::{eyre, Result};
+use nalgebra::{Point2, Vector3};
+use sample_consensus::{Estimator, Model};
+
+use crate::{
+    find_homography_iter, find_homography_with_options, homography::UNPROJECTABLE_RESIDUAL,
+    HomographyMatrix, HomographyOptions,
+};
+
+/// A homography between two calibrated cameras, see [`find_homography_calibrated`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibratedHomography<C1, C2> {
+    /// Maps the normalized coordinates of the first camera to the second one: the bearings
+    /// intersected with the `z = 1` plane
+    pub normalized: HomographyMatrix,
+    /// Maps the pixels of the first image to the second one. Exact for cameras without
+    /// distortion, otherwise the homography closest to [`Self::transfer`] at the matched pixels.
+    /// Only [`CalibratedHomographyEstimator::fit`] computes it, the hypotheses of the consensus
+    /// don't need it; see [`Self::pixel_homography`].
+    pub pixel: Option<HomographyMatrix>,
+    pub camera1: C1,
+    pub camera2: C2,
+}
+
+impl<C1, C2> CalibratedHomography<C1, C2>
+where
+    C1: CameraModel,
+    C2: CameraModel,
+{
+    /// Maps a pixel of the first image to the second one through the lens models of the cameras.
+    /// `None` if the pixel can't be normalized or is mapped to infinity.
+    pub fn transfer(&self, p: &Point2<f64>) -> Option<Point2<f64>> {
+        let n = normalize(&self.camera1, p)?;
+        let n = Point2::from_homogeneous(self.normalized.0 * n.to_homogeneous())?;
+        let projection = C2::Projection::from_bearing_vector(n.to_homogeneous());
+        Some(self.camera2.uncalibrate(projection).0)
+    }
+
+    /// Fits the homography of pixels to the [transfer](Self::transfer) of `pixels` of the first
+    /// image.
+    pub fn pixel_homography(
+        &self,
+        pixels: impl IntoIterator<Item = Point2<f64>>,
+        options: HomographyOptions,
+    ) -> Result<HomographyMatrix> {
+        let transferred = pixels
+            .into_iter()
+            .filter_map(|p| self.transfer(&p).map(|q| FeatureMatch(p, q)))
+            .collect::<Vec<_>>();
+        Ok(HomographyMatrix(find_homography_iter(
+            transferred,
+            options,
+        )?))
+    }
+}
+
+impl<C1, C2> Model<FeatureMatch<Point2<f64>>> for CalibratedHomography<C1, C2>
+where
+    C1: CameraModel,
+    C2: CameraModel,
+{
+    /// The squared distance of the transferred pixel, so the inlier threshold is in pixels
+    /// whatever the distortion of the lenses.
+    fn residual(&self, data: &FeatureMatch<Point2<f64>>) -> f64 {
+        let FeatureMatch(a, b) = data;
+        self.transfer(a).map_or(UNPROJECTABLE_RESIDUAL, |b2| {
+            nalgebra::distance_squared(b, &b2)
+        })
+    }
+}
+
+/// Estimates homographies on the normalized coordinates of two calibrated cameras.
+///
+/// Implements [`cv::Estimator`](https://docs.rs/cv/0.6.0/cv/trait.Estimator.html) on matches
+/// of raw pixels, so it can be used with [`Ransac`](crate::Ransac) on the images of distorted
+/// lenses, like fisheye cameras. The cameras are any [`CameraModel`], e.g. from `cv-pinhole`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibratedHomographyEstimator<C1, C2> {
+    pub camera1: C1,
+    pub camera2: C2,
+    /// Options of the fitting on the normalized coordinates
+    pub options: HomographyOptions,
+}
+
+impl<C1, C2> CalibratedHomographyEstimator<C1, C2>
+where
+    C1: CameraModel + Clone,
+    C2: CameraModel + Clone,
+{
+    pub fn new(camera1: C1, camera2: C2) -> Self {
+        Self {
+            camera1,
+            camera2,
+            options: HomographyOptions::default(),
+        }
+    }
+
+    /// Maps the pixels of a match to normalized coordinates. `None` if one of them has a bearing
+    /// at or beyond 90° from the optical axis.
+    pub fn normalize(&self, m: &FeatureMatch<Point2<f64>>) -> Option<FeatureMatch<Point2<f64>>> {
+        Some(FeatureMatch(
+            normalize(&self.camera1, &m.0)?,
+            normalize(&self.camera2, &m.1)?,
+        ))
+    }
+
+    /// Fits the homography to all the matches with least squares.
+    ///
+    /// Fails if a pixel can't be normalized or if the fitting fails.
+    pub fn fit(
+        &self,
+        matches: &[FeatureMatch<Point2<f64>>],
+    ) -> Result<CalibratedHomography<C1, C2>> {
+        let normalized = matches
+            .iter()
+            .map(|m| {
+                self.normalize(m)
+                    .ok_or_else(|| eyre!("A pixel can't be normalized: {:?}", m))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let h = find_homography_with_options(normalized, self.options)?;
+        let mut model = self.model(HomographyMatrix(h));
+        model.pixel = Some(model.pixel_homography(matches.iter().map(|m| m.0), self.options)?);
+        Ok(model)
+    }
+
+    fn model(&self, normalized: HomographyMatrix) -> CalibratedHomography<C1, C2> {
+        CalibratedHomography {
+            normalized,
+            pixel: None,
+            camera1: self.camera1.clone(),
+            camera2: self.camera2.clone(),
+        }
+    }
+}
+
+impl<C1, C2> Estimator<FeatureMatch<Point2<f64>>> for CalibratedHomographyEstimator<C1, C2>
+where
+    C1: CameraModel + Clone,
+    C2: CameraModel + Clone,
+{
+    type Model = CalibratedHomography<C1, C2>;
+    type ModelIter = Option<CalibratedHomography<C1, C2>>;
+    const MIN_SAMPLES: usize = 4;
+
+    fn estimate<I>(&self, data: I) -> Self::ModelIter
+    where
+        I: Iterator<Item = FeatureMatch<Point2<f64>>> + Clone,
+    {
+        let mut normalized = [FeatureMatch(Point2::origin(), Point2::origin()); 4];
+        let mut len = 0;
+        for (slot, m) in normalized.iter_mut().zip(data) {
+            *slot = self.normalize(&m)?;
+            len += 1;
+        }
+        let h = find_homography_iter(normalized[..len].iter().copied(), self.options).ok()?;
+        Some(self.model(HomographyMatrix(h)))
+    }
+}
+
+/// Estimates the homography between two images of calibrated cameras.
+///
+/// The pixels are mapped to bearings with the camera models, which removes the distortion of the
+/// lenses, and the homography is fitted to their normalized coordinates. Fitting on the raw
+/// pixels of distorted images is wrong, as the distortion isn't projective. The result has the
+/// homography in both normalized and pixel coordinates, and its residuals are in pixels.
+pub fn find_homography_calibrated<C1, C2>(
+    matches: &[FeatureMatch<Point2<f64>>],
+    camera1: C1,
+    camera2: C2,
+) -> Result<CalibratedHomography<C1, C2>>
+where
+    C1: CameraModel + Clone,
+    C2: CameraModel + Clone,
+{
+    CalibratedHomographyEstimator::new(camera1, camera2).fit(matches)
+}
+
+/// Normalized coordinates of a pixel, `None` if the bearing doesn't point forward.
+fn normalize<C: CameraModel>(camera: &C, p: &Point2<f64>) -> Option<Point2<f64>> {
+    let bearing: Vector3<f64> = camera.calibrate(KeyPoint(*p)).bearing_unnormalized();
+    if bearing.z <= f64::EPSILON * bearing.norm() {
+        return None;
+    }
+    Some(Point2::new(bearing.x / bearing.z, bearing.y / bearing.z))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{find_homography, find_homography_calibrated, CalibratedHomographyEstimator};
+    use approx::assert_relative_eq;
+    use cv_core::{CameraModel, FeatureMatch};
+    use cv_pinhole::{CameraIntrinsics, CameraIntrinsicsK1Distortion, NormalizedKeyPoint};
+    use nalgebra::{Matrix3, Point2, Rotation3, Vector2};
+    use sample_consensus::{Estimator, Model};
+
+    fn intrinsics() -> CameraIntrinsics {
+        CameraIntrinsics::identity()
+            .focals(Vector2::new(400.0, 410.0))
+            .principal_point(Point2::new(320.0, 240.0))
+    }
+
+    fn normalized_homography() -> Matrix3<f64> {
+        *Rotation3::from_euler_angles(0.05, 0.2, -0.03).matrix()
+    }
+
+    /// Matches of a rotating camera on a grid of normalized coordinates
+    fn matches<C: CameraModel<Projection = NormalizedKeyPoint>>(
+        camera: &C,
+    ) -> Vec<FeatureMatch<Point2<f64>>> {
+        let h = normalized_homography();
+        (0..6)
+            .flat_map(|i| {
+                (0..5).map(move |j| Point2::new(i as f64 * 0.2 - 0.5, j as f64 * 0.2 - 0.4))
+            })
+            .map(|n| {
+                let n2 = Point2::from_homogeneous(h * n.to_homogeneous()).unwrap();
+                let pixel = |n: Point2<f64>| camera.uncalibrate(NormalizedKeyPoint(n)).0;
+                FeatureMatch(pixel(n), pixel(n2))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pinhole_pixel_homography() {
+        let camera = intrinsics();
+        let matches = matches(&camera);
+        let result = find_homography_calibrated(&matches, camera, camera).unwrap();
+        assert_relative_eq!(
+            result.normalized.0 / result.normalized.0[(2, 2)],
+            normalized_homography() / normalized_homography()[(2, 2)],
+            epsilon = 1e-9
+        );
+        let k = camera.matrix();
+        let expected = k * normalized_homography() * k.try_inverse().unwrap();
+        assert_relative_eq!(
+            result.pixel.unwrap().0 / result.pixel.unwrap().0[(2, 2)],
+            expected / expected[(2, 2)],
+            epsilon = 1e-6
+        );
+        assert!(matches.iter().all(|m| result.residual(m) < 1e-12));
+    }
+
+    #[test]
+    fn distorted_lens() {
+        let camera = CameraIntrinsicsK1Distortion::new(intrinsics(), -0.1);
+        let matches = matches(&camera);
+        let result = find_homography_calibrated(&matches, camera, camera).unwrap();
+        assert_relative_eq!(
+            result.normalized.0 / result.normalized.0[(2, 2)],
+            normalized_homography() / normalized_homography()[(2, 2)],
+            epsilon = 1e-6
+        );
+        assert!(matches.iter().all(|m| result.residual(m) < 1e-10));
+        // The distortion can't be explained by a homography of the raw pixels
+        let raw = crate::HomographyMatrix(find_homography(matches.clone()).unwrap());
+        assert!(matches.iter().any(|m| raw.residual(m) > 1.0));
+
+        let estimator = CalibratedHomographyEstimator::new(camera, camera);
+        // The corners of the grid
+        let sample = [0, 4, 25, 29].map(|ix| matches[ix]);
+        let model = estimator.estimate(sample.iter().copied()).unwrap();
+        // The hypotheses skip the pixel homography
+        assert!(model.pixel.is_none());
+        assert!(matches.iter().all(|m| model.residual(m) < 1e-8));
+        let p = camera.uncalibrate(NormalizedKeyPoint(Point2::new(0.1, 0.2)));
+        let n = normalized_homography() * Point2::new(0.1, 0.2).to_homogeneous();
+        let expected = camera.uncalibrate(NormalizedKeyPoint(Point2::from_homogeneous(n).unwrap()));
+        assert_relative_eq!(model.transfer(&p.0).unwrap(), expected.0, epsilon = 1e-6);
+    }
+}
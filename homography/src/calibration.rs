@@ -0,0 +1,639 @@
+use cv_core::FeatureMatch;
+use eyre::{eyre, Result};
+use nalgebra::{
+    self as na, DMatrix, DVector, Matrix3, Point2, Point3, Rotation3, SMatrix, Vector3,
+};
+
+use crate::{find_homography_with_options, HomographyMatrix, HomographyOptions};
+
+/// Intrinsic parameters of a pinhole camera.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraIntrinsics {
+    pub fx: f64,
+    pub fy: f64,
+    pub cx: f64,
+    pub cy: f64,
+    pub skew: f64,
+}
+
+impl CameraIntrinsics {
+    /// Reads the parameters of an upper triangular camera matrix, normalized by its last element.
+    pub fn from_matrix(k: &Matrix3<f64>) -> Self {
+        let k = k / k[(2, 2)];
+        Self {
+            fx: k[(0, 0)],
+            fy: k[(1, 1)],
+            cx: k[(0, 2)],
+            cy: k[(1, 2)],
+            skew: k[(0, 1)],
+        }
+    }
+
+    /// The camera matrix `K`.
+    pub fn matrix(&self) -> Matrix3<f64> {
+        Matrix3::new(
+            self.fx, self.skew, self.cx, 0.0, self.fy, self.cy, 0.0, 0.0, 1.0,
+        )
+    }
+
+    /// Maps a point of the normalized image plane (`z = 1`) to pixels.
+    pub fn to_pixel(&self, p: &Point2<f64>) -> Point2<f64> {
+        Point2::new(
+            self.fx * p.x + self.skew * p.y + self.cx,
+            self.fy * p.y + self.cy,
+        )
+    }
+
+    /// Maps a pixel to the normalized image plane.
+    pub fn to_normalized(&self, p: &Point2<f64>) -> Point2<f64> {
+        let y = (p.y - self.cy) / self.fy;
+        Point2::new((p.x - self.cx - self.skew * y) / self.fx, y)
+    }
+}
+
+/// Radial and tangential lens distortion with the Brown-Conrady model, like OpenCV's
+/// `(k1, k2, p1, p2, k3)` distortion coefficients.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Distortion {
+    pub k1: f64,
+    pub k2: f64,
+    pub k3: f64,
+    pub p1: f64,
+    pub p2: f64,
+}
+
+impl Distortion {
+    /// Distorts a point of the normalized image plane.
+    pub fn distort(&self, p: &Point2<f64>) -> Point2<f64> {
+        let Self { k1, k2, k3, p1, p2 } = *self;
+        let (x, y) = (p.x, p.y);
+        let r2 = x * x + y * y;
+        let radial = 1.0 + r2 * (k1 + r2 * (k2 + r2 * k3));
+        Point2::new(
+            x * radial + 2.0 * p1 * x * y + p2 * (r2 + 2.0 * x * x),
+            y * radial + p1 * (r2 + 2.0 * y * y) + 2.0 * p2 * x * y,
+        )
+    }
+
+    /// Inverts [`Self::distort`] with fixed-point iterations, like OpenCV's `undistortPoints`.
+    pub fn undistort(&self, p: &Point2<f64>) -> Point2<f64> {
+        let mut undistorted = *p;
+        for _ in 0..20 {
+            let error = self.distort(&undistorted) - p;
+            undistorted -= error;
+            if error.norm_squared() < 1e-24 {
+                break;
+            }
+        }
+        undistorted
+    }
+}
+
+/// Pose of a planar target relative to the camera: a target point `X` is at `R X + t` in the
+/// frame of the camera.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TargetPose {
+    pub rotation: Rotation3<f64>,
+    pub translation: Vector3<f64>,
+}
+
+impl TargetPose {
+    /// Projects a point of the target plane to the image. `None` if it's behind the camera.
+    pub fn project(
+        &self,
+        target: &Point2<f64>,
+        intrinsics: &CameraIntrinsics,
+        distortion: &Distortion,
+    ) -> Option<Point2<f64>> {
+        let p = self.rotation * Point3::new(target.x, target.y, 0.0) + self.translation;
+        if p.z <= f64::EPSILON {
+            return None;
+        }
+        let normalized = Point2::new(p.x / p.z, p.y / p.z);
+        Some(intrinsics.to_pixel(&distortion.distort(&normalized)))
+    }
+}
+
+/// Options of [`calibrate_camera`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationOptions {
+    /// Number of the estimated radial distortion coefficients (`k1`, `k2`, `k3`), at most 3
+    pub radial_coefficients: usize,
+    /// Estimate the tangential distortion coefficients (`p1`, `p2`)
+    pub tangential: bool,
+    /// Keep the skew of the pixels at zero. Only two views are needed then.
+    pub zero_skew: bool,
+    /// Maximum number of Levenberg-Marquardt iterations. Zero skips the refinement,
+    /// and returns the closed form solution without distortion.
+    pub max_iterations: usize,
+    /// The refinement stops when an iteration decreases the cost by less than this fraction
+    pub tolerance: f64,
+    /// Options of the target to image homographies
+    pub homography: HomographyOptions,
+}
+
+impl Default for CalibrationOptions {
+    fn default() -> Self {
+        Self {
+            radial_coefficients: 2,
+            tangential: true,
+            zero_skew: true,
+            max_iterations: 100,
+            tolerance: 1e-12,
+            homography: HomographyOptions::default(),
+        }
+    }
+}
+
+/// Result of [`calibrate_camera`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    pub intrinsics: CameraIntrinsics,
+    pub distortion: Distortion,
+    /// Pose of the target in each view
+    pub poses: Vec<TargetPose>,
+    /// Root mean square reprojection error in pixels
+    pub rms_error: f64,
+    pub iterations: usize,
+}
+
+/// Calibrates a camera from views of a planar target with Zhang's method.
+///
+/// Each view is a set of matches from points of the target plane (e.g. in millimeters) to their
+/// pixels on the image. The homography of each view is fitted with [`find_homography`](crate::find_homography),
+/// the intrinsics are solved in closed form from the image of the absolute conic with
+/// [`intrinsics_from_homographies`], and the pose of the target with [`pose_from_homography`].
+/// Finally the intrinsics, the distortion and the poses are refined jointly by minimizing the
+/// reprojection error with Levenberg-Marquardt.
+///
+/// Needs at least 3 views, or 2 with [`CalibrationOptions::zero_skew`], of different orientations.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(views = views.len(), iterations))
+)]
+pub fn calibrate_camera(
+    views: &[Vec<FeatureMatch<Point2<f64>>>],
+    options: CalibrationOptions,
+) -> Result<Calibration> {
+    let homographies = views
+        .iter()
+        .enumerate()
+        .map(|(ix, view)| {
+            find_homography_with_options(view.clone(), options.homography)
+                .map(HomographyMatrix)
+                .map_err(|e| e.wrap_err(format!("Failed to fit the homography of view {}", ix)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // The conic is solved on image coordinates scaled to around one for a better conditioning
+    let points = views
+        .iter()
+        .flatten()
+        .map(|m| m.1.coords)
+        .collect::<Vec<_>>();
+    let center = points.iter().sum::<na::Vector2<f64>>() / points.len() as f64;
+    let spread = points.iter().map(|p| (p - center).norm()).sum::<f64>() / points.len() as f64;
+    let scale = 1.0 / spread.max(f64::EPSILON);
+    let normalization = Matrix3::new(
+        scale,
+        0.0,
+        -scale * center.x,
+        0.0,
+        scale,
+        -scale * center.y,
+        0.0,
+        0.0,
+        1.0,
+    );
+    let normalized = homographies
+        .iter()
+        .map(|h| HomographyMatrix(normalization * h.0))
+        .collect::<Vec<_>>();
+    let intrinsics = intrinsics_from_homographies(&normalized, options.zero_skew)?;
+    let denormalization = normalization
+        .try_inverse()
+        .expect("the normalization is invertible");
+    let intrinsics = CameraIntrinsics::from_matrix(&(denormalization * intrinsics.matrix()));
+
+    let poses = homographies
+        .iter()
+        .map(|h| pose_from_homography(h, &intrinsics))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut refinement = Refinement::new(views, intrinsics, poses, options);
+    refinement.run(options);
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("iterations", refinement.iterations);
+    Ok(refinement.into_calibration())
+}
+
+/// Solves the camera intrinsics from the homographies of a planar target to at least 3 images
+/// (2 with `zero_skew`), using the constraints of the image of the absolute conic.
+pub fn intrinsics_from_homographies(
+    homographies: &[HomographyMatrix],
+    zero_skew: bool,
+) -> Result<CameraIntrinsics> {
+    let needed = if zero_skew { 2 } else { 3 };
+    if homographies.len() < needed {
+        return Err(eyre!(
+            "At least {} views are needed, got {}",
+            needed,
+            homographies.len()
+        ));
+    }
+    // B = K⁻ᵀK⁻¹ as (B11, B12, B22, B13, B23, B33)
+    let v = |h: &Matrix3<f64>, i: usize, j: usize| {
+        let (hi, hj) = (h.column(i), h.column(j));
+        na::SVector::<f64, 6>::new(
+            hi[0] * hj[0],
+            hi[0] * hj[1] + hi[1] * hj[0],
+            hi[1] * hj[1],
+            hi[2] * hj[0] + hi[0] * hj[2],
+            hi[2] * hj[1] + hi[1] * hj[2],
+            hi[2] * hj[2],
+        )
+    };
+    let mut vtv = SMatrix::<f64, 6, 6>::zeros();
+    for h in homographies {
+        let h = h.0 / h.0.column(0).norm();
+        let rows = [v(&h, 0, 1), v(&h, 0, 0) - v(&h, 1, 1)];
+        for row in rows {
+            vtv += row * row.transpose();
+        }
+    }
+    if zero_skew {
+        let row = na::SVector::<f64, 6>::new(0.0, 1.0, 0.0, 0.0, 0.0, 0.0);
+        vtv += row * row.transpose();
+    }
+    let eigen = vtv.symmetric_eigen();
+    let (ix, _) = eigen.eigenvalues.argmin();
+    let b = eigen.eigenvectors.column(ix);
+    // B is only known up to a sign, it's positive definite
+    let b = if b[0] < 0.0 { -b } else { b.clone_owned() };
+    let (b11, b12, b22, b13, b23, b33) = (b[0], b[1], b[2], b[3], b[4], b[5]);
+
+    let denominator = b11 * b22 - b12 * b12;
+    let cy = (b12 * b13 - b11 * b23) / denominator;
+    let lambda = b33 - (b13 * b13 + cy * (b12 * b13 - b11 * b23)) / b11;
+    let fx = (lambda / b11).sqrt();
+    let fy = (lambda * b11 / denominator).sqrt();
+    let skew = -b12 * fx * fx * fy / lambda;
+    let cx = skew * cy / fy - b13 * fx * fx / lambda;
+    let intrinsics = CameraIntrinsics {
+        fx,
+        fy,
+        cx,
+        cy,
+        skew: if zero_skew { 0.0 } else { skew },
+    };
+    if [fx, fy, cx, cy, skew].iter().all(|v| v.is_finite()) {
+        Ok(intrinsics)
+    } else {
+        Err(eyre!(
+            "The views don't determine the intrinsics, the target should be seen from \
+             different orientations"
+        ))
+    }
+}
+
+/// Pose of a planar target from its homography to the image and the camera intrinsics.
+///
+/// The rotation is the closest one to the columns of `K⁻¹H`. The target is in front of the camera.
+pub fn pose_from_homography(
+    h: &HomographyMatrix,
+    intrinsics: &CameraIntrinsics,
+) -> Result<TargetPose> {
+    let k_inverse = intrinsics
+        .matrix()
+        .try_inverse()
+        .ok_or_else(|| eyre!("The camera matrix is not invertible"))?;
+    let m = k_inverse * h.0;
+    let norm = m.column(0).norm();
+    if norm < f64::EPSILON {
+        return Err(eyre!("The homography is singular"));
+    }
+    let lambda = if m[(2, 2)] < 0.0 { -1.0 } else { 1.0 } / norm;
+    let r1 = m.column(0) * lambda;
+    let r2 = m.column(1) * lambda;
+    let r3 = r1.cross(&r2);
+    let rotation = Rotation3::from_matrix(&Matrix3::from_columns(&[r1, r2, r3]));
+    Ok(TargetPose {
+        rotation,
+        translation: m.column(2) * lambda,
+    })
+}
+
+/// Parameters of the camera: fx, fy, cx, cy, skew, k1, k2, k3, p1, p2
+type CameraParameters = [f64; 10];
+
+/// Levenberg-Marquardt refinement of the calibration.
+struct Refinement<'a> {
+    views: &'a [Vec<FeatureMatch<Point2<f64>>>],
+    camera: CameraParameters,
+    /// Indices of the estimated camera parameters
+    free: Vec<usize>,
+    poses: Vec<TargetPose>,
+    cost: f64,
+    iterations: usize,
+}
+
+impl<'a> Refinement<'a> {
+    fn new(
+        views: &'a [Vec<FeatureMatch<Point2<f64>>>],
+        intrinsics: CameraIntrinsics,
+        poses: Vec<TargetPose>,
+        options: CalibrationOptions,
+    ) -> Self {
+        let CameraIntrinsics {
+            fx,
+            fy,
+            cx,
+            cy,
+            skew,
+        } = intrinsics;
+        let mut free = vec![0, 1, 2, 3];
+        if !options.zero_skew {
+            free.push(4);
+        }
+        free.extend((5..8).take(options.radial_coefficients.min(3)));
+        if options.tangential {
+            free.extend([8, 9]);
+        }
+        let camera = [fx, fy, cx, cy, skew, 0.0, 0.0, 0.0, 0.0, 0.0];
+        let cost = total_cost(views, &camera, &poses);
+        Self {
+            views,
+            camera,
+            free,
+            poses,
+            cost,
+            iterations: 0,
+        }
+    }
+
+    fn run(&mut self, options: CalibrationOptions) {
+        let unknowns = self.free.len() + 6 * self.poses.len();
+        let mut lambda = 1e-3;
+        while self.iterations < options.max_iterations && self.cost.is_finite() {
+            self.iterations += 1;
+            let (jtj, jtr) = self.normal_equations(unknowns);
+            let mut improved = false;
+            while lambda < 1e16 {
+                let mut damped = jtj.clone();
+                for i in 0..unknowns {
+                    damped[(i, i)] += lambda * jtj[(i, i)].max(1e-12);
+                }
+                let Some(cholesky) = damped.cholesky() else {
+                    lambda *= 10.0;
+                    continue;
+                };
+                let step = cholesky.solve(&jtr);
+                let (camera, poses) = self.updated(&step);
+                let cost = total_cost(self.views, &camera, &poses);
+                if cost < self.cost {
+                    let decrease = (self.cost - cost) / self.cost;
+                    self.camera = camera;
+                    self.poses = poses;
+                    self.cost = cost;
+                    lambda = (lambda / 10.0).max(1e-12);
+                    improved = decrease >= options.tolerance;
+                    break;
+                }
+                lambda *= 10.0;
+            }
+            if !improved {
+                break;
+            }
+        }
+    }
+
+    fn updated(&self, step: &DVector<f64>) -> (CameraParameters, Vec<TargetPose>) {
+        let mut camera = self.camera;
+        for (column, &ix) in self.free.iter().enumerate() {
+            camera[ix] += step[column];
+        }
+        let offset = self.free.len();
+        let poses = self
+            .poses
+            .iter()
+            .enumerate()
+            .map(|(view, pose)| {
+                let step = step.fixed_rows::<6>(offset + 6 * view);
+                perturbed(pose, &step.into_owned())
+            })
+            .collect();
+        (camera, poses)
+    }
+
+    /// JᵀJ and -Jᵀr with numeric derivatives. Each residual depends on the camera parameters
+    /// and the pose of its own view, so only those blocks are filled.
+    fn normal_equations(&self, unknowns: usize) -> (DMatrix<f64>, DVector<f64>) {
+        let free = self.free.len();
+        let mut jtj = DMatrix::zeros(unknowns, unknowns);
+        let mut jtr = DVector::zeros(unknowns);
+        let mut jacobian = DMatrix::zeros(2, free + 6);
+        for (view, (matches, pose)) in self.views.iter().zip(&self.poses).enumerate() {
+            let columns = (0..free)
+                .chain((0..6).map(|i| free + 6 * view + i))
+                .collect::<Vec<_>>();
+            for FeatureMatch(target, pixel) in matches {
+                let Some(projected) = project(&self.camera, pose, target) else {
+                    continue;
+                };
+                let residual = projected - pixel;
+                // Derivatives that can't be evaluated, because a perturbed point falls behind
+                // the camera, are left at zero instead of the ones of the previous match
+                jacobian.fill(0.0);
+                for (column, &ix) in self.free.iter().enumerate() {
+                    let h = 1e-6 * self.camera[ix].abs().max(1e-2);
+                    let (mut plus, mut minus) = (self.camera, self.camera);
+                    plus[ix] += h;
+                    minus[ix] -= h;
+                    if let (Some(a), Some(b)) =
+                        (project(&plus, pose, target), project(&minus, pose, target))
+                    {
+                        jacobian.set_column(column, &((a - b) / (2.0 * h)));
+                    }
+                }
+                for i in 0..6 {
+                    let h = if i < 3 {
+                        1e-7
+                    } else {
+                        1e-6 * pose.translation.norm().max(1.0)
+                    };
+                    let mut delta = na::SVector::<f64, 6>::zeros();
+                    delta[i] = h;
+                    let plus = project(&self.camera, &perturbed(pose, &delta), target);
+                    let minus = project(&self.camera, &perturbed(pose, &-delta), target);
+                    if let (Some(a), Some(b)) = (plus, minus) {
+                        jacobian.set_column(free + i, &((a - b) / (2.0 * h)));
+                    }
+                }
+                let jtj_local = jacobian.transpose() * &jacobian;
+                let jtr_local = jacobian.transpose() * residual;
+                for (a, &ca) in columns.iter().enumerate() {
+                    jtr[ca] -= jtr_local[a];
+                    for (b, &cb) in columns.iter().enumerate() {
+                        jtj[(ca, cb)] += jtj_local[(a, b)];
+                    }
+                }
+            }
+        }
+        (jtj, jtr)
+    }
+
+    fn into_calibration(self) -> Calibration {
+        let (intrinsics, distortion) = split(&self.camera);
+        let observations = self.views.iter().map(Vec::len).sum::<usize>();
+        Calibration {
+            intrinsics,
+            distortion,
+            poses: self.poses,
+            rms_error: (self.cost / observations.max(1) as f64).sqrt(),
+            iterations: self.iterations,
+        }
+    }
+}
+
+fn split(camera: &CameraParameters) -> (CameraIntrinsics, Distortion) {
+    let [fx, fy, cx, cy, skew, k1, k2, k3, p1, p2] = *camera;
+    (
+        CameraIntrinsics {
+            fx,
+            fy,
+            cx,
+            cy,
+            skew,
+        },
+        Distortion { k1, k2, k3, p1, p2 },
+    )
+}
+
+fn project(
+    camera: &CameraParameters,
+    pose: &TargetPose,
+    target: &Point2<f64>,
+) -> Option<Point2<f64>> {
+    let (intrinsics, distortion) = split(camera);
+    pose.project(target, &intrinsics, &distortion)
+}
+
+/// Applies a small rotation (as a rotation vector) and translation to the pose.
+fn perturbed(pose: &TargetPose, delta: &na::SVector<f64, 6>) -> TargetPose {
+    TargetPose {
+        rotation: Rotation3::new(delta.fixed_rows::<3>(0).into_owned()) * pose.rotation,
+        translation: pose.translation + delta.fixed_rows::<3>(3),
+    }
+}
+
+fn total_cost(
+    views: &[Vec<FeatureMatch<Point2<f64>>>],
+    camera: &CameraParameters,
+    poses: &[TargetPose],
+) -> f64 {
+    let mut cost = 0.0;
+    for (matches, pose) in views.iter().zip(poses) {
+        for FeatureMatch(target, pixel) in matches {
+            match project(camera, pose, target) {
+                Some(projected) => cost += na::distance_squared(&projected, pixel),
+                None => return f64::INFINITY,
+            }
+        }
+    }
+    cost
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{calibrate_camera, CalibrationOptions, CameraIntrinsics, Distortion, TargetPose};
+    use approx::assert_relative_eq;
+    use cv_core::FeatureMatch;
+    use nalgebra::{Point2, Rotation3, Vector2, Vector3};
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg64;
+
+    fn views(
+        distortion: &Distortion,
+        noise: f64,
+    ) -> (CameraIntrinsics, Vec<Vec<FeatureMatch<Point2<f64>>>>) {
+        let intrinsics = CameraIntrinsics {
+            fx: 800.0,
+            fy: 780.0,
+            cx: 330.0,
+            cy: 235.0,
+            skew: 0.0,
+        };
+        let mut rng = Pcg64::seed_from_u64(0);
+        let views = [
+            (0.3, 0.1, 0.0),
+            (-0.25, 0.2, 0.1),
+            (0.1, -0.35, -0.05),
+            (-0.1, -0.15, 0.3),
+            (0.35, 0.3, -0.2),
+        ]
+        .iter()
+        .map(|&(rx, ry, rz)| {
+            let pose = TargetPose {
+                rotation: Rotation3::from_euler_angles(rx, ry, rz),
+                translation: Vector3::new(-120.0, -90.0, 550.0),
+            };
+            // 9×7 inner corners of a chessboard with 30 mm squares
+            (0..9 * 7)
+                .map(|i| {
+                    let target = Point2::new((i % 9) as f64 * 30.0, (i / 9) as f64 * 30.0);
+                    let pixel = pose.project(&target, &intrinsics, distortion).unwrap();
+                    let jitter =
+                        Vector2::new(rng.gen_range(-noise..=noise), rng.gen_range(-noise..=noise));
+                    FeatureMatch(target, pixel + jitter)
+                })
+                .collect()
+        })
+        .collect();
+        (intrinsics, views)
+    }
+
+    #[test]
+    fn closed_form_without_distortion() {
+        let (truth, views) = views(&Distortion::default(), 0.0);
+        let options = CalibrationOptions {
+            max_iterations: 0,
+            ..Default::default()
+        };
+        let calibration = calibrate_camera(&views, options).unwrap();
+        assert_relative_eq!(
+            calibration.intrinsics.matrix(),
+            truth.matrix(),
+            epsilon = 1e-6
+        );
+        assert!(calibration.rms_error < 1e-6);
+        assert_eq!(calibration.poses.len(), 5);
+    }
+
+    #[test]
+    fn refines_distortion() {
+        let distortion = Distortion {
+            k1: -0.25,
+            k2: 0.1,
+            k3: 0.0,
+            p1: 0.001,
+            p2: -0.002,
+        };
+        let (truth, views) = views(&distortion, 0.1);
+        let calibration = calibrate_camera(&views, CalibrationOptions::default()).unwrap();
+        let estimate = calibration.intrinsics;
+        assert!(calibration.rms_error < 0.1, "{}", calibration.rms_error);
+        assert!((estimate.fx - truth.fx).abs() < 2.0, "{:?}", estimate);
+        assert!((estimate.fy - truth.fy).abs() < 2.0, "{:?}", estimate);
+        assert!((estimate.cx - truth.cx).abs() < 2.0, "{:?}", estimate);
+        assert!((estimate.cy - truth.cy).abs() < 2.0, "{:?}", estimate);
+        assert!((calibration.distortion.k1 - distortion.k1).abs() < 0.02);
+        assert!((calibration.distortion.p1 - distortion.p1).abs() < 1e-3);
+
+        let p = Point2::new(0.3, -0.2);
+        assert_relative_eq!(
+            distortion.undistort(&distortion.distort(&p)),
+            p,
+            epsilon = 1e-9
+        );
+    }
+}
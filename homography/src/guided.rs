@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+
+use cv_core::FeatureMatch;
+use eyre::{eyre, Result};
+use nalgebra::{Point2, Vector2};
+use sample_consensus::Model;
+
+use crate::{find_homography_iter, HomographyMatrix, HomographyOptions};
+
+/// Options of [`guided_matching`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GuidedMatchingOptions {
+    /// Candidates are searched within this many pixels of the projected point, must be positive
+    pub radius: f64,
+    /// Lowe's ratio test applied to the candidates inside the search window.
+    /// It can be more permissive than the one of global matching, as there are far fewer candidates.
+    pub ratio: f64,
+    /// Matches with a larger descriptor distance are rejected
+    pub max_distance: f64,
+    /// Only the matches within this many pixels of their projection by the current model are used
+    /// to re-estimate it, so a few wrong matches in the search windows don't drag the model away
+    pub inlier_threshold: f64,
+    /// Upper limit of the match - re-estimate rounds
+    pub max_iterations: usize,
+    /// Options of the re-estimation
+    pub homography: HomographyOptions,
+}
+
+impl Default for GuidedMatchingOptions {
+    fn default() -> Self {
+        Self {
+            radius: 3.0,
+            ratio: 0.8,
+            max_distance: f64::INFINITY,
+            inlier_threshold: 2.0,
+            max_iterations: 10,
+            homography: HomographyOptions::default(),
+        }
+    }
+}
+
+/// Result of [`guided_matching`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct GuidedMatches {
+    /// Homography re-estimated from the inlier matches
+    pub model: HomographyMatrix,
+    /// Index pairs of the matched keypoints in increasing order of the first index
+    pub matches: Vec<(usize, usize)>,
+    /// Number of match - re-estimate rounds
+    pub iterations: usize,
+}
+
+impl GuidedMatches {
+    /// The matched keypoint coordinates.
+    pub fn feature_matches(
+        &self,
+        points1: &[Point2<f64>],
+        points2: &[Point2<f64>],
+    ) -> Vec<FeatureMatch<Point2<f64>>> {
+        self.matches
+            .iter()
+            .map(|&(ix1, ix2)| FeatureMatch(points1[ix1], points2[ix2]))
+            .collect()
+    }
+}
+
+/// Finds matches between the keypoints of two images constrained by an estimated homography.
+///
+/// Every keypoint of the first image is projected with `model` and matched to the keypoint of
+/// the second image with the closest descriptor inside the search window around the projection.
+/// A keypoint of the second image is matched at most once. The homography is then re-estimated from
+/// the matches that are inliers of the current model (see [`GuidedMatchingOptions::inlier_threshold`]),
+/// and this repeats until the set of matches stops changing. When fewer than 4 inliers remain, the
+/// current model is kept and the iteration stops.
+///
+/// This recovers the correspondences that were rejected by a global ratio test because of
+/// similar-looking features elsewhere in the image. `distance` is the descriptor distance,
+/// like the Hamming distance of binary descriptors. Keypoints projected to infinity or outside the
+/// bounding box of the keypoints of the second image, grown by the radius, are left unmatched.
+///
+/// Fails if [`GuidedMatchingOptions::radius`] is not positive.
+pub fn guided_matching<D, F>(
+    points1: &[Point2<f64>],
+    descriptors1: &[D],
+    points2: &[Point2<f64>],
+    descriptors2: &[D],
+    model: &HomographyMatrix,
+    distance: F,
+    options: GuidedMatchingOptions,
+) -> Result<GuidedMatches>
+where
+    F: Fn(&D, &D) -> f64,
+{
+    if options.radius.is_nan() || options.radius <= 0.0 {
+        return Err(eyre!(
+            "The search radius must be positive, got {}",
+            options.radius
+        ));
+    }
+    let grid = PointGrid::new(points2, options.radius);
+    let mut model = *model;
+    let mut matches: Vec<(usize, usize)> = vec![];
+    let mut iterations = 0;
+    while iterations < options.max_iterations {
+        iterations += 1;
+        let new_matches = match_in_windows(
+            points1,
+            descriptors1,
+            points2,
+            descriptors2,
+            &grid,
+            &model,
+            &distance,
+            &options,
+        );
+        if new_matches == matches {
+            break;
+        }
+        matches = new_matches;
+        let threshold_squared = options.inlier_threshold * options.inlier_threshold;
+        let inliers = matches
+            .iter()
+            .map(|&(ix1, ix2)| FeatureMatch(points1[ix1], points2[ix2]))
+            .filter(|m| model.residual(m) <= threshold_squared);
+        if inliers.clone().count() < 4 {
+            break;
+        }
+        model = HomographyMatrix(find_homography_iter(inliers, options.homography)?);
+    }
+    Ok(GuidedMatches {
+        model,
+        matches,
+        iterations,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn match_in_windows<D, F>(
+    points1: &[Point2<f64>],
+    descriptors1: &[D],
+    points2: &[Point2<f64>],
+    descriptors2: &[D],
+    grid: &PointGrid,
+    model: &HomographyMatrix,
+    distance: &F,
+    options: &GuidedMatchingOptions,
+) -> Vec<(usize, usize)>
+where
+    F: Fn(&D, &D) -> f64,
+{
+    let radius_squared = options.radius * options.radius;
+    // Best match of each keypoint of the second image: (distance, index in the first image)
+    let mut best_of_second: HashMap<usize, (f64, usize)> = HashMap::new();
+    for (ix1, (p1, d1)) in points1.iter().zip(descriptors1).enumerate() {
+        let Some(projected) = Point2::from_homogeneous(model.0 * p1.to_homogeneous()) else {
+            continue;
+        };
+        if !grid.covers(&projected) {
+            continue;
+        }
+        let mut best: Option<(f64, usize)> = None;
+        let mut second_distance = f64::INFINITY;
+        for ix2 in grid.neighbourhood(&projected) {
+            if nalgebra::distance_squared(&projected, &points2[ix2]) > radius_squared {
+                continue;
+            }
+            let d = distance(d1, &descriptors2[ix2]);
+            match best {
+                Some((best_distance, _)) if d >= best_distance => {
+                    second_distance = second_distance.min(d);
+                }
+                _ => {
+                    second_distance =
+                        best.map_or(second_distance, |(best_distance, _)| best_distance);
+                    best = Some((d, ix2));
+                }
+            }
+        }
+        let Some((d, ix2)) = best else {
+            continue;
+        };
+        if d > options.max_distance || d >= options.ratio * second_distance {
+            continue;
+        }
+        let entry = best_of_second.entry(ix2).or_insert((d, ix1));
+        if d < entry.0 {
+            *entry = (d, ix1);
+        }
+    }
+    let mut matches = best_of_second
+        .into_iter()
+        .map(|(ix2, (_, ix1))| (ix1, ix2))
+        .collect::<Vec<_>>();
+    matches.sort_unstable();
+    matches
+}
+
+/// Uniform grid of points with cells of the size of the search radius.
+struct PointGrid {
+    cell_size: f64,
+    /// Bounding box of the points grown by the cell size
+    min: Point2<f64>,
+    max: Point2<f64>,
+    cells: HashMap<(i64, i64), Vec<usize>>,
+}
+
+impl PointGrid {
+    /// Points that are not finite are left out.
+    fn new(points: &[Point2<f64>], cell_size: f64) -> Self {
+        let mut grid = Self {
+            cell_size,
+            min: Point2::new(f64::INFINITY, f64::INFINITY),
+            max: Point2::new(f64::NEG_INFINITY, f64::NEG_INFINITY),
+            cells: HashMap::new(),
+        };
+        for (ix, p) in points.iter().enumerate() {
+            if !(p.x.is_finite() && p.y.is_finite()) {
+                continue;
+            }
+            grid.min = grid.min.inf(&(p - Vector2::repeat(cell_size)));
+            grid.max = grid.max.sup(&(p + Vector2::repeat(cell_size)));
+            grid.cells.entry(grid.cell(p)).or_default().push(ix);
+        }
+        grid
+    }
+
+    /// Whether `p` is within the cell size of the bounding box of the points, false if it's not
+    /// finite.
+    fn covers(&self, p: &Point2<f64>) -> bool {
+        (self.min.x..=self.max.x).contains(&p.x) && (self.min.y..=self.max.y).contains(&p.y)
+    }
+
+    fn cell(&self, p: &Point2<f64>) -> (i64, i64) {
+        (
+            (p.x / self.cell_size).floor() as i64,
+            (p.y / self.cell_size).floor() as i64,
+        )
+    }
+
+    /// Points in the 3×3 cells around `p`, a superset of the points within the cell size.
+    ///
+    /// With a tiny cell size, the cell indices of far points saturate, so the cells are shared by
+    /// more points but never overflow.
+    fn neighbourhood<'a>(&'a self, p: &Point2<f64>) -> impl Iterator<Item = usize> + 'a {
+        let (cx, cy) = self.cell(p);
+        (cx.saturating_sub(1)..=cx.saturating_add(1))
+            .flat_map(move |x| (cy.saturating_sub(1)..=cy.saturating_add(1)).map(move |y| (x, y)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{guided_matching, GuidedMatchingOptions, HomographyMatrix};
+    use approx::AbsDiffEq;
+    use nalgebra::{Matrix3, Point2, Vector2};
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg64;
+    use test_utils::TestData;
+
+    #[test]
+    fn recovers_matches_from_rough_model() {
+        let mut rng = Pcg64::seed_from_u64(0);
+        let TestData { matches, h } = TestData::from_rng(&mut rng, 300, 1000.0, 0.0);
+        let points1 = matches.iter().map(|m| m.0).collect::<Vec<_>>();
+        let descriptors1 = (0..300).map(|_| rng.gen::<u64>()).collect::<Vec<_>>();
+        // Shuffled matches with a few flipped bits, plus distractors
+        let mut points2 = matches.iter().map(|m| m.1).collect::<Vec<_>>();
+        let mut descriptors2 = descriptors1
+            .iter()
+            .map(|d| d ^ (1 << rng.gen_range(0..64)))
+            .collect::<Vec<_>>();
+        points2.reverse();
+        descriptors2.reverse();
+        for _ in 0..300 {
+            points2.push(Point2::new(
+                rng.gen_range(0.0..1000.0),
+                rng.gen_range(0.0..1000.0),
+            ));
+            descriptors2.push(rng.gen());
+        }
+
+        let rough = HomographyMatrix(Matrix3::new_translation(&Vector2::new(1.0, -0.5)) * h);
+        let hamming = |a: &u64, b: &u64| (a ^ b).count_ones() as f64;
+        let result = guided_matching(
+            &points1,
+            &descriptors1,
+            &points2,
+            &descriptors2,
+            &rough,
+            hamming,
+            GuidedMatchingOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            result.matches,
+            (0..300).map(|ix| (ix, 299 - ix)).collect::<Vec<_>>()
+        );
+        assert!(h.abs_diff_eq(&result.model.0, 0.000001));
+        assert!(result.iterations > 1);
+    }
+
+    #[test]
+    fn refits_on_inliers_only() {
+        let mut rng = Pcg64::seed_from_u64(1);
+        let TestData { matches, h } = TestData::from_rng(&mut rng, 100, 1000.0, 0.0);
+        let points1 = matches.iter().map(|m| m.0).collect::<Vec<_>>();
+        let descriptors1 = (0..100).map(|_| rng.gen::<u64>()).collect::<Vec<_>>();
+        let mut points2 = matches.iter().map(|m| m.1).collect::<Vec<_>>();
+        // The counterparts of the first 20 keypoints are replaced by look-alikes 2.5 px away
+        for p in &mut points2[..20] {
+            *p += Vector2::new(2.5, 0.0);
+        }
+
+        let hamming = |a: &u64, b: &u64| (a ^ b).count_ones() as f64;
+        let result = guided_matching(
+            &points1,
+            &descriptors1,
+            &points2,
+            &descriptors1,
+            &HomographyMatrix(h),
+            hamming,
+            GuidedMatchingOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            result.matches,
+            (0..100).map(|ix| (ix, ix)).collect::<Vec<_>>()
+        );
+        assert!(h.abs_diff_eq(&result.model.0, 0.000001));
+    }
+
+    #[test]
+    fn keeps_the_model_with_too_few_matches() {
+        let mut rng = Pcg64::seed_from_u64(2);
+        let TestData { matches, h } = TestData::from_rng(&mut rng, 3, 1000.0, 0.0);
+        let points1 = matches.iter().map(|m| m.0).collect::<Vec<_>>();
+        let points2 = matches.iter().map(|m| m.1).collect::<Vec<_>>();
+        let descriptors = [1u64, 2, 4];
+        let hamming = |a: &u64, b: &u64| (a ^ b).count_ones() as f64;
+        let result = guided_matching(
+            &points1,
+            &descriptors,
+            &points2,
+            &descriptors,
+            &HomographyMatrix(h),
+            hamming,
+            GuidedMatchingOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(result.matches, vec![(0, 0), (1, 1), (2, 2)]);
+        assert_eq!(result.model.0, h);
+    }
+
+    #[test]
+    fn skips_far_projections_and_tiny_windows() {
+        let points = [
+            Point2::new(0.0, 0.0),
+            Point2::new(100.0, 0.0),
+            Point2::new(0.0, 100.0),
+            Point2::new(100.0, 100.0),
+        ];
+        let descriptors = [1u64, 2, 4, 8];
+        let hamming = |a: &u64, b: &u64| (a ^ b).count_ones() as f64;
+        let run = |model: &HomographyMatrix, radius| {
+            let options = GuidedMatchingOptions {
+                radius,
+                // Later rounds would lose the matches to the rounding errors of the refit
+                max_iterations: 1,
+                ..Default::default()
+            };
+            guided_matching(
+                &points,
+                &descriptors,
+                &points,
+                &descriptors,
+                model,
+                hamming,
+                options,
+            )
+        };
+        let identity = HomographyMatrix(Matrix3::identity());
+
+        // Only the origin stays in place, the other points are projected far away or to the line
+        // at infinity
+        #[rustfmt::skip]
+        let pole = HomographyMatrix(Matrix3::new(
+            1e300, 0.0, 0.0,
+            0.0, 1.0, 0.0,
+            0.0, -0.01, 1.0,
+        ));
+        assert_eq!(run(&pole, 3.0).unwrap().matches, vec![(0, 0)]);
+        // The cell indices of a tiny radius saturate
+        let result = run(&identity, 1e-300).unwrap();
+        assert_eq!(result.matches, vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+
+        assert!(run(&identity, 0.0).is_err());
+        assert!(run(&identity, -1.0).is_err());
+        assert!(run(&identity, f64::NAN).is_err());
+    }
+}
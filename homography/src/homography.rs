@@ -1,12 +1,17 @@
 use cv_core::FeatureMatch;
 use eyre::{eyre, Result};
-use itertools::Itertools;
 use na::Const;
-use nalgebra::{self as na, Matrix3, SMatrix};
+use nalgebra::{self as na, Matrix3, SMatrix, SVector};
 type Point2 = na::Point2<f64>;
 use derive_more::{AsMut, AsRef, Deref, DerefMut, Display, From, Into};
 use sample_consensus::{Estimator, Model};
 
+use crate::DltAccumulator;
+
+/// Residual of matches that are mapped to infinity by the homography.
+// TODO is there a "correct" value to use here?
+pub(crate) const UNPROJECTABLE_RESIDUAL: f64 = 99999.9;
+
 #[derive(
     Debug, Clone, Copy, PartialEq, PartialOrd, AsMut, AsRef, Deref, DerefMut, Display, From, Into,
 )]
@@ -22,15 +27,104 @@ impl Model<FeatureMatch<Point2>> for HomographyMatrix {
         if let Some(b2) = b2 {
             na::distance_squared(b, &b2)
         } else {
-            // TODO is there a "correct" value to use here?
-            99999.9
+            UNPROJECTABLE_RESIDUAL
+        }
+    }
+}
+
+/// A [`FeatureMatch`] paired with a confidence weight.
+///
+/// Weights are relative to each other, a match with weight `2.0` counts twice as much as one with
+/// weight `1.0` in the fit, and is twice as likely to be sampled by a weighted
+/// `Ransac` (crate feature `ransac`). The residual of a match is its reprojection error whatever
+/// its weight, so the inlier threshold stays in pixels. Matches with a non-positive weight are
+/// ignored by the fitting and never count as inliers.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct WeightedFeatureMatch(pub FeatureMatch<Point2>, pub f64);
+
+impl From<FeatureMatch<Point2>> for WeightedFeatureMatch {
+    fn from(feature_match: FeatureMatch<Point2>) -> Self {
+        Self(feature_match, 1.0)
+    }
+}
+
+impl Model<WeightedFeatureMatch> for HomographyMatrix {
+    /// The squared reprojection error of the match, infinite for a non-positive weight.
+    fn residual(&self, data: &WeightedFeatureMatch) -> f64 {
+        let WeightedFeatureMatch(feature_match, weight) = data;
+        if *weight > 0.0 {
+            Model::<FeatureMatch<Point2>>::residual(self, feature_match)
+        } else {
+            f64::INFINITY
         }
     }
 }
 
 /// Implements [`cv::Estimator`](https://docs.rs/cv/0.6.0/cv/trait.Estimator.html)
+///
+/// Solves with the default [`HomographyOptions`], see [`HomographyEstimator::with_options`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub struct HomographyEstimator {}
 
+impl HomographyEstimator {
+    /// Estimator with the given options of the minimal solver
+    pub fn with_options(options: HomographyOptions) -> HomographyEstimatorWithOptions {
+        HomographyEstimatorWithOptions { options }
+    }
+}
+
+/// A [`HomographyEstimator`] with options of the minimal solver.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct HomographyEstimatorWithOptions {
+    pub options: HomographyOptions,
+}
+
+impl HomographyEstimatorWithOptions {
+    /// Solves for a sample of the minimal size, copying it into an array on the stack first
+    /// so the (potentially expensive) sample iterator is walked only once.
+    fn estimate_minimal<M>(&self, data: impl Iterator<Item = M>) -> Option<HomographyMatrix>
+    where
+        M: Into<WeightedFeatureMatch>,
+    {
+        let mut sample =
+            [WeightedFeatureMatch(FeatureMatch(Point2::origin(), Point2::origin()), 0.0); 4];
+        let mut len = 0;
+        for (slot, m) in sample.iter_mut().zip(data) {
+            *slot = m.into();
+            len += 1;
+        }
+        find_homography_iter(sample[..len].iter().copied(), self.options)
+            .ok()
+            .map(HomographyMatrix)
+    }
+}
+
+impl Estimator<FeatureMatch<Point2>> for HomographyEstimatorWithOptions {
+    type Model = HomographyMatrix;
+    type ModelIter = Option<HomographyMatrix>;
+    const MIN_SAMPLES: usize = 4;
+
+    fn estimate<I>(&self, data: I) -> Self::ModelIter
+    where
+        I: Iterator<Item = FeatureMatch<Point2>> + Clone,
+    {
+        self.estimate_minimal(data)
+    }
+}
+
+impl Estimator<WeightedFeatureMatch> for HomographyEstimatorWithOptions {
+    type Model = HomographyMatrix;
+    type ModelIter = Option<HomographyMatrix>;
+    const MIN_SAMPLES: usize = 4;
+
+    fn estimate<I>(&self, data: I) -> Self::ModelIter
+    where
+        I: Iterator<Item = WeightedFeatureMatch> + Clone,
+    {
+        self.estimate_minimal(data)
+    }
+}
+
 impl Estimator<FeatureMatch<Point2>> for HomographyEstimator {
     type Model = HomographyMatrix;
     type ModelIter = Option<HomographyMatrix>;
@@ -40,45 +134,220 @@ impl Estimator<FeatureMatch<Point2>> for HomographyEstimator {
     where
         I: Iterator<Item = FeatureMatch<Point2>> + Clone,
     {
-        let matches = data.take(Self::MIN_SAMPLES).collect_vec();
-        if let Ok(homography_matrix) = find_homography(matches) {
-            Some(HomographyMatrix(homography_matrix))
-        } else {
-            None
-        }
+        HomographyEstimatorWithOptions::default().estimate(data)
     }
 }
 
+impl Estimator<WeightedFeatureMatch> for HomographyEstimator {
+    type Model = HomographyMatrix;
+    type ModelIter = Option<HomographyMatrix>;
+    const MIN_SAMPLES: usize = 4;
+
+    fn estimate<I>(&self, data: I) -> Self::ModelIter
+    where
+        I: Iterator<Item = WeightedFeatureMatch> + Clone,
+    {
+        HomographyEstimatorWithOptions::default().estimate(data)
+    }
+}
+
+/// How the points are normalized before solving the linear system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Normalization {
+    /// Shifts the centroid to the origin and scales the axes separately so the mean absolute
+    /// deviation is one along both of them. This is what OpenCV does.
+    #[default]
+    Anisotropic,
+    /// Hartley's normalization: shifts the centroid to the origin and scales uniformly so the
+    /// average distance from the origin is √2.
+    Isotropic,
+}
+
+/// How the homogeneous linear system of the DLT is solved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Solver {
+    /// Eigen decomposition of the 9×9 normal matrix LᵀL, like OpenCV. Cheap, but squares the
+    /// condition number of the problem.
+    #[default]
+    NormalEquations,
+    /// SVD of the 2N×9 design matrix. It's reduced to a 9×9 triangular factor with Givens rotations
+    /// as the rows are added, so it has the same memory footprint as [`Solver::NormalEquations`],
+    /// then the factor is decomposed with one-sided Jacobi rotations.
+    Svd,
+}
+
+/// Options of the homography estimation.
+///
+/// The default matches the OpenCV implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HomographyOptions {
+    pub normalization: Normalization,
+    pub solver: Solver,
+}
+
 /// Computes the perpective transformation for a set of point matches.
 /// [OpenCV implementation](https://github.com/opencv/opencv/blob/a1143c4ea02afa7c45c2a1e86be431b81a83bcd1/modules/calib3d/src/fundam.cpp#L118-L183)
 pub fn find_homography(matches: Vec<FeatureMatch<Point2>>) -> Result<Matrix3<f64>> {
-    // TODO detect degenerate cases
-    let (m1, m2): (Vec<_>, Vec<_>) = matches.iter().map(|m| (m.0, m.1)).unzip();
+    find_homography_with_options(matches, HomographyOptions::default())
+}
+
+/// Computes the perpective transformation for a set of point matches using the given options.
+pub fn find_homography_with_options(
+    matches: Vec<FeatureMatch<Point2>>,
+    options: HomographyOptions,
+) -> Result<Matrix3<f64>> {
+    dlt(matches.into_iter().map(WeightedFeatureMatch::from), options)
+}
+
+/// Computes the perpective transformation for a set of weighted point matches.
+///
+/// Works like [`find_homography`], but each match contributes to the normalization and to the
+/// least squares fit proportionally to its weight. With all weights equal to `1.0` the result is
+/// the same as the one of [`find_homography`].
+pub fn find_homography_weighted(matches: Vec<WeightedFeatureMatch>) -> Result<Matrix3<f64>> {
+    find_homography_weighted_with_options(matches, HomographyOptions::default())
+}
+
+/// Computes the perpective transformation for a set of weighted point matches using the given
+/// options.
+pub fn find_homography_weighted_with_options(
+    matches: Vec<WeightedFeatureMatch>,
+    options: HomographyOptions,
+) -> Result<Matrix3<f64>> {
+    dlt(matches.into_iter(), options)
+}
+
+/// Computes the perpective transformation for a slice of point matches.
+///
+/// Unlike [`find_homography`] it doesn't take ownership of the matches and doesn't allocate.
+pub fn find_homography_slice(matches: &[FeatureMatch<Point2>]) -> Result<Matrix3<f64>> {
+    find_homography_iter(matches.iter().copied(), HomographyOptions::default())
+}
 
-    let count = m1.len();
+/// Computes the perpective transformation from an iterator of (weighted) point matches.
+///
+/// The iterator is cloned and walked three times (normalization, scaling and accumulation)
+/// instead of collecting the matches, so the estimation runs without heap allocations.
+pub fn find_homography_iter<I, M>(matches: I, options: HomographyOptions) -> Result<Matrix3<f64>>
+where
+    I: IntoIterator<Item = M>,
+    I::IntoIter: Clone,
+    M: Into<WeightedFeatureMatch>,
+{
+    dlt(matches.into_iter().map(Into::into), options)
+}
+
+/// Affine transformation that centers and scales a point set: `p' = (p - center) * scale`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NormalizationTransform {
+    pub center: Point2,
+    pub scale: na::Vector2<f64>,
+}
+
+impl NormalizationTransform {
+    pub fn new(center: Point2, scale: na::Vector2<f64>) -> Self {
+        Self { center, scale }
+    }
+
+    /// Maps the `width`×`height` image to the [-1, 1] range on both axes.
+    ///
+    /// Useful when the normalization has to be fixed before seeing the matches.
+    pub fn from_image_size(width: f64, height: f64) -> Self {
+        Self::new(
+            Point2::new(width / 2.0, height / 2.0),
+            na::Vector2::new(2.0 / width, 2.0 / height),
+        )
+    }
+
+    pub fn apply(&self, p: &Point2) -> Point2 {
+        Point2::new(
+            (p.x - self.center.x) * self.scale.x,
+            (p.y - self.center.y) * self.scale.y,
+        )
+    }
+
+    pub fn matrix(&self) -> Matrix3<f64> {
+        let Self {
+            center: c,
+            scale: s,
+        } = self;
+        Matrix3::new(s.x, 0., -c.x * s.x, 0., s.y, -c.y * s.y, 0., 0., 1.)
+    }
+
+    pub fn inverse_matrix(&self) -> Matrix3<f64> {
+        let Self {
+            center: c,
+            scale: s,
+        } = self;
+        Matrix3::new(1. / s.x, 0., c.x, 0., 1. / s.y, c.y, 0., 0., 1.)
+    }
+}
+
+/// Computes the normalization transforms of the source and the destination points.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "trace", skip_all, fields(?normalization, matches))
+)]
+pub(crate) fn normalization_transforms<I>(
+    matches: I,
+    normalization: Normalization,
+) -> Result<(NormalizationTransform, NormalizationTransform)>
+where
+    I: Iterator<Item = WeightedFeatureMatch> + Clone,
+{
+    let mut count = 0;
+    let mut weight_sum = 0.0;
     let mut c2 = Point2::origin();
     let mut c1 = Point2::origin();
 
-    for i in 0..count {
-        c2.x += m2[i].x;
-        c2.y += m2[i].y;
-        c1.x += m1[i].x;
-        c1.y += m1[i].y;
+    for WeightedFeatureMatch(FeatureMatch(m1, m2), w) in matches.clone() {
+        count += 1;
+        weight_sum += w;
+        c2.x += w * m2.x;
+        c2.y += w * m2.y;
+        c1.x += w * m1.x;
+        c1.y += w * m1.y;
+    }
+
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("matches", count);
+    if count < 4 {
+        return Err(eyre!("At least 4 matches are needed, got {}", count));
+    }
+    if weight_sum < f64::EPSILON {
+        return Err(eyre!("Sum of the match weights must be positive"));
     }
 
-    c2.x /= count as f64;
-    c2.y /= count as f64;
-    c1.x /= count as f64;
-    c1.y /= count as f64;
+    c2.x /= weight_sum;
+    c2.y /= weight_sum;
+    c1.x /= weight_sum;
+    c1.y /= weight_sum;
 
-    let mut s2 = Point2::origin();
-    let mut s1 = Point2::origin();
+    let mut s2 = na::Vector2::zeros();
+    let mut s1 = na::Vector2::zeros();
 
-    for i in 0..count {
-        s2.x += (c2.x - m2[i].x).abs();
-        s2.y += (c2.y - m2[i].y).abs();
-        s1.x += (c1.x - m1[i].x).abs();
-        s1.y += (c1.y - m1[i].y).abs();
+    match normalization {
+        Normalization::Anisotropic => {
+            for WeightedFeatureMatch(FeatureMatch(m1, m2), w) in matches {
+                s2.x += w * (c2.x - m2.x).abs();
+                s2.y += w * (c2.y - m2.y).abs();
+                s1.x += w * (c1.x - m1.x).abs();
+                s1.y += w * (c1.y - m1.y).abs();
+            }
+        }
+        Normalization::Isotropic => {
+            for WeightedFeatureMatch(FeatureMatch(m1, m2), w) in matches {
+                let d2 = w * na::distance(&c2, &m2);
+                let d1 = w * na::distance(&c1, &m1);
+                s2.x += d2;
+                s2.y += d2;
+                s1.x += d1;
+                s1.y += d1;
+            }
+            // The target distance is √2 instead of 1
+            s2 /= std::f64::consts::SQRT_2;
+            s1 /= std::f64::consts::SQRT_2;
+        }
     }
 
     if s2.x.abs() < f64::EPSILON
@@ -89,57 +358,162 @@ pub fn find_homography(matches: Vec<FeatureMatch<Point2>>) -> Result<Matrix3<f64
         return Err(eyre!("Points are too close to each other"));
     }
 
-    s2.x = count as f64 / s2.x;
-    s2.y = count as f64 / s2.y;
-    s1.x = count as f64 / s1.x;
-    s1.y = count as f64 / s1.y;
-
-    let inv_h_norm = Matrix3::new(1. / s2.x, 0., c2.x, 0., 1. / s2.y, c2.y, 0., 0., 1.);
-    let h_norm2 = Matrix3::new(s1.x, 0., -c1.x * s1.x, 0., s1.y, -c1.y * s1.y, 0., 0., 1.);
-
-    let mut ltl: SMatrix<f64, 9, 9> = SMatrix::zeros();
-    for i in 0..count {
-        let x2 = (m2[i].x - c2.x) * s2.x;
-        let y2 = (m2[i].y - c2.y) * s2.y;
-        let x1 = (m1[i].x - c1.x) * s1.x;
-        let y1 = (m1[i].y - c1.y) * s1.y;
-        let lx = [x1, y1, 1., 0., 0., 0., -x2 * x1, -x2 * y1, -x2];
-        let ly = [0., 0., 0., x1, y1, 1., -y2 * x1, -y2 * y1, -y2];
-        // println!("{} lx {:?} ly {:?}", i, lx, ly);
-        for j in 0..9 {
-            for k in 0..9 {
-                ltl[(j, k)] += lx[j] * lx[k] + ly[j] * ly[k];
+    s2.x = weight_sum / s2.x;
+    s2.y = weight_sum / s2.y;
+    s1.x = weight_sum / s1.x;
+    s1.y = weight_sum / s1.y;
+
+    Ok((
+        NormalizationTransform {
+            center: c1,
+            scale: s1,
+        },
+        NormalizationTransform {
+            center: c2,
+            scale: s2,
+        },
+    ))
+}
+
+/// The two rows of the DLT design matrix for a (normalized) point pair.
+pub(crate) fn dlt_rows(p1: &Point2, p2: &Point2) -> ([f64; 9], [f64; 9]) {
+    let (x1, y1, x2, y2) = (p1.x, p1.y, p2.x, p2.y);
+    let lx = [x1, y1, 1., 0., 0., 0., -x2 * x1, -x2 * y1, -x2];
+    let ly = [0., 0., 0., x1, y1, 1., -y2 * x1, -y2 * y1, -y2];
+    (lx, ly)
+}
+
+/// Rotates `row` into the upper triangular matrix `r` with Givens rotations,
+/// so `r` stays the R factor of the QR decomposition of all the rows added so far.
+pub(crate) fn givens_update(r: &mut SMatrix<f64, 9, 9>, mut row: [f64; 9]) {
+    for i in 0..9 {
+        if row[i] == 0.0 {
+            continue;
+        }
+        let a = r[(i, i)];
+        let b = row[i];
+        let norm = a.hypot(b);
+        let (c, s) = (a / norm, b / norm);
+        r[(i, i)] = norm;
+        row[i] = 0.0;
+        for j in i + 1..9 {
+            let rij = r[(i, j)];
+            r[(i, j)] = c * rij + s * row[j];
+            row[j] = c * row[j] - s * rij;
+        }
+    }
+}
+
+/// Right singular vector of the smallest singular value, computed with one-sided Jacobi rotations.
+///
+/// It's accurate even for the tiny singular values of nearly exact fits,
+/// where the SVD of nalgebra 0.30 fails to converge to the right values.
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+pub(crate) fn smallest_right_singular_vector(mut a: SMatrix<f64, 9, 9>) -> SVector<f64, 9> {
+    let mut v = SMatrix::<f64, 9, 9>::identity();
+    for _ in 0..64 {
+        let mut rotated = false;
+        for p in 0..8 {
+            for q in p + 1..9 {
+                let alpha = a.column(p).norm_squared();
+                let beta = a.column(q).norm_squared();
+                let gamma = a.column(p).dot(&a.column(q));
+                if gamma.abs() <= f64::EPSILON * (alpha * beta).sqrt() {
+                    continue;
+                }
+                rotated = true;
+                // Rotation that makes columns p and q orthogonal
+                let zeta = (beta - alpha) / (2.0 * gamma);
+                let t = zeta.signum() / (zeta.abs() + (1.0 + zeta * zeta).sqrt());
+                let c = 1.0 / (1.0 + t * t).sqrt();
+                let s = c * t;
+                for m in [&mut a, &mut v] {
+                    for k in 0..9 {
+                        let (mp, mq) = (m[(k, p)], m[(k, q)]);
+                        m[(k, p)] = c * mp - s * mq;
+                        m[(k, q)] = s * mp + c * mq;
+                    }
+                }
             }
         }
+        if !rotated {
+            break;
+        }
     }
+    // The singular values are the norms of the orthogonalized columns
+    let (idx, _) = SVector::<f64, 9>::from_fn(|i, _| a.column(i).norm_squared()).argmin();
+    v.column(idx).clone_owned()
+}
 
-    ltl.fill_lower_triangle_with_upper_triangle();
-    let eigen = ltl.symmetric_eigen();
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "trace", skip_all, fields(?options.solver))
+)]
+fn dlt<I>(matches: I, options: HomographyOptions) -> Result<Matrix3<f64>>
+where
+    I: Iterator<Item = WeightedFeatureMatch> + Clone,
+{
+    // TODO detect degenerate cases
+    let matches = matches.filter(|WeightedFeatureMatch(_, w)| *w > 0.0);
 
-    let (eigen_vector_idx, _) = eigen.eigenvalues.argmin();
-    let h0 = eigen.eigenvectors.column(eigen_vector_idx);
-    let h0 = h0
-        .clone_owned()
-        .reshape_generic(Const::<3>, Const::<3>)
-        .transpose();
+    let (norm1, norm2) = normalization_transforms(matches.clone(), options.normalization)?;
+    match options.solver {
+        Solver::NormalEquations => {
+            let mut accumulator = DltAccumulator::new(norm1, norm2);
+            accumulator.extend(matches);
+            accumulator.solve()
+        }
+        Solver::Svd => {
+            #[cfg(feature = "tracing")]
+            let accumulate_span = tracing::trace_span!("accumulate").entered();
+            let mut r: SMatrix<f64, 9, 9> = SMatrix::zeros();
+            for WeightedFeatureMatch(FeatureMatch(m1, m2), w) in matches {
+                let (lx, ly) = dlt_rows(&norm1.apply(&m1), &norm2.apply(&m2));
+                let w = w.sqrt();
+                givens_update(&mut r, lx.map(|l| l * w));
+                givens_update(&mut r, ly.map(|l| l * w));
+            }
+            #[cfg(feature = "tracing")]
+            accumulate_span.exit();
 
-    let res = (inv_h_norm * h0) * h_norm2;
-    let res = res * (1.0 / res[(2, 2)]);
+            let h0 = smallest_right_singular_vector(r);
+            Ok(denormalize(h0, &norm1, &norm2))
+        }
+    }
+}
+
+/// Reshapes the solution of the normalized system into a homography between the original points.
+pub(crate) fn denormalize(
+    h0: SVector<f64, 9>,
+    norm1: &NormalizationTransform,
+    norm2: &NormalizationTransform,
+) -> Matrix3<f64> {
+    let h0 = h0.reshape_generic(Const::<3>, Const::<3>).transpose();
 
-    Ok(res)
+    let res = (norm2.inverse_matrix() * h0) * norm1.matrix();
+    res * (1.0 / res[(2, 2)])
 }
 
 // TODO reimplement all tests from https://github.com/opencv/opencv/blob/4.x/modules/calib3d/test/test_homography.cpp
 #[cfg(test)]
 pub mod tests {
-    use crate::find_homography;
+    use crate::{
+        find_homography, find_homography_weighted, find_homography_with_options, HomographyMatrix,
+        HomographyOptions, Normalization, Solver, WeightedFeatureMatch,
+    };
     use approx::AbsDiffEq;
+    use cv_core::FeatureMatch;
+    use nalgebra::{Matrix3, Point2};
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+    use sample_consensus::Model;
     use test_utils::TestData;
 
     #[test]
     fn it_works() {
+        let mut rng = Pcg64::seed_from_u64(0);
         for _ in 0..24 {
-            let TestData { matches, h: h_src } = TestData::new(48);
+            let TestData { matches, h: h_src } = TestData::from_rng(&mut rng, 48, 100.0, 0.0);
             let h = find_homography(matches).unwrap();
 
             let max_diff = 0.000001;
@@ -150,4 +524,134 @@ pub mod tests {
             assert!((h_src - h).norm() < max_diff, "L2 norm is too large");
         }
     }
+
+    #[test]
+    fn weighted_matches() {
+        let mut rng = Pcg64::seed_from_u64(1);
+        for _ in 0..24 {
+            let TestData { matches, h: h_src } = TestData::from_rng(&mut rng, 48, 100.0, 0.0);
+            let mut weighted = matches
+                .iter()
+                .map(|&m| WeightedFeatureMatch(m, 1.0))
+                .collect::<Vec<_>>();
+
+            // Unit weights give the same result as the unweighted estimation
+            let h_unweighted = find_homography(matches).unwrap();
+            let h = find_homography_weighted(weighted.clone()).unwrap();
+            assert!(h_unweighted.abs_diff_eq(&h, 1e-9));
+
+            // A gross outlier with a tiny weight barely affects the estimate
+            weighted.push(WeightedFeatureMatch(
+                FeatureMatch(Point2::new(10.0, 10.0), Point2::new(90.0, -40.0)),
+                1e-9,
+            ));
+            let h = find_homography_weighted(weighted).unwrap();
+            assert!(h_src.abs_diff_eq(&h, 0.0001), "outlier was not suppressed");
+        }
+    }
+
+    #[test]
+    fn weighted_residual_is_in_pixels() {
+        let h = HomographyMatrix(Matrix3::identity());
+        let m = FeatureMatch(Point2::new(10.0, 10.0), Point2::new(13.0, 14.0));
+        for weight in [0.01, 1.0, 100.0] {
+            let residual =
+                Model::<WeightedFeatureMatch>::residual(&h, &WeightedFeatureMatch(m, weight));
+            assert_eq!(residual, 25.0);
+        }
+        let residual = Model::<WeightedFeatureMatch>::residual(&h, &WeightedFeatureMatch(m, 0.0));
+        assert_eq!(residual, f64::INFINITY);
+    }
+
+    const ALL_OPTIONS: [HomographyOptions; 4] = [
+        HomographyOptions {
+            normalization: Normalization::Anisotropic,
+            solver: Solver::NormalEquations,
+        },
+        HomographyOptions {
+            normalization: Normalization::Anisotropic,
+            solver: Solver::Svd,
+        },
+        HomographyOptions {
+            normalization: Normalization::Isotropic,
+            solver: Solver::NormalEquations,
+        },
+        HomographyOptions {
+            normalization: Normalization::Isotropic,
+            solver: Solver::Svd,
+        },
+    ];
+
+    /// Mean distance between the points transformed with the estimated and the true homography
+    fn transfer_error(
+        matches: &[FeatureMatch<Point2<f64>>],
+        h: &Matrix3<f64>,
+        h_src: &Matrix3<f64>,
+    ) -> f64 {
+        let sum: f64 = matches
+            .iter()
+            .map(|FeatureMatch(a, _)| {
+                let b = Point2::from_homogeneous(h * a.to_homogeneous()).unwrap();
+                let b_src = Point2::from_homogeneous(h_src * a.to_homogeneous()).unwrap();
+                nalgebra::distance(&b, &b_src)
+            })
+            .sum();
+        sum / matches.len() as f64
+    }
+
+    #[test]
+    fn all_options_work() {
+        let mut rng = Pcg64::seed_from_u64(2);
+        for options in ALL_OPTIONS {
+            for _ in 0..24 {
+                let TestData { matches, h: h_src } = TestData::from_rng(&mut rng, 48, 100.0, 0.0);
+                let h = find_homography_with_options(matches, options).unwrap();
+                assert!(
+                    h_src.abs_diff_eq(&h, 0.000001),
+                    "absolute difference is too large with {:?}",
+                    options
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn options_accuracy_under_noise() {
+        use rand::SeedableRng;
+        use rand_pcg::Pcg64;
+
+        // (image size, noise std) pairs, up to large images with points far from the origin
+        let scenarios = [(100.0, 0.5), (1000.0, 1.0), (10000.0, 2.0)];
+        let mut rng = Pcg64::seed_from_u64(4);
+        for (img_size, noise_std) in scenarios {
+            let mut errors = [0.0; ALL_OPTIONS.len()];
+            let runs = 50;
+            for _ in 0..runs {
+                let TestData { matches, h: h_src } =
+                    TestData::projective_with_noise(&mut rng, 200, img_size, noise_std);
+                for (error, options) in errors.iter_mut().zip(ALL_OPTIONS) {
+                    let h = find_homography_with_options(matches.clone(), options).unwrap();
+                    *error += transfer_error(&matches, &h, &h_src) / runs as f64;
+                }
+            }
+            for (error, options) in errors.iter().zip(ALL_OPTIONS) {
+                // The least squares estimate should average out most of the noise
+                assert!(
+                    *error < noise_std / 2.0,
+                    "transfer error {} is too large with {:?}",
+                    error,
+                    options
+                );
+            }
+            // Hartley's normalization with the SVD is at least as accurate as OpenCV's defaults
+            let [opencv, .., hartley_svd] = errors;
+            assert!(
+                hartley_svd <= opencv,
+                "{} > {} at image size {}",
+                hartley_svd,
+                opencv,
+                img_size
+            );
+        }
+    }
 }
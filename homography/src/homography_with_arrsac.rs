@@ -4,15 +4,170 @@ use rand::SeedableRng;
 use rand_pcg::Pcg64;
 use sample_consensus::Consensus;
 
-use crate::{HomographyEstimator, HomographyMatrix};
+use crate::{
+    Budget, BudgetedEstimator, ConsensusObserver, FeatureMatchSoa, HomographyEstimator,
+    HomographyMatrix, Hypothesis, ObservedEstimator, RobustEstimate, SoaEstimator, SoaModel,
+    Termination, WeightedFeatureMatch,
+};
 
 type Point2 = nalgebra::Point2<f64>;
 
 /// Find homography with the [ARRSAC](https://docs.rs/arrsac/latest/arrsac/) sample consensus algorithm.  
 /// *This is supported on **crate feature `arrsac-sc`** only.*
+///
+/// The hypotheses are scored on a [`FeatureMatchSoa`] through a [`SoaEstimator`].
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "consensus", level = "debug", skip_all, fields(matches = matches.len()))
+)]
 pub fn find_homography_with_arrsac(matches: &[FeatureMatch<Point2>]) -> Option<HomographyMatrix> {
     let mut arrsac = Arrsac::new(0.1, Pcg64::from_seed([1; 32]));
+    let matches = FeatureMatchSoa::from(matches);
     let estimator = HomographyEstimator {};
+    let estimator = SoaEstimator::new(&estimator, &matches);
     // TODO shuffle matches?
-    arrsac.model(&estimator, matches.iter().cloned())
+    arrsac
+        .model(&estimator, estimator.indices())
+        .map(SoaModel::into_homography)
+}
+
+/// Find homography with ARRSAC using the confidence weights of the matches.  
+/// *This is supported on **crate feature `arrsac-sc`** only.*
+///
+/// The weights only weight the least squares fit of every sample. ARRSAC doesn't take sampling
+/// probabilities, so the matches are sampled uniformly, whatever their weights. To sample the
+/// confident matches more often, use `find_homography_with_ransac_weighted` (crate feature
+/// `ransac`).
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "consensus", level = "debug", skip_all, fields(matches = matches.len()))
+)]
+pub fn find_homography_with_arrsac_weighted(
+    matches: &[WeightedFeatureMatch],
+) -> Option<HomographyMatrix> {
+    let mut arrsac = Arrsac::new(0.1, Pcg64::from_seed([1; 32]));
+    let estimator = HomographyEstimator {};
+    arrsac.model(&estimator, matches.iter().copied())
+}
+
+/// Find homography with ARRSAC within the limits of a [`Budget`].  
+/// *This is supported on **crate feature `arrsac-sc`** only.*
+///
+/// When the budget runs out, no more hypotheses are generated and the best one of those generated
+/// so far is returned with [`RobustEstimate::terminated_early`] set.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "consensus", level = "debug", skip_all, fields(matches = matches.len()))
+)]
+pub fn find_homography_with_arrsac_budget(
+    matches: &[FeatureMatch<Point2>],
+    budget: &Budget,
+) -> Option<RobustEstimate<HomographyMatrix>> {
+    let mut arrsac = Arrsac::new(0.1, Pcg64::from_seed([1; 32]));
+    let matches = FeatureMatchSoa::from(matches);
+    let estimator = HomographyEstimator {};
+    let estimator = SoaEstimator::new(&estimator, &matches);
+    let indices = estimator.indices();
+    let estimator = BudgetedEstimator::new(&estimator, budget);
+    arrsac
+        .model_inliers(&estimator, indices)
+        .map(|(model, inliers)| RobustEstimate {
+            model: model.into_homography(),
+            inliers,
+            terminated_early: estimator.terminated_early(),
+        })
+}
+
+/// Find homography with ARRSAC and report its progress to `observer`.  
+/// *This is supported on **crate feature `arrsac-sc`** only.*
+///
+/// Same as [`find_homography_with_arrsac`], but every hypothesis is also scored on all the matches
+/// for the observer, see [`ObservedEstimator`].
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "consensus", level = "debug", skip_all, fields(matches = matches.len()))
+)]
+pub fn find_homography_with_arrsac_observed<O>(
+    matches: &[FeatureMatch<Point2>],
+    observer: &mut O,
+) -> Option<HomographyMatrix>
+where
+    O: ConsensusObserver<HomographyMatrix>,
+{
+    let threshold = 0.1;
+    let mut arrsac = Arrsac::new(threshold, Pcg64::from_seed([1; 32]));
+    let matches = FeatureMatchSoa::from(matches);
+    let estimator = HomographyEstimator {};
+    let estimator = SoaEstimator::new(&estimator, &matches);
+    let indices = estimator.indices();
+    let mut observer = HomographyObserver(observer);
+    let estimator = ObservedEstimator::new(&estimator, matches.len(), threshold, &mut observer);
+    let result = arrsac.model_inliers(&estimator, indices);
+    estimator.finish(result.as_ref().map(|(_, inliers)| inliers.len()), false);
+    result.map(|(model, _)| model.into_homography())
+}
+
+/// Reports the homographies of [`SoaModel`]s to an observer of [`HomographyMatrix`]es.
+struct HomographyObserver<'a, O>(&'a mut O);
+
+impl<O> ConsensusObserver<SoaModel<'_>> for HomographyObserver<'_, O>
+where
+    O: ConsensusObserver<HomographyMatrix>,
+{
+    fn hypothesis(&mut self, hypothesis: &Hypothesis<SoaModel>) {
+        self.0.hypothesis(&Hypothesis {
+            iteration: hypothesis.iteration,
+            sample: hypothesis.sample,
+            model: hypothesis.model.homography(),
+            score: hypothesis.score,
+            new_best: hypothesis.new_best,
+        });
+    }
+
+    fn failed_sample(&mut self, iteration: usize, sample: &[usize]) {
+        self.0.failed_sample(iteration, sample);
+    }
+
+    fn termination(&mut self, termination: &Termination) {
+        self.0.termination(termination);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{find_homography_with_arrsac_observed, RecordingObserver};
+    use approx::AbsDiffEq;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+    use sample_consensus::Model;
+    use test_utils::TestData;
+
+    #[test]
+    fn observer_sees_the_hypotheses() {
+        let mut rng = Pcg64::seed_from_u64(0);
+        let TestData { matches, h: h_src } = TestData::with_outliers_from_rng(&mut rng, 300, 0.4);
+        let mut observer = RecordingObserver::new();
+        let h = find_homography_with_arrsac_observed(&matches, &mut observer).unwrap();
+        assert!(h_src.abs_diff_eq(&h.0, 0.0001));
+
+        let termination = observer.termination.unwrap();
+        assert!(termination.iterations >= observer.hypotheses.len());
+        assert!(!observer.hypotheses.is_empty());
+        let inliers = matches.iter().filter(|m| h.residual(*m) < 0.1).count();
+        assert_eq!(termination.best_score, Some(inliers));
+        assert!(observer
+            .hypotheses
+            .iter()
+            .any(|hypothesis| hypothesis.model == h));
+        for hypothesis in &observer.hypotheses {
+            assert_eq!(hypothesis.sample.len(), 4);
+            let score = matches
+                .iter()
+                .filter(|m| hypothesis.model.residual(*m) < 0.1)
+                .count();
+            assert_eq!(hypothesis.score, score);
+        }
+        let history = observer.best_score_history();
+        assert!(history.windows(2).all(|w| w[0] <= w[1]));
+    }
 }
@@ -0,0 +1,193 @@
+use akaze::{Akaze, KeyPoint};
+use cv_core::FeatureMatch;
+use eyre::{eyre, Result};
+use image::DynamicImage;
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+
+use crate::{
+    feature_matches, match_descriptors, DescriptorMatch, HomographyEstimator, HomographyMatrix,
+    HomographyOptions, HomographyWorkspace, MatchingOptions, Ransac,
+};
+
+type Point2 = nalgebra::Point2<f64>;
+
+/// Options of [`find_homography_from_images`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImagePairOptions {
+    /// Matching of the AKAZE descriptors
+    pub matching: MatchingOptions,
+    /// Maximum squared reprojection error of an inlier, in pixels
+    pub inlier_threshold: f64,
+    /// Seed of the [`Ransac`] random number generator
+    pub seed: u64,
+    /// Options of the least squares fit on the inliers
+    pub homography: HomographyOptions,
+}
+
+impl Default for ImagePairOptions {
+    fn default() -> Self {
+        Self {
+            matching: MatchingOptions {
+                cross_check: true,
+                ..Default::default()
+            },
+            inlier_threshold: 4.0,
+            seed: 0,
+            homography: HomographyOptions::default(),
+        }
+    }
+}
+
+/// Result of [`find_homography_from_images`].
+#[derive(Debug, Clone)]
+pub struct ImagePairHomography {
+    /// Transforms the points of the first image to the second one
+    pub homography: HomographyMatrix,
+    /// Keypoints detected on the first image
+    pub keypoints1: Vec<KeyPoint>,
+    /// Keypoints detected on the second image
+    pub keypoints2: Vec<KeyPoint>,
+    /// Matches between the keypoints
+    pub matches: Vec<DescriptorMatch>,
+    /// Whether each of the `matches` is an inlier of the homography
+    pub inliers: Vec<bool>,
+}
+
+impl ImagePairHomography {
+    /// Coordinates of the matched keypoints, in the order of [`Self::matches`].
+    pub fn feature_matches(&self) -> Vec<FeatureMatch<Point2>> {
+        feature_matches(
+            &self.matches,
+            &keypoint_positions(&self.keypoints1),
+            &keypoint_positions(&self.keypoints2),
+        )
+    }
+
+    /// Coordinates of the inlier matches.
+    pub fn inlier_matches(&self) -> Vec<FeatureMatch<Point2>> {
+        self.feature_matches()
+            .into_iter()
+            .zip(&self.inliers)
+            .filter(|(_, inlier)| **inlier)
+            .map(|(feature_match, _)| feature_match)
+            .collect()
+    }
+}
+
+/// Estimates the homography between two images.
+/// *This is supported on **crate feature `akaze`** only.*
+///
+/// Extracts AKAZE keypoints from both images, matches their descriptors, finds the homography with
+/// [`Ransac`] and re-fits it on the inliers. With the `rayon` feature the two images are processed
+/// in parallel.
+pub fn find_homography_from_images(
+    image1: &DynamicImage,
+    image2: &DynamicImage,
+    options: ImagePairOptions,
+) -> Result<ImagePairHomography> {
+    let extract = |image: &DynamicImage| Akaze::sparse().extract(image);
+    #[cfg(feature = "rayon")]
+    let ((keypoints1, descriptors1), (keypoints2, descriptors2)) =
+        rayon::join(|| extract(image1), || extract(image2));
+    #[cfg(not(feature = "rayon"))]
+    let ((keypoints1, descriptors1), (keypoints2, descriptors2)) =
+        (extract(image1), extract(image2));
+
+    let matches = match_descriptors(&descriptors1, &descriptors2, options.matching);
+    let points = feature_matches(
+        &matches,
+        &keypoint_positions(&keypoints1),
+        &keypoint_positions(&keypoints2),
+    );
+    if points.len() < 4 {
+        return Err(eyre!(
+            "At least 4 matches are needed, got {} ({} and {} keypoints)",
+            points.len(),
+            keypoints1.len(),
+            keypoints2.len()
+        ));
+    }
+
+    let estimator = HomographyEstimator::with_options(options.homography);
+    let (model, _) = Ransac::new(options.inlier_threshold, Pcg64::seed_from_u64(options.seed))
+        .model_inliers_slice(&estimator, &points)
+        .ok_or_else(|| eyre!("Sample consensus failed on {} matches", points.len()))?;
+    let mut workspace = HomographyWorkspace::new(options.homography);
+    let homography = workspace.refit(&model, &points, options.inlier_threshold)?;
+    let mut inliers = vec![false; points.len()];
+    for &ix in workspace.inliers() {
+        inliers[ix] = true;
+    }
+
+    Ok(ImagePairHomography {
+        homography,
+        keypoints1,
+        keypoints2,
+        matches,
+        inliers,
+    })
+}
+
+fn keypoint_positions(keypoints: &[KeyPoint]) -> Vec<Point2> {
+    keypoints
+        .iter()
+        .map(|kp| Point2::new(kp.point.0 as f64, kp.point.1 as f64))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{find_homography_from_images, HomographyMatrix, ImagePairOptions, WarpOptions};
+    use image::DynamicImage;
+    use nalgebra::{Matrix3, Point2};
+
+    #[test]
+    fn test_images() {
+        let image1 = image::open("../test-data/image1.png").unwrap();
+        let image2 = image::open("../test-data/image2.png").unwrap();
+        let result =
+            find_homography_from_images(&image1, &image2, ImagePairOptions::default()).unwrap();
+        assert_eq!(result.inliers.len(), result.matches.len());
+        assert!(result.inlier_matches().len() >= 20);
+    }
+
+    #[test]
+    fn finds_a_known_warp() {
+        let image1 = image::open("../test-data/image1.png").unwrap().to_rgb8();
+        let (width, height) = image1.dimensions();
+        let (w, h) = (width as f64, height as f64);
+        // Rotates by 5° and shrinks by 10% around the center, with some perspective
+        let (sin, cos) = 5f64.to_radians().sin_cos();
+        let center = Matrix3::new_translation(&nalgebra::Vector2::new(w / 2.0, h / 2.0));
+        #[rustfmt::skip]
+        let transform = Matrix3::new(
+            0.9 * cos, -0.9 * sin, 0.0,
+            0.9 * sin, 0.9 * cos, 0.0,
+            0.1 / w, 0.05 / h, 1.0,
+        );
+        let h_src = HomographyMatrix(center * transform * center.try_inverse().unwrap());
+        let image2 =
+            crate::warp_perspective(&image1, &h_src, width, height, WarpOptions::default())
+                .unwrap();
+
+        let result = find_homography_from_images(
+            &DynamicImage::ImageRgb8(image1),
+            &DynamicImage::ImageRgb8(image2),
+            ImagePairOptions::default(),
+        )
+        .unwrap();
+        // Compared where it matters, over the middle of the image
+        let project = |h: &HomographyMatrix, p: &Point2<f64>| {
+            Point2::from_homogeneous(h.0 * p.to_homogeneous()).unwrap()
+        };
+        for i in 1..4 {
+            for j in 1..4 {
+                let p = Point2::new(w * i as f64 / 4.0, h * j as f64 / 4.0);
+                let error =
+                    nalgebra::distance(&project(&result.homography, &p), &project(&h_src, &p));
+                assert!(error < 1.0, "{} pixels off at {}", error, p);
+            }
+        }
+    }
+}
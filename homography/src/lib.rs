@@ -26,11 +26,76 @@
 //! assert!(result.abs_diff_eq(&expected, 0.0001));
 //! ```
 
+mod accumulator;
+mod batch;
+mod budget;
+mod calibrated;
+mod calibration;
+mod guided;
 mod homography;
+mod lines;
+mod mosaic;
+mod observer;
+mod pose;
+mod projection;
+mod rotation;
+mod soa;
+mod workspace;
 
+pub use crate::accumulator::*;
+pub use crate::batch::*;
+pub use crate::budget::*;
+pub use crate::calibrated::*;
+pub use crate::calibration::*;
+pub use crate::guided::*;
 pub use crate::homography::*;
+pub use crate::lines::*;
+pub use crate::mosaic::*;
+pub use crate::observer::*;
+pub use crate::pose::*;
+pub use crate::projection::*;
+pub use crate::rotation::*;
+pub use crate::soa::*;
+pub use crate::workspace::*;
 
 #[cfg(feature = "arrsac-sc")]
 mod homography_with_arrsac;
 #[cfg(feature = "arrsac-sc")]
 pub use crate::homography_with_arrsac::*;
+
+#[cfg(feature = "akaze")]
+mod image_pair;
+#[cfg(feature = "akaze")]
+pub use crate::image_pair::*;
+
+#[cfg(feature = "image")]
+mod remap;
+#[cfg(feature = "image")]
+pub use crate::remap::*;
+#[cfg(feature = "image")]
+mod stitch;
+#[cfg(feature = "image")]
+pub use crate::stitch::*;
+#[cfg(feature = "image")]
+mod target;
+#[cfg(feature = "image")]
+pub use crate::target::*;
+#[cfg(feature = "image")]
+mod warp;
+#[cfg(feature = "image")]
+pub use crate::warp::*;
+
+#[cfg(feature = "matching")]
+mod matching;
+#[cfg(feature = "matching")]
+pub use crate::matching::*;
+
+#[cfg(feature = "ransac")]
+mod multi;
+#[cfg(feature = "ransac")]
+pub use crate::multi::*;
+
+#[cfg(feature = "ransac")]
+mod ransac;
+#[cfg(feature = "ransac")]
+pub use crate::ransac::*;
@@ -0,0 +1,400 @@
+use cv_core::FeatureMatch;
+use eyre::{eyre, Result};
+use nalgebra::{self as na, Matrix3, SMatrix, Vector3};
+use sample_consensus::{Estimator, Model};
+
+use crate::homography::{
+    denormalize, dlt_rows, givens_update, normalization_transforms, smallest_right_singular_vector,
+    UNPROJECTABLE_RESIDUAL,
+};
+use crate::{
+    HomographyEstimator, HomographyEstimatorWithOptions, HomographyMatrix, HomographyOptions,
+    Solver, WeightedFeatureMatch,
+};
+type Point2 = na::Point2<f64>;
+
+/// A line `a x + b y + c = 0` with the homogeneous coefficients `(a, b, c)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Line(pub Vector3<f64>);
+
+impl Line {
+    /// The line through two points.
+    pub fn through(p: &Point2, q: &Point2) -> Self {
+        Self(p.to_homogeneous().cross(&q.to_homogeneous()))
+    }
+
+    /// Distance of a point from the line. Infinite for the line at infinity.
+    pub fn distance(&self, p: &Point2) -> f64 {
+        let Self(l) = self;
+        (l.dot(&p.to_homogeneous())).abs() / l.x.hypot(l.y)
+    }
+
+    /// Maps the line with a homography of points: lines are transformed by `H⁻ᵀ`.
+    /// `None` if the homography is singular.
+    pub fn transform(&self, h: &HomographyMatrix) -> Option<Line> {
+        Some(Self(h.0.try_inverse()?.transpose() * self.0))
+    }
+}
+
+/// A segment of a detected line.
+///
+/// Only the supporting line of the segment constrains the homography, so the endpoints of
+/// matching segments don't have to correspond. They are used to normalize the coordinates and
+/// to measure the residuals in pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineSegment {
+    pub start: Point2,
+    pub end: Point2,
+}
+
+impl LineSegment {
+    pub fn line(&self) -> Line {
+        Line::through(&self.start, &self.end)
+    }
+}
+
+/// A point or a line correspondence, for estimating homographies from mixed data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Correspondence {
+    Point(FeatureMatch<Point2>),
+    Line(FeatureMatch<LineSegment>),
+}
+
+impl From<FeatureMatch<Point2>> for Correspondence {
+    fn from(feature_match: FeatureMatch<Point2>) -> Self {
+        Self::Point(feature_match)
+    }
+}
+
+impl From<FeatureMatch<LineSegment>> for Correspondence {
+    fn from(feature_match: FeatureMatch<LineSegment>) -> Self {
+        Self::Line(feature_match)
+    }
+}
+
+impl Model<FeatureMatch<LineSegment>> for HomographyMatrix {
+    /// The mean squared distance of the transferred endpoints of the first segment from the line
+    /// of the second one.
+    fn residual(&self, data: &FeatureMatch<LineSegment>) -> f64 {
+        let FeatureMatch(a, b) = data;
+        let line = b.line();
+        let distance = |p: &Point2| {
+            Point2::from_homogeneous(self.0 * p.to_homogeneous()).map(|p| line.distance(&p))
+        };
+        match (distance(&a.start), distance(&a.end)) {
+            (Some(d1), Some(d2)) if (d1 + d2).is_finite() => (d1 * d1 + d2 * d2) / 2.0,
+            _ => UNPROJECTABLE_RESIDUAL,
+        }
+    }
+}
+
+impl Model<Correspondence> for HomographyMatrix {
+    fn residual(&self, data: &Correspondence) -> f64 {
+        match data {
+            Correspondence::Point(m) => Model::<FeatureMatch<Point2>>::residual(self, m),
+            Correspondence::Line(m) => Model::<FeatureMatch<LineSegment>>::residual(self, m),
+        }
+    }
+}
+
+impl Estimator<FeatureMatch<LineSegment>> for HomographyEstimatorWithOptions {
+    type Model = HomographyMatrix;
+    type ModelIter = Option<HomographyMatrix>;
+    const MIN_SAMPLES: usize = 4;
+
+    fn estimate<I>(&self, data: I) -> Self::ModelIter
+    where
+        I: Iterator<Item = FeatureMatch<LineSegment>> + Clone,
+    {
+        dlt_mixed(data.take(4).map(Correspondence::Line), self.options)
+            .ok()
+            .map(HomographyMatrix)
+    }
+}
+
+impl Estimator<Correspondence> for HomographyEstimatorWithOptions {
+    type Model = HomographyMatrix;
+    type ModelIter = Option<HomographyMatrix>;
+    const MIN_SAMPLES: usize = 4;
+
+    fn estimate<I>(&self, data: I) -> Self::ModelIter
+    where
+        I: Iterator<Item = Correspondence> + Clone,
+    {
+        dlt_mixed(data.take(4), self.options)
+            .ok()
+            .map(HomographyMatrix)
+    }
+}
+
+impl Estimator<FeatureMatch<LineSegment>> for HomographyEstimator {
+    type Model = HomographyMatrix;
+    type ModelIter = Option<HomographyMatrix>;
+    const MIN_SAMPLES: usize = 4;
+
+    fn estimate<I>(&self, data: I) -> Self::ModelIter
+    where
+        I: Iterator<Item = FeatureMatch<LineSegment>> + Clone,
+    {
+        HomographyEstimatorWithOptions::default().estimate(data)
+    }
+}
+
+impl Estimator<Correspondence> for HomographyEstimator {
+    type Model = HomographyMatrix;
+    type ModelIter = Option<HomographyMatrix>;
+    const MIN_SAMPLES: usize = 4;
+
+    fn estimate<I>(&self, data: I) -> Self::ModelIter
+    where
+        I: Iterator<Item = Correspondence> + Clone,
+    {
+        HomographyEstimatorWithOptions::default().estimate(data)
+    }
+}
+
+/// Computes the perspective transformation from line correspondences.
+///
+/// Lines transform by `H⁻ᵀ`, so each pair of lines gives two linear equations on `H`, like a pair
+/// of points. At least 4 lines are needed, no 3 of them through the same point.
+pub fn find_homography_lines(matches: &[FeatureMatch<LineSegment>]) -> Result<Matrix3<f64>> {
+    dlt_mixed(
+        matches.iter().copied().map(Correspondence::Line),
+        HomographyOptions::default(),
+    )
+}
+
+/// Computes the perspective transformation from a mix of point and line correspondences.
+///
+/// Any 4 correspondences in general position determine the homography, except 2 points with
+/// 2 lines: the line through the points and the intersection of the lines only fix 3 points of
+/// the plane then. This mix fails, like segments of zero length, which have no line.
+pub fn find_homography_mixed(data: &[Correspondence]) -> Result<Matrix3<f64>> {
+    find_homography_mixed_with_options(data, HomographyOptions::default())
+}
+
+/// Computes the perspective transformation from a mix of point and line correspondences using the
+/// given options.
+///
+/// The points and the lines are stacked into a single DLT system. The normalization transforms are
+/// computed from the points and the endpoints of the segments, and the lines are normalized with
+/// the inverse transposed transforms, scaled to unit normals. With the same normalization, the
+/// equations of a line measure the distance of its points like the ones of a point pair.
+pub fn find_homography_mixed_with_options(
+    data: &[Correspondence],
+    options: HomographyOptions,
+) -> Result<Matrix3<f64>> {
+    dlt_mixed(data.iter().copied(), options)
+}
+
+fn dlt_mixed<I>(data: I, options: HomographyOptions) -> Result<Matrix3<f64>>
+where
+    I: Iterator<Item = Correspondence> + Clone,
+{
+    let (mut point_count, mut line_count) = (0, 0);
+    for c in data.clone() {
+        match c {
+            Correspondence::Point(_) => point_count += 1,
+            Correspondence::Line(FeatureMatch(a, b)) => {
+                if a.start == a.end || b.start == b.end {
+                    return Err(eyre!("A segment has zero length: {:?}", (a, b)));
+                }
+                line_count += 1;
+            }
+        }
+    }
+    let count = point_count + line_count;
+    if count < 4 {
+        return Err(eyre!(
+            "At least 4 correspondences are needed, got {}",
+            count
+        ));
+    }
+    if (point_count, line_count) == (2, 2) {
+        return Err(eyre!("2 points and 2 lines don't determine a homography"));
+    }
+    // The endpoints don't correspond, but the transforms only depend on the points of each image
+    let points = data.clone().flat_map(|c| {
+        let (first, second) = match c {
+            Correspondence::Point(m) => (m, None),
+            Correspondence::Line(FeatureMatch(a, b)) => (
+                FeatureMatch(a.start, b.start),
+                Some(FeatureMatch(a.end, b.end)),
+            ),
+        };
+        std::iter::once(first)
+            .chain(second)
+            .map(WeightedFeatureMatch::from)
+    });
+    let (norm1, norm2) = normalization_transforms(points, options.normalization)?;
+    let (line_norm1, line_norm2) = (
+        norm1.inverse_matrix().transpose(),
+        norm2.inverse_matrix().transpose(),
+    );
+
+    // A point pair gives 2 rows and a line pair 3, the unused row of a point pair is left out
+    let rows = data.flat_map(|c| {
+        let (rows, len) = match c {
+            Correspondence::Point(FeatureMatch(p1, p2)) => {
+                let (lx, ly) = dlt_rows(&norm1.apply(&p1), &norm2.apply(&p2));
+                ([lx, ly, [0.0; 9]], 2)
+            }
+            Correspondence::Line(FeatureMatch(a, b)) => {
+                let unit = |l: Vector3<f64>| l / l.x.hypot(l.y).max(f64::EPSILON);
+                let l1 = unit(line_norm1 * a.line().0);
+                let l2 = unit(line_norm2 * b.line().0);
+                (line_rows(&l1, &l2), 3)
+            }
+        };
+        rows.into_iter().take(len)
+    });
+    let h0 = match options.solver {
+        Solver::NormalEquations => {
+            let mut ltl = SMatrix::<f64, 9, 9>::zeros();
+            for row in rows {
+                let row = SMatrix::<f64, 1, 9>::from_row_slice(&row);
+                ltl += row.transpose() * row;
+            }
+            let eigen = ltl.symmetric_eigen();
+            let (idx, _) = eigen.eigenvalues.argmin();
+            eigen.eigenvectors.column(idx).clone_owned()
+        }
+        Solver::Svd => {
+            let mut r = SMatrix::<f64, 9, 9>::zeros();
+            for row in rows {
+                givens_update(&mut r, row);
+            }
+            smallest_right_singular_vector(r)
+        }
+    };
+    Ok(denormalize(h0, &norm1, &norm2))
+}
+
+/// The rows of `l1 × Hᵀ l2 = 0` for a pair of (normalized) lines. Only two of them are
+/// independent, but any of them can vanish, so all three are used.
+fn line_rows(l1: &Vector3<f64>, l2: &Vector3<f64>) -> [[f64; 9]; 3] {
+    let mut rows = [[0.0; 9]; 3];
+    // (Hᵀ l2)ⱼ = Σᵢ Hᵢⱼ l2ᵢ, and Hᵢⱼ is the element 3 i + j of the solution
+    for i in 0..3 {
+        let l = l2[i];
+        rows[0][3 * i + 2] += l1.y * l;
+        rows[0][3 * i + 1] -= l1.z * l;
+        rows[1][3 * i] += l1.z * l;
+        rows[1][3 * i + 2] -= l1.x * l;
+        rows[2][3 * i + 1] += l1.x * l;
+        rows[2][3 * i] -= l1.y * l;
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        find_homography_lines, find_homography_mixed_with_options, Correspondence,
+        HomographyEstimator, HomographyMatrix, HomographyOptions, Line, LineSegment, Normalization,
+        Solver,
+    };
+    use approx::assert_relative_eq;
+    use cv_core::FeatureMatch;
+    use nalgebra::{Matrix3, Point2};
+    use sample_consensus::{Estimator, Model};
+
+    fn homography() -> Matrix3<f64> {
+        Matrix3::new(1.1, 0.2, 30.0, -0.15, 0.9, 12.0, 0.0004, -0.0002, 1.0)
+    }
+
+    fn transfer(p: Point2<f64>) -> Point2<f64> {
+        Point2::from_homogeneous(homography() * p.to_homogeneous()).unwrap()
+    }
+
+    /// Segments between pairs of points, the second endpoints of the matching segments are
+    /// moved along their lines
+    fn segments() -> Vec<FeatureMatch<LineSegment>> {
+        [
+            ((10.0, 20.0), (200.0, 35.0)),
+            ((15.0, 180.0), (40.0, 5.0)),
+            ((120.0, 140.0), (190.0, 170.0)),
+            ((60.0, 90.0), (170.0, 10.0)),
+            ((100.0, 100.0), (100.0, 190.0)),
+        ]
+        .iter()
+        .map(|&((x1, y1), (x2, y2))| {
+            let (a, b) = (Point2::new(x1, y1), Point2::new(x2, y2));
+            let (a2, b2) = (transfer(a), transfer(b));
+            FeatureMatch(
+                LineSegment { start: a, end: b },
+                LineSegment {
+                    start: a2,
+                    end: a2 + (b2 - a2) * 0.6,
+                },
+            )
+        })
+        .collect()
+    }
+
+    #[test]
+    fn from_lines() {
+        let segments = segments();
+        let h = find_homography_lines(&segments).unwrap();
+        assert_relative_eq!(h, homography(), epsilon = 1e-9);
+        for m in &segments {
+            assert!(HomographyMatrix(h).residual(m) < 1e-12);
+            let transformed = m.0.line().transform(&HomographyMatrix(h)).unwrap();
+            assert!(transformed.distance(&m.1.end) < 1e-9);
+        }
+        assert!(find_homography_lines(&segments[..3]).is_err());
+        let diagonal = Line::through(&Point2::new(0.0, 0.0), &Point2::new(1.0, 1.0));
+        assert_relative_eq!(
+            diagonal.distance(&Point2::new(1.0, 0.0)),
+            std::f64::consts::FRAC_1_SQRT_2
+        );
+    }
+
+    #[test]
+    fn from_points_and_lines() {
+        let points = [(150.0, 60.0), (30.0, 140.0), (80.0, 20.0)]
+            .map(|(x, y)| Point2::new(x, y))
+            .map(|p| Correspondence::from(FeatureMatch(p, transfer(p))));
+        let lines = segments()
+            .into_iter()
+            .map(Correspondence::from)
+            .collect::<Vec<_>>();
+        // Three lines and a point, and a line and three points
+        let mixes = [
+            [&lines[..3], &points[..1]].concat(),
+            [&lines[..1], &points[..]].concat(),
+        ];
+        for data in &mixes {
+            for normalization in [Normalization::Anisotropic, Normalization::Isotropic] {
+                for solver in [Solver::NormalEquations, Solver::Svd] {
+                    let options = HomographyOptions {
+                        normalization,
+                        solver,
+                    };
+                    let h = find_homography_mixed_with_options(data, options).unwrap();
+                    assert_relative_eq!(h, homography(), epsilon = 1e-8);
+                }
+            }
+
+            let model = HomographyEstimator::default()
+                .estimate(data.iter().copied())
+                .unwrap();
+            assert!(data.iter().all(|c| model.residual(c) < 1e-12));
+        }
+
+        // Two points and two lines, and a segment of zero length, are degenerate samples
+        let estimator = HomographyEstimator::default();
+        let two_and_two = [&lines[..2], &points[..2]].concat();
+        assert!(estimator.estimate(two_and_two.iter().copied()).is_none());
+        let mut collapsed = segments();
+        collapsed[0].1.end = collapsed[0].1.start;
+        assert!(estimator.estimate(collapsed.into_iter().take(4)).is_none());
+        let five = [&lines[..2], &points[..]].concat();
+        assert!(find_homography_mixed_with_options(&five, HomographyOptions::default()).is_ok());
+
+        let model = HomographyMatrix(homography());
+        let outlier =
+            Correspondence::from(FeatureMatch(Point2::new(0.0, 0.0), Point2::new(5.0, 5.0)));
+        assert!(model.residual(&outlier) > 1.0);
+    }
+}
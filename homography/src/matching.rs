@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+
+use bitarray::BitArray;
+use cv_core::FeatureMatch;
+use rand::seq::index::sample;
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+
+type Point2 = nalgebra::Point2<f64>;
+
+/// A pair of matched descriptors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DescriptorMatch {
+    /// Index of the descriptor in the first set
+    pub index1: usize,
+    /// Index of the descriptor in the second set
+    pub index2: usize,
+    /// Hamming distance of the two descriptors
+    pub distance: u32,
+}
+
+impl DescriptorMatch {
+    /// The keypoint coordinates of the match.
+    pub fn feature_match(&self, points1: &[Point2], points2: &[Point2]) -> FeatureMatch<Point2> {
+        FeatureMatch(points1[self.index1], points2[self.index2])
+    }
+}
+
+/// Converts descriptor matches to point matches. The result is in the same order as `matches`,
+/// so their indices and distances still apply.
+pub fn feature_matches(
+    matches: &[DescriptorMatch],
+    points1: &[Point2],
+    points2: &[Point2],
+) -> Vec<FeatureMatch<Point2>> {
+    matches
+        .iter()
+        .map(|m| m.feature_match(points1, points2))
+        .collect()
+}
+
+/// How the nearest neighbours of the descriptors are searched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatchingMethod {
+    /// Exact search comparing every pair of descriptors
+    #[default]
+    BruteForce,
+    /// Approximate search with locality sensitive hashing, see [`LshIndex`]
+    Lsh(LshOptions),
+}
+
+/// Options of [`match_descriptors`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MatchingOptions {
+    pub method: MatchingMethod,
+    /// Lowe's ratio: the nearest neighbour is only accepted if its distance is less than this
+    /// ratio of the distance of the second nearest one. `None` disables the test.
+    pub ratio: Option<f32>,
+    /// Keep only the matches where the descriptors are each other's nearest neighbours
+    pub cross_check: bool,
+    /// Matches with a larger Hamming distance are rejected
+    pub max_distance: Option<u32>,
+}
+
+impl Default for MatchingOptions {
+    fn default() -> Self {
+        Self {
+            method: MatchingMethod::default(),
+            ratio: Some(0.8),
+            cross_check: false,
+            max_distance: None,
+        }
+    }
+}
+
+/// Matches every descriptor of `descriptors1` to its nearest neighbour in `descriptors2`.
+/// *This is supported on **crate feature `matching`** only.*
+///
+/// The matches are returned in increasing order of `index1`.
+pub fn match_descriptors<const B: usize>(
+    descriptors1: &[BitArray<B>],
+    descriptors2: &[BitArray<B>],
+    options: MatchingOptions,
+) -> Vec<DescriptorMatch> {
+    let k = if options.ratio.is_some() { 2 } else { 1 };
+    let forward: Vec<Vec<(usize, u32)>> = match options.method {
+        MatchingMethod::BruteForce => descriptors1
+            .iter()
+            .map(|d| brute_force_knn(descriptors2, d, k))
+            .collect(),
+        MatchingMethod::Lsh(lsh_options) => {
+            let index = LshIndex::new(descriptors2, lsh_options);
+            descriptors1.iter().map(|d| index.knn(d, k)).collect()
+        }
+    };
+    let backward: Option<Vec<Option<usize>>> = options.cross_check.then(|| match options.method {
+        MatchingMethod::BruteForce => descriptors2
+            .iter()
+            .map(|d| brute_force_knn(descriptors1, d, 1).first().map(|n| n.0))
+            .collect(),
+        MatchingMethod::Lsh(lsh_options) => {
+            let index = LshIndex::new(descriptors1, lsh_options);
+            descriptors2
+                .iter()
+                .map(|d| index.knn(d, 1).first().map(|n| n.0))
+                .collect()
+        }
+    });
+
+    forward
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index1, neighbours)| {
+            let (index2, distance) = *neighbours.first()?;
+            if let (Some(ratio), Some((_, second))) = (options.ratio, neighbours.get(1)) {
+                if distance as f32 >= *second as f32 * ratio {
+                    return None;
+                }
+            }
+            if options.max_distance.is_some_and(|max| distance > max) {
+                return None;
+            }
+            if let Some(backward) = &backward {
+                if backward[index2] != Some(index1) {
+                    return None;
+                }
+            }
+            Some(DescriptorMatch {
+                index1,
+                index2,
+                distance,
+            })
+        })
+        .collect()
+}
+
+/// The `k` nearest descriptors as `(index, distance)` pairs in increasing order of distance.
+/// Equal distances are ordered by index.
+fn brute_force_knn<const B: usize>(
+    descriptors: &[BitArray<B>],
+    query: &BitArray<B>,
+    k: usize,
+) -> Vec<(usize, u32)> {
+    nearest(descriptors.iter().map(|d| d.distance(query)).enumerate(), k)
+}
+
+fn nearest(candidates: impl Iterator<Item = (usize, u32)>, k: usize) -> Vec<(usize, u32)> {
+    let mut neighbours: Vec<(usize, u32)> = Vec::with_capacity(k + 1);
+    for (index, distance) in candidates {
+        if neighbours.len() == k && neighbours.last().is_some_and(|n| n.1 <= distance) {
+            continue;
+        }
+        let position = neighbours.partition_point(|n| n.1 <= distance);
+        neighbours.insert(position, (index, distance));
+        neighbours.truncate(k);
+    }
+    neighbours
+}
+
+/// Options of [`LshIndex`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LshOptions {
+    /// Number of hash tables. More tables find more true neighbours, but are slower.
+    pub tables: usize,
+    /// Number of sampled bits in the hash keys, at most 32.
+    /// Fewer bits make larger buckets, more exact but slower search.
+    pub key_bits: usize,
+    /// Also look into the buckets of the keys that differ in one bit from the query's key
+    pub multi_probe: bool,
+    /// Seed of the random bit selection
+    pub seed: u64,
+}
+
+impl Default for LshOptions {
+    fn default() -> Self {
+        Self {
+            tables: 8,
+            key_bits: 16,
+            multi_probe: true,
+            seed: 0,
+        }
+    }
+}
+
+/// Approximate nearest neighbour index of binary descriptors with bit sampling
+/// locality sensitive hashing.
+/// *This is supported on **crate feature `matching`** only.*
+///
+/// Every table hashes the descriptors by a random subset of their bits. The candidates that share a
+/// bucket with the query in any table are compared with their exact Hamming distance.
+pub struct LshIndex<'a, const B: usize> {
+    descriptors: &'a [BitArray<B>],
+    options: LshOptions,
+    /// Sampled bit positions of each table
+    bits: Vec<Vec<usize>>,
+    buckets: Vec<HashMap<u32, Vec<usize>>>,
+}
+
+impl<'a, const B: usize> LshIndex<'a, B> {
+    pub fn new(descriptors: &'a [BitArray<B>], options: LshOptions) -> Self {
+        let key_bits = options.key_bits.clamp(1, 32).min(B * 8);
+        let options = LshOptions {
+            key_bits,
+            ..options
+        };
+        let mut rng = Pcg64::seed_from_u64(options.seed);
+        let bits = (0..options.tables)
+            .map(|_| sample(&mut rng, B * 8, key_bits).into_vec())
+            .collect::<Vec<_>>();
+        let buckets = bits
+            .iter()
+            .map(|bits| {
+                let mut buckets: HashMap<u32, Vec<usize>> = HashMap::new();
+                for (ix, d) in descriptors.iter().enumerate() {
+                    buckets.entry(key(d, bits)).or_default().push(ix);
+                }
+                buckets
+            })
+            .collect();
+        Self {
+            descriptors,
+            options,
+            bits,
+            buckets,
+        }
+    }
+
+    /// The approximate `k` nearest descriptors as `(index, distance)` pairs
+    /// in increasing order of distance.
+    pub fn knn(&self, query: &BitArray<B>, k: usize) -> Vec<(usize, u32)> {
+        let mut candidates = vec![];
+        for (bits, buckets) in self.bits.iter().zip(&self.buckets) {
+            let query_key = key(query, bits);
+            let probes = if self.options.multi_probe {
+                self.options.key_bits
+            } else {
+                0
+            };
+            let keys =
+                std::iter::once(query_key).chain((0..probes).map(|bit| query_key ^ (1 << bit)));
+            for key in keys {
+                if let Some(bucket) = buckets.get(&key) {
+                    candidates.extend_from_slice(bucket);
+                }
+            }
+        }
+        candidates.sort_unstable();
+        candidates.dedup();
+        nearest(
+            candidates
+                .into_iter()
+                .map(|ix| (ix, self.descriptors[ix].distance(query))),
+            k,
+        )
+    }
+}
+
+fn key<const B: usize>(descriptor: &BitArray<B>, bits: &[usize]) -> u32 {
+    let bytes = descriptor.bytes();
+    bits.iter().enumerate().fold(0, |key, (i, &bit)| {
+        key | ((((bytes[bit / 8] >> (bit % 8)) & 1) as u32) << i)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{feature_matches, match_descriptors, LshOptions, MatchingMethod, MatchingOptions};
+    use bitarray::BitArray;
+    use nalgebra::Point2;
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg64;
+
+    /// Descriptors of the second set are the first set's in reverse order with `flips` bits
+    /// flipped, followed by random ones.
+    fn descriptors(n: usize, flips: usize) -> (Vec<BitArray<64>>, Vec<BitArray<64>>) {
+        let mut rng = Pcg64::seed_from_u64(0);
+        let random = |rng: &mut Pcg64| BitArray::new(std::array::from_fn(|_| rng.gen()));
+        let ds1 = (0..n).map(|_| random(&mut rng)).collect::<Vec<_>>();
+        let mut ds2 = ds1
+            .iter()
+            .rev()
+            .map(|d| {
+                let mut bytes = *d.bytes();
+                for _ in 0..flips {
+                    let bit = rng.gen_range(0..512);
+                    bytes[bit / 8] ^= 1 << (bit % 8);
+                }
+                BitArray::new(bytes)
+            })
+            .collect::<Vec<_>>();
+        ds2.extend((0..n).map(|_| random(&mut rng)));
+        (ds1, ds2)
+    }
+
+    #[test]
+    fn brute_force_and_lsh_find_the_same_matches() {
+        let (ds1, ds2) = descriptors(200, 20);
+        let expected = (0..200).map(|ix| (ix, 199 - ix)).collect::<Vec<_>>();
+        for method in [
+            MatchingMethod::BruteForce,
+            MatchingMethod::Lsh(LshOptions::default()),
+        ] {
+            let options = MatchingOptions {
+                method,
+                cross_check: true,
+                ..Default::default()
+            };
+            let matches = match_descriptors(&ds1, &ds2, options);
+            let pairs = matches
+                .iter()
+                .map(|m| (m.index1, m.index2))
+                .collect::<Vec<_>>();
+            assert_eq!(pairs, expected, "{:?}", method);
+            assert!(matches.iter().all(|m| m.distance <= 20));
+        }
+    }
+
+    #[test]
+    fn ratio_test_and_conversion() {
+        let (mut ds1, ds2) = descriptors(10, 0);
+        // Two equally good neighbours fail the ratio test
+        ds1.push(ds2[0]);
+        let mut ds2 = ds2;
+        ds2.push(ds2[0]);
+        let matches = match_descriptors(&ds1, &ds2, MatchingOptions::default());
+        assert_eq!(matches.len(), 9);
+        assert!(matches.iter().all(|m| m.distance == 0));
+
+        let points1 = (0..11)
+            .map(|i| Point2::new(i as f64, 0.0))
+            .collect::<Vec<_>>();
+        let points2 = (0..21)
+            .map(|i| Point2::new(0.0, i as f64))
+            .collect::<Vec<_>>();
+        let feature_matches = feature_matches(&matches, &points1, &points2);
+        for (m, fm) in matches.iter().zip(&feature_matches) {
+            assert_eq!(fm.0.x, m.index1 as f64);
+            assert_eq!(fm.1.y, m.index2 as f64);
+        }
+    }
+}
@@ -0,0 +1,841 @@
+use std::collections::{BTreeMap, BinaryHeap};
+
+use cv_core::FeatureMatch;
+use eyre::{eyre, Result};
+use nalgebra::{self as na, Matrix3, SMatrix, SVector, Vector2};
+
+use crate::{find_homography_iter, HomographyMatrix, HomographyOptions, NormalizationTransform};
+
+type Point2 = na::Point2<f64>;
+type Matrix8 = SMatrix<f64, 8, 8>;
+type Vector8 = SVector<f64, 8>;
+
+/// Point matches between two images of a mosaic.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageMatches {
+    pub image1: usize,
+    pub image2: usize,
+    /// The first point of each match is on `image1`, the second one is on `image2`.
+    /// They are expected to be inliers, e.g. the ones kept by [`Ransac`](crate::Ransac).
+    pub matches: Vec<FeatureMatch<Point2>>,
+}
+
+/// Images of a mosaic connected by their pairwise matches.
+///
+/// The edges beyond the ones that connect the images are loop closures: they are not needed to
+/// place the images, but [`refine_mosaic`] uses them to distribute the drift of the chained
+/// homographies over the whole mosaic.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MosaicGraph {
+    images: usize,
+    edges: Vec<ImageMatches>,
+}
+
+impl MosaicGraph {
+    pub fn new(images: usize) -> Self {
+        Self {
+            images,
+            edges: vec![],
+        }
+    }
+
+    pub fn images(&self) -> usize {
+        self.images
+    }
+
+    pub fn edges(&self) -> &[ImageMatches] {
+        &self.edges
+    }
+
+    /// Adds the matches between two images. Fails if an index is out of range, or the two images
+    /// are the same, or there are less than 4 matches.
+    pub fn add_matches(
+        &mut self,
+        image1: usize,
+        image2: usize,
+        matches: Vec<FeatureMatch<Point2>>,
+    ) -> Result<()> {
+        if image1 >= self.images || image2 >= self.images {
+            return Err(eyre!(
+                "Images {} and {} are out of the {} images of the mosaic",
+                image1,
+                image2,
+                self.images
+            ));
+        }
+        if image1 == image2 {
+            return Err(eyre!("Matches of image {} with itself", image1));
+        }
+        if matches.len() < 4 {
+            return Err(eyre!(
+                "At least 4 matches are needed between image {} and {}, got {}",
+                image1,
+                image2,
+                matches.len()
+            ));
+        }
+        self.edges.push(ImageMatches {
+            image1,
+            image2,
+            matches,
+        });
+        Ok(())
+    }
+
+    /// Homographies mapping each image to `reference`, chained along a spanning tree.
+    ///
+    /// The tree is grown from the reference image through the edges with the most matches first.
+    /// The homography of each tree edge is fitted on its matches. Fails if an image is not
+    /// connected to the reference.
+    pub fn initial_homographies(
+        &self,
+        reference: usize,
+        options: HomographyOptions,
+    ) -> Result<Vec<HomographyMatrix>> {
+        if reference >= self.images {
+            return Err(eyre!(
+                "Reference image {} is out of the {} images",
+                reference,
+                self.images
+            ));
+        }
+        let mut homographies: Vec<Option<Matrix3<f64>>> = vec![None; self.images];
+        homographies[reference] = Some(Matrix3::identity());
+        // Prim's algorithm on the number of matches
+        let mut queue = BinaryHeap::new();
+        let push_edges = |queue: &mut BinaryHeap<(usize, usize)>, image: usize| {
+            for (ix, edge) in self.edges.iter().enumerate() {
+                if edge.image1 == image || edge.image2 == image {
+                    queue.push((edge.matches.len(), ix));
+                }
+            }
+        };
+        push_edges(&mut queue, reference);
+        while let Some((_, ix)) = queue.pop() {
+            let edge = &self.edges[ix];
+            let (parent, child, forward) =
+                match (homographies[edge.image1], homographies[edge.image2]) {
+                    (Some(_), Some(_)) => continue,
+                    (None, Some(parent)) => (parent, edge.image1, true),
+                    (Some(parent), None) => (parent, edge.image2, false),
+                    (None, None) => unreachable!("edges are only queued from placed images"),
+                };
+            let h = find_homography_iter(edge.matches.iter().copied(), options)?;
+            let h = if forward {
+                h
+            } else {
+                h.try_inverse().ok_or_else(|| {
+                    eyre!(
+                        "Homography between image {} and {} is singular",
+                        edge.image1,
+                        edge.image2
+                    )
+                })?
+            };
+            homographies[child] = Some(parent * h);
+            push_edges(&mut queue, child);
+        }
+
+        homographies
+            .into_iter()
+            .enumerate()
+            .map(|(ix, h)| {
+                let h =
+                    h.ok_or_else(|| eyre!("Image {} is not connected to image {}", ix, reference))?;
+                Ok(HomographyMatrix(h / h[(2, 2)]))
+            })
+            .collect()
+    }
+}
+
+/// Options of [`optimize_mosaic`] and [`refine_mosaic`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MosaicOptions {
+    /// The image whose plane the others are mapped to. Its homography stays the identity.
+    pub reference: usize,
+    /// Maximum number of Levenberg-Marquardt iterations
+    pub max_iterations: usize,
+    /// The optimization stops when an iteration decreases the cost by less than this fraction
+    pub tolerance: f64,
+    /// Options of the pairwise fits of [`MosaicGraph::initial_homographies`]
+    pub homography: HomographyOptions,
+}
+
+impl Default for MosaicOptions {
+    fn default() -> Self {
+        Self {
+            reference: 0,
+            max_iterations: 100,
+            tolerance: 1e-10,
+            homography: HomographyOptions::default(),
+        }
+    }
+}
+
+/// Result of [`optimize_mosaic`] and [`refine_mosaic`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct MosaicSolution {
+    /// Maps the points of each image to the reference image
+    pub homographies: Vec<HomographyMatrix>,
+    /// Root mean square distance of the matched points mapped to the reference image,
+    /// with the initial homographies
+    pub initial_error: f64,
+    /// Root mean square distance of the matched points with the optimized homographies
+    pub final_error: f64,
+    /// Root mean square distance of the matched points of each edge of the graph
+    pub edge_errors: Vec<f64>,
+    pub iterations: usize,
+}
+
+/// Places the images of the mosaic with [`MosaicGraph::initial_homographies`], then refines them
+/// jointly with [`refine_mosaic`].
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(images = graph.images()))
+)]
+pub fn optimize_mosaic(graph: &MosaicGraph, options: MosaicOptions) -> Result<MosaicSolution> {
+    let initial = graph.initial_homographies(options.reference, options.homography)?;
+    refine_mosaic(graph, &initial, options)
+}
+
+/// Jointly refines the homographies of all the images of the mosaic.
+///
+/// Minimizes the squared distances of the matched points mapped to the reference image with
+/// Levenberg-Marquardt, over the eight free parameters of every homography except the reference.
+/// The normal equations have an 8×8 block for every image and for every edge, and nothing else,
+/// so they are kept sparse and solved with a block-sparse Cholesky decomposition. The images are
+/// eliminated in reverse Cuthill-McKee order to keep the fill-in low, so mosaics of hundreds of
+/// images don't need a dense system. The points of each image are normalized first to keep the
+/// system well conditioned.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(iterations))
+)]
+pub fn refine_mosaic(
+    graph: &MosaicGraph,
+    initial: &[HomographyMatrix],
+    options: MosaicOptions,
+) -> Result<MosaicSolution> {
+    let images = graph.images();
+    if initial.len() != images {
+        return Err(eyre!(
+            "Got {} homographies for {} images",
+            initial.len(),
+            images
+        ));
+    }
+    if options.reference >= images {
+        return Err(eyre!(
+            "Reference image {} is out of the {} images",
+            options.reference,
+            images
+        ));
+    }
+
+    let transforms = image_normalizations(graph);
+    let edges = graph
+        .edges()
+        .iter()
+        .map(|edge| ImageMatches {
+            matches: edge
+                .matches
+                .iter()
+                .map(|FeatureMatch(a, b)| {
+                    FeatureMatch(
+                        transforms[edge.image1].apply(a),
+                        transforms[edge.image2].apply(b),
+                    )
+                })
+                .collect(),
+            ..*edge
+        })
+        .collect::<Vec<_>>();
+    let reference = &transforms[options.reference];
+    let mut normalized = initial
+        .iter()
+        .zip(&transforms)
+        .map(|(h, t)| {
+            let g = reference.matrix() * h.0 * t.inverse_matrix();
+            if g[(2, 2)].abs() < f64::EPSILON {
+                return Err(eyre!("Homography maps the center of its image to infinity"));
+            }
+            Ok(g / g[(2, 2)])
+        })
+        .collect::<Result<Vec<_>>>()?;
+    normalized[options.reference] = Matrix3::identity();
+
+    // Block of the parameters of each image in the normal equations
+    let mut blocks = vec![None; images];
+    let mut unknowns = 0;
+    for image in elimination_order(graph, options.reference) {
+        if image != options.reference {
+            blocks[image] = Some(unknowns);
+            unknowns += 1;
+        }
+    }
+
+    let mut cost = cost(&edges, &normalized)
+        .ok_or_else(|| eyre!("The initial homographies map matched points to infinity"))?;
+    let mut lambda = 1e-3;
+    let mut iterations = 0;
+    while iterations < options.max_iterations && unknowns > 0 {
+        iterations += 1;
+        let equations = NormalEquations::new(&edges, &normalized, &blocks, unknowns);
+        let mut improved = false;
+        // Retries with a larger damping until the cost decreases
+        while lambda < 1e16 {
+            let Some(step) = equations.solve(lambda) else {
+                lambda *= 10.0;
+                continue;
+            };
+            let candidate = normalized
+                .iter()
+                .zip(&blocks)
+                .map(|(g, block)| match block {
+                    Some(block) => update(g, &step[*block]),
+                    None => *g,
+                })
+                .collect::<Vec<_>>();
+            match self::cost(&edges, &candidate) {
+                Some(candidate_cost) if candidate_cost < cost => {
+                    let decrease = (cost - candidate_cost) / cost;
+                    normalized = candidate;
+                    cost = candidate_cost;
+                    lambda = (lambda / 10.0).max(1e-12);
+                    improved = decrease >= options.tolerance;
+                    break;
+                }
+                _ => lambda *= 10.0,
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("iterations", iterations);
+
+    let mut homographies = normalized
+        .iter()
+        .zip(&transforms)
+        .map(|(g, t)| {
+            let h = reference.inverse_matrix() * g * t.matrix();
+            HomographyMatrix(h / h[(2, 2)])
+        })
+        .collect::<Vec<_>>();
+    // Without the rounding errors of the normalization
+    homographies[options.reference] = HomographyMatrix(Matrix3::identity());
+    let edge_errors = graph
+        .edges()
+        .iter()
+        .map(|edge| rms_error(std::slice::from_ref(edge), &homographies))
+        .collect();
+    Ok(MosaicSolution {
+        initial_error: rms_error(graph.edges(), initial),
+        final_error: rms_error(graph.edges(), &homographies),
+        homographies,
+        edge_errors,
+        iterations,
+    })
+}
+
+/// Isotropic normalization of the matched points of each image.
+fn image_normalizations(graph: &MosaicGraph) -> Vec<NormalizationTransform> {
+    let mut points = vec![vec![]; graph.images()];
+    for edge in graph.edges() {
+        for FeatureMatch(a, b) in &edge.matches {
+            points[edge.image1].push(*a);
+            points[edge.image2].push(*b);
+        }
+    }
+    points
+        .iter()
+        .map(|points| {
+            if points.is_empty() {
+                return NormalizationTransform::new(Point2::origin(), Vector2::repeat(1.0));
+            }
+            let count = points.len() as f64;
+            let center =
+                Point2::from(points.iter().map(|p| p.coords).sum::<Vector2<f64>>() / count);
+            let distance = points.iter().map(|p| na::distance(p, &center)).sum::<f64>() / count;
+            let scale = if distance > f64::EPSILON {
+                std::f64::consts::SQRT_2 / distance
+            } else {
+                1.0
+            };
+            NormalizationTransform::new(center, Vector2::repeat(scale))
+        })
+        .collect()
+}
+
+fn project(h: &Matrix3<f64>, p: &Point2) -> Option<Point2> {
+    let p = h * p.to_homogeneous();
+    (p.z.abs() > f64::EPSILON).then(|| Point2::new(p.x / p.z, p.y / p.z))
+}
+
+/// Sum of the squared residuals, `None` if a point is mapped to infinity.
+fn cost(edges: &[ImageMatches], homographies: &[Matrix3<f64>]) -> Option<f64> {
+    let mut cost = 0.0;
+    for edge in edges {
+        let (h1, h2) = (&homographies[edge.image1], &homographies[edge.image2]);
+        for FeatureMatch(a, b) in &edge.matches {
+            cost += na::distance_squared(&project(h1, a)?, &project(h2, b)?);
+        }
+    }
+    Some(cost)
+}
+
+fn rms_error(edges: &[ImageMatches], homographies: &[HomographyMatrix]) -> f64 {
+    let homographies = homographies.iter().map(|h| h.0).collect::<Vec<_>>();
+    let count = edges.iter().map(|e| e.matches.len()).sum::<usize>();
+    match cost(edges, &homographies) {
+        Some(cost) if count > 0 => (cost / count as f64).sqrt(),
+        Some(_) => 0.0,
+        None => f64::INFINITY,
+    }
+}
+
+/// The projection of `p` and its derivative by the first eight elements of `h` in row-major order.
+fn project_with_jacobian(h: &Matrix3<f64>, p: &Point2) -> Option<(Point2, SMatrix<f64, 2, 8>)> {
+    let projected = project(h, p)?;
+    let w = (h * p.to_homogeneous()).z;
+    let (x, y) = (p.x / w, p.y / w);
+    let (u, v) = (projected.x, projected.y);
+    #[rustfmt::skip]
+    let jacobian = SMatrix::<f64, 2, 8>::from_row_slice(&[
+        x, y, 1.0 / w, 0.0, 0.0, 0.0, -u * x, -u * y,
+        0.0, 0.0, 0.0, x, y, 1.0 / w, -v * x, -v * y,
+    ]);
+    Some((projected, jacobian))
+}
+
+/// Normal equations `JᵀJ δ = -Jᵀr` of the residuals, stored as 8×8 blocks.
+///
+/// `JᵀJ` only couples the parameters of images connected by an edge, so it has a block on the
+/// diagonal for every image and one off the diagonal for every edge between two unknown images.
+struct NormalEquations {
+    diagonal: Vec<Matrix8>,
+    /// Block of each edge: the rows of the first image, the columns of the second one.
+    /// The transposed block is implied.
+    off_diagonal: Vec<(usize, usize, Matrix8)>,
+    rhs: Vec<Vector8>,
+}
+
+impl NormalEquations {
+    /// The equations at `homographies`. Points mapped to infinity are left out.
+    fn new(
+        edges: &[ImageMatches],
+        homographies: &[Matrix3<f64>],
+        blocks: &[Option<usize>],
+        unknowns: usize,
+    ) -> Self {
+        let mut equations = Self {
+            diagonal: vec![Matrix8::zeros(); unknowns],
+            off_diagonal: vec![],
+            rhs: vec![Vector8::zeros(); unknowns],
+        };
+        for edge in edges {
+            let (h1, h2) = (&homographies[edge.image1], &homographies[edge.image2]);
+            let (b1, b2) = (blocks[edge.image1], blocks[edge.image2]);
+            let mut coupling = Matrix8::zeros();
+            for FeatureMatch(a, b) in &edge.matches {
+                let (Some((pa, ja)), Some((pb, jb))) =
+                    (project_with_jacobian(h1, a), project_with_jacobian(h2, b))
+                else {
+                    continue;
+                };
+                let residual = pa - pb;
+                // The residual grows with the parameters of the first image and shrinks with the second
+                if let Some(b1) = b1 {
+                    equations.diagonal[b1] += ja.transpose() * ja;
+                    equations.rhs[b1] -= ja.transpose() * residual;
+                }
+                if let Some(b2) = b2 {
+                    equations.diagonal[b2] += jb.transpose() * jb;
+                    equations.rhs[b2] += jb.transpose() * residual;
+                }
+                coupling -= ja.transpose() * jb;
+            }
+            if let (Some(b1), Some(b2)) = (b1, b2) {
+                equations.off_diagonal.push((b1, b2, coupling));
+            }
+        }
+        equations
+    }
+
+    /// Solves the equations with the diagonal scaled by `1 + lambda`, `None` if they are not
+    /// positive definite.
+    ///
+    /// The factor `L` of `LLᵀ` has a block below the diagonal for every edge, plus the fill-in of
+    /// eliminating the blocks in their order.
+    fn solve(&self, lambda: f64) -> Option<Vec<Vector8>> {
+        let mut diagonal = self
+            .diagonal
+            .iter()
+            .map(|block| {
+                let mut damped = *block;
+                for i in 0..8 {
+                    damped[(i, i)] += lambda * block[(i, i)].max(1e-12);
+                }
+                damped
+            })
+            .collect::<Vec<_>>();
+        // Blocks below the diagonal, by column and row
+        let mut columns = vec![BTreeMap::<usize, Matrix8>::new(); self.rhs.len()];
+        for (b1, b2, block) in &self.off_diagonal {
+            let (row, column, block) = if b1 > b2 {
+                (*b1, *b2, *block)
+            } else {
+                (*b2, *b1, block.transpose())
+            };
+            *columns[column].entry(row).or_insert_with(Matrix8::zeros) += block;
+        }
+
+        let mut factor_diagonal = Vec::with_capacity(self.rhs.len());
+        let mut factor_columns = Vec::with_capacity(self.rhs.len());
+        for k in 0..self.rhs.len() {
+            let l_kk = diagonal[k].cholesky()?.unpack();
+            // L_ik = A_ik L_kk⁻ᵀ
+            let column = std::mem::take(&mut columns[k])
+                .into_iter()
+                .map(|(i, a_ik)| {
+                    let l_ik = l_kk.solve_lower_triangular(&a_ik.transpose())?;
+                    Some((i, l_ik.transpose()))
+                })
+                .collect::<Option<Vec<_>>>()?;
+            // The rows are increasing, so `i >= j`
+            for (ix, (i, l_ik)) in column.iter().enumerate() {
+                for (j, l_jk) in &column[..ix] {
+                    *columns[*j].entry(*i).or_insert_with(Matrix8::zeros) -=
+                        l_ik * l_jk.transpose();
+                }
+                diagonal[*i] -= l_ik * l_ik.transpose();
+            }
+            factor_diagonal.push(l_kk);
+            factor_columns.push(column);
+        }
+
+        // L y = b
+        let mut y = self.rhs.clone();
+        for k in 0..y.len() {
+            let y_k = factor_diagonal[k].solve_lower_triangular(&y[k])?;
+            for (i, l_ik) in &factor_columns[k] {
+                y[*i] -= l_ik * y_k;
+            }
+            y[k] = y_k;
+        }
+        // Lᵀ x = y
+        let mut x = y;
+        for k in (0..x.len()).rev() {
+            let mut rhs = x[k];
+            for (i, l_ik) in &factor_columns[k] {
+                rhs -= l_ik.tr_mul(&x[*i]);
+            }
+            x[k] = factor_diagonal[k].tr_solve_lower_triangular(&rhs)?;
+        }
+        Some(x)
+    }
+}
+
+/// The images in reverse Cuthill-McKee order, starting from the reference.
+///
+/// Neighbours in the mosaic stay close in this order, which keeps the fill-in of the Cholesky
+/// decomposition of the normal equations within a narrow band.
+fn elimination_order(graph: &MosaicGraph, reference: usize) -> Vec<usize> {
+    let mut neighbours = vec![vec![]; graph.images()];
+    for edge in graph.edges() {
+        neighbours[edge.image1].push(edge.image2);
+        neighbours[edge.image2].push(edge.image1);
+    }
+    for adjacent in &mut neighbours {
+        adjacent.sort_unstable();
+        adjacent.dedup();
+    }
+    let mut visited = vec![false; graph.images()];
+    let mut order = Vec::with_capacity(graph.images());
+    for start in std::iter::once(reference).chain(0..graph.images()) {
+        if visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        order.push(start);
+        // Breadth-first, visiting the neighbours with fewer connections first
+        let mut next = order.len() - 1;
+        while next < order.len() {
+            let image = order[next];
+            next += 1;
+            let mut adjacent = neighbours[image]
+                .iter()
+                .copied()
+                .filter(|&n| !visited[n])
+                .collect::<Vec<_>>();
+            adjacent.sort_by_key(|&n| neighbours[n].len());
+            for n in adjacent {
+                visited[n] = true;
+                order.push(n);
+            }
+        }
+    }
+    order.reverse();
+    order
+}
+
+fn update(h: &Matrix3<f64>, step: &Vector8) -> Matrix3<f64> {
+    let mut h = *h;
+    for (i, delta) in step.iter().enumerate() {
+        h[(i / 3, i % 3)] += delta;
+    }
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rms_error, Matrix8, NormalEquations, Vector8};
+    use crate::{optimize_mosaic, HomographyMatrix, MosaicGraph, MosaicOptions};
+    use approx::assert_relative_eq;
+    use cv_core::FeatureMatch;
+    use nalgebra::{DMatrix, DVector, Matrix3, Point2, Vector2};
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg64;
+
+    fn project(h: &Matrix3<f64>, p: &Point2<f64>) -> Point2<f64> {
+        Point2::from_homogeneous(h * p.to_homogeneous()).unwrap()
+    }
+
+    /// Five images along a row, each mapped to the first one by `truth`,
+    /// with noisy matches between neighbours and a loop closure between the two ends.
+    fn mosaic(rng: &mut Pcg64) -> (MosaicGraph, Vec<Matrix3<f64>>) {
+        let truth = (0..5)
+            .map(|i| {
+                let i = i as f64;
+                Matrix3::new(
+                    1.0 + 0.02 * i,
+                    0.01 * i,
+                    250.0 * i,
+                    -0.015 * i,
+                    1.0 - 0.01 * i,
+                    10.0 * i,
+                    1e-5 * i,
+                    -2e-5 * i,
+                    1.0,
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut graph = MosaicGraph::new(5);
+        let mut add = |graph: &mut MosaicGraph, i: usize, j: usize, count: usize| {
+            let (hi, hj) = (
+                truth[i].try_inverse().unwrap(),
+                truth[j].try_inverse().unwrap(),
+            );
+            let noise = 1.5;
+            let matches = (0..count)
+                .map(|_| {
+                    // A point of the plane seen on both images
+                    let x = 250.0 * (i.min(j) as f64) + rng.gen_range(-150.0..550.0);
+                    let p = Point2::new(x, rng.gen_range(-100.0..400.0));
+                    let (a, b) = (project(&hi, &p), project(&hj, &p));
+                    let mut jitter =
+                        || Point2::new(rng.gen_range(-noise..noise), rng.gen_range(-noise..noise));
+                    FeatureMatch(a + jitter().coords, b + jitter().coords)
+                })
+                .collect();
+            graph.add_matches(i, j, matches).unwrap();
+        };
+        for i in 0..4 {
+            add(&mut graph, i, i + 1, 40);
+        }
+        add(&mut graph, 4, 0, 40);
+        (graph, truth)
+    }
+
+    #[test]
+    fn refinement_reduces_drift() {
+        let mut rng = Pcg64::seed_from_u64(0);
+        let (graph, truth) = mosaic(&mut rng);
+        let solution = optimize_mosaic(&graph, MosaicOptions::default()).unwrap();
+        assert!(solution.final_error < solution.initial_error);
+        assert!(solution.final_error < 2.0, "{}", solution.final_error);
+        assert_eq!(solution.edge_errors.len(), 5);
+        assert_eq!(solution.homographies[0].0, Matrix3::identity());
+
+        // The corners of the images land close to their true place in the reference image
+        let corner_error = |h: &Matrix3<f64>, truth: &Matrix3<f64>| {
+            [(0.0, 0.0), (400.0, 0.0), (0.0, 300.0), (400.0, 300.0)]
+                .iter()
+                .map(|&(x, y)| {
+                    let p = Point2::new(x, y);
+                    nalgebra::distance(&project(h, &p), &project(truth, &p))
+                })
+                .fold(0.0, f64::max)
+        };
+        let initial = graph.initial_homographies(0, Default::default()).unwrap();
+        let before = (1..5)
+            .map(|i| corner_error(&initial[i].0, &truth[i]))
+            .fold(0.0, f64::max);
+        let after = (1..5)
+            .map(|i| corner_error(&solution.homographies[i].0, &truth[i]))
+            .fold(0.0, f64::max);
+        assert!(after < before, "{} {}", after, before);
+        assert!(after < 3.0, "{}", after);
+    }
+
+    #[test]
+    fn disconnected_images() {
+        let mut rng = Pcg64::seed_from_u64(1);
+        let (graph, _) = mosaic(&mut rng);
+        let mut disconnected = MosaicGraph::new(6);
+        for edge in graph.edges() {
+            disconnected
+                .add_matches(edge.image1, edge.image2, edge.matches.clone())
+                .unwrap();
+        }
+        assert!(optimize_mosaic(&disconnected, MosaicOptions::default()).is_err());
+        assert!(disconnected.add_matches(6, 0, vec![]).is_err());
+    }
+
+    #[test]
+    fn refines_a_large_grid() {
+        // 8×8 images with matches to their right and bottom neighbours: every cell is a loop
+        let (rows, cols) = (8, 8);
+        let mut rng = Pcg64::seed_from_u64(2);
+        let truth = (0..rows * cols)
+            .map(|ix| {
+                let (r, c) = ((ix / cols) as f64, (ix % cols) as f64);
+                let mut h = Matrix3::new_translation(&Vector2::new(250.0 * c, 200.0 * r));
+                if ix > 0 {
+                    let mut perturbation = Matrix3::identity();
+                    for (i, scale) in [0.01, 0.01, 5.0, 0.01, 0.01, 5.0, 1e-5, 1e-5]
+                        .into_iter()
+                        .enumerate()
+                    {
+                        perturbation[(i / 3, i % 3)] += rng.gen_range(-scale..scale);
+                    }
+                    h *= perturbation;
+                }
+                h
+            })
+            .collect::<Vec<_>>();
+        let mut graph = MosaicGraph::new(rows * cols);
+        for ix in 0..rows * cols {
+            let (r, c) = ((ix / cols) as f64, (ix % cols) as f64);
+            // The overlaps of the 400×300 footprints on the plane
+            let mut neighbours = vec![];
+            if (ix % cols) + 1 < cols {
+                neighbours.push((
+                    ix + 1,
+                    (250.0 * (c + 1.0), 250.0 * c + 400.0),
+                    (200.0 * r, 200.0 * r + 300.0),
+                ));
+            }
+            if ix / cols + 1 < rows {
+                neighbours.push((
+                    ix + cols,
+                    (250.0 * c, 250.0 * c + 400.0),
+                    (200.0 * (r + 1.0), 200.0 * r + 300.0),
+                ));
+            }
+            for (jx, xs, ys) in neighbours {
+                let (hi, hj) = (
+                    truth[ix].try_inverse().unwrap(),
+                    truth[jx].try_inverse().unwrap(),
+                );
+                let matches = (0..30)
+                    .map(|_| {
+                        let p = Point2::new(rng.gen_range(xs.0..xs.1), rng.gen_range(ys.0..ys.1));
+                        let mut jitter =
+                            || Vector2::new(rng.gen_range(-0.5..0.5), rng.gen_range(-0.5..0.5));
+                        FeatureMatch(project(&hi, &p) + jitter(), project(&hj, &p) + jitter())
+                    })
+                    .collect();
+                graph.add_matches(ix, jx, matches).unwrap();
+            }
+        }
+        assert_eq!(graph.edges().len(), 112);
+
+        let solution = optimize_mosaic(&graph, MosaicOptions::default()).unwrap();
+        assert_eq!(solution.homographies[0].0, Matrix3::identity());
+        // The chained homographies drift apart on the loops, the refined ones fit the matches
+        // at least as well as the true ones
+        let truth = truth.into_iter().map(HomographyMatrix).collect::<Vec<_>>();
+        let truth_error = rms_error(graph.edges(), &truth);
+        assert!(
+            solution.initial_error > 2.0 * truth_error,
+            "{}",
+            solution.initial_error
+        );
+        assert!(
+            solution.final_error <= truth_error,
+            "{} {}",
+            solution.final_error,
+            truth_error
+        );
+    }
+
+    #[test]
+    fn sparse_solve_matches_dense() {
+        let mut rng = Pcg64::seed_from_u64(3);
+        let unknowns = 12;
+        let mut random_block = |scale: f64| Matrix8::from_fn(|_, _| rng.gen_range(-scale..scale));
+        let mut equations = NormalEquations {
+            diagonal: (0..unknowns)
+                .map(|_| {
+                    let m = random_block(1.0);
+                    m * m.transpose() + Matrix8::identity() * 4.0
+                })
+                .collect(),
+            off_diagonal: vec![],
+            rhs: vec![],
+        };
+        // A ring with chords, in both orientations and with a repeated edge
+        for (b1, b2) in [
+            (0, 1),
+            (2, 1),
+            (2, 3),
+            (3, 4),
+            (5, 4),
+            (5, 6),
+            (6, 7),
+            (7, 8),
+        ]
+        .into_iter()
+        .chain([(8, 9), (10, 9), (10, 11), (11, 0), (0, 6), (9, 3), (3, 9)])
+        {
+            equations.off_diagonal.push((b1, b2, random_block(0.2)));
+        }
+        equations.rhs = (0..unknowns)
+            .map(|_| Vector8::from_fn(|_, _| rng.gen_range(-1.0..1.0)))
+            .collect();
+
+        let lambda = 0.1;
+        let mut dense = DMatrix::zeros(8 * unknowns, 8 * unknowns);
+        for (b, block) in equations.diagonal.iter().enumerate() {
+            let mut damped = *block;
+            for i in 0..8 {
+                damped[(i, i)] *= 1.0 + lambda;
+            }
+            dense
+                .fixed_slice_mut::<8, 8>(8 * b, 8 * b)
+                .copy_from(&damped);
+        }
+        for (b1, b2, block) in &equations.off_diagonal {
+            let mut upper = dense.fixed_slice_mut::<8, 8>(8 * b1, 8 * b2);
+            upper += block;
+            let mut lower = dense.fixed_slice_mut::<8, 8>(8 * b2, 8 * b1);
+            lower += block.transpose();
+        }
+        let rhs = DVector::from_iterator(
+            8 * unknowns,
+            equations.rhs.iter().flat_map(|r| r.iter().copied()),
+        );
+        let expected = dense.cholesky().unwrap().solve(&rhs);
+
+        let solution = equations.solve(lambda).unwrap();
+        for (b, x) in solution.iter().enumerate() {
+            assert_relative_eq!(
+                *x,
+                expected.fixed_rows::<8>(8 * b).into_owned(),
+                epsilon = 1e-10
+            );
+        }
+    }
+}
@@ -0,0 +1,436 @@
+use cv_core::FeatureMatch;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+use sample_consensus::{Estimator, Model};
+
+use crate::{
+    find_homography_iter, HomographyEstimator, HomographyMatrix, HomographyOptions,
+    HomographyWorkspace, Ransac,
+};
+
+type Point2 = nalgebra::Point2<f64>;
+
+/// Algorithm used by [`find_homographies`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MultiModelMethod {
+    /// Runs [`Ransac`] repeatedly, removing the inliers of each found model before the next run.
+    /// Fast, but a model can steal the matches of another plane that is close to it.
+    #[default]
+    Sequential,
+    /// J-Linkage: every match gets the set of hypotheses it agrees with, then the matches are
+    /// clustered by the Jaccard distance of these sets, and each cluster is fitted with [`Ransac`]
+    /// so the outliers that were clustered in are left out. The number of models doesn't have to
+    /// be known, but the clustering is slow on many matches: every merge rescans the clusters, so
+    /// it takes O(n²) to O(n³) time in the number of matches.
+    JLinkage,
+}
+
+/// Options of [`find_homographies`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MultiHomographyOptions {
+    pub method: MultiModelMethod,
+    /// Maximum squared reprojection error of an inlier
+    pub inlier_threshold: f64,
+    /// Models with fewer inliers are dropped
+    pub min_inliers: usize,
+    /// Upper limit of the returned models
+    pub max_models: usize,
+    /// Number of random hypotheses generated by [`MultiModelMethod::JLinkage`]
+    pub hypotheses: usize,
+    /// Samples of [`MultiModelMethod::JLinkage`] are drawn from this many nearest neighbours
+    /// of a random match, as matches close to each other are likely to be on the same plane
+    pub neighbours: usize,
+    /// Seed of the random number generator
+    pub seed: u64,
+    /// Options of the least squares fit of the models on their inliers
+    pub homography: HomographyOptions,
+}
+
+impl Default for MultiHomographyOptions {
+    fn default() -> Self {
+        Self {
+            method: MultiModelMethod::default(),
+            inlier_threshold: 0.1,
+            min_inliers: 10,
+            max_models: 16,
+            hypotheses: 1000,
+            neighbours: 10,
+            seed: 0,
+            homography: HomographyOptions::default(),
+        }
+    }
+}
+
+/// Homographies found by [`find_homographies`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultiHomography {
+    /// Models in decreasing order of their inlier count
+    pub models: Vec<HomographyMatrix>,
+    /// Index of the model of each match, `None` for outliers
+    pub labels: Vec<Option<usize>>,
+}
+
+impl MultiHomography {
+    /// Indices of the matches labeled with `model`.
+    pub fn inliers(&self, model: usize) -> Vec<usize> {
+        self.labels
+            .iter()
+            .enumerate()
+            .filter(|(_, label)| **label == Some(model))
+            .map(|(ix, _)| ix)
+            .collect()
+    }
+}
+
+/// Finds the homographies of several planes in the matches.
+/// *This is supported on **crate feature `ransac`** only.*
+///
+/// Every model is re-fitted on its inliers. The inlier sets are disjoint, a match that fits more
+/// than one model goes to the one with the smallest residual.
+pub fn find_homographies(
+    matches: &[FeatureMatch<Point2>],
+    options: MultiHomographyOptions,
+) -> MultiHomography {
+    let models = match options.method {
+        MultiModelMethod::Sequential => sequential_models(matches, &options),
+        MultiModelMethod::JLinkage => j_linkage_models(matches, &options),
+    };
+    assign_labels(matches, models, &options)
+}
+
+fn sequential_models(
+    matches: &[FeatureMatch<Point2>],
+    options: &MultiHomographyOptions,
+) -> Vec<HomographyMatrix> {
+    let estimator = HomographyEstimator::with_options(options.homography);
+    let mut workspace = HomographyWorkspace::new(options.homography);
+    let mut remaining = matches.to_vec();
+    let mut models = vec![];
+    while models.len() < options.max_models && remaining.len() >= options.min_inliers.max(4) {
+        let rng = Pcg64::seed_from_u64(options.seed.wrapping_add(models.len() as u64));
+        let Some((model, _)) =
+            Ransac::new(options.inlier_threshold, rng).model_inliers_slice(&estimator, &remaining)
+        else {
+            break;
+        };
+        let Ok(model) = workspace.refit(&model, &remaining, options.inlier_threshold) else {
+            break;
+        };
+        let inlier_count = remaining
+            .iter()
+            .filter(|&m| model.residual(m) < options.inlier_threshold)
+            .count();
+        if inlier_count < options.min_inliers {
+            break;
+        }
+        remaining.retain(|m| model.residual(m) >= options.inlier_threshold);
+        models.push(model);
+    }
+    models
+}
+
+/// Maximal number of re-fits growing the model of a J-Linkage cluster
+const REFIT_ITERATIONS: usize = 8;
+
+fn j_linkage_models(
+    matches: &[FeatureMatch<Point2>],
+    options: &MultiHomographyOptions,
+) -> Vec<HomographyMatrix> {
+    let n = matches.len();
+    if n < options.min_inliers.max(4) {
+        return vec![];
+    }
+
+    // Preference sets: bit `h` of a match is set if it's an inlier of hypothesis `h`
+    let neighbours = nearest_neighbours(matches, options.neighbours.max(3));
+    let estimator = HomographyEstimator::with_options(options.homography);
+    let mut rng = Pcg64::seed_from_u64(options.seed);
+    let words = options.hypotheses.div_ceil(64);
+    let mut preferences = vec![vec![0u64; words]; n];
+    for h in 0..options.hypotheses {
+        let first = rng.gen_range(0..n);
+        let mut sample = vec![first];
+        while sample.len() < 4 {
+            let ix = neighbours[first][rng.gen_range(0..neighbours[first].len())];
+            if !sample.contains(&ix) {
+                sample.push(ix);
+            }
+        }
+        let Some(model) = estimator.estimate(sample.iter().map(|&ix| matches[ix])) else {
+            continue;
+        };
+        for (preference, m) in preferences.iter_mut().zip(matches) {
+            if model.residual(m) < options.inlier_threshold {
+                preference[h / 64] |= 1 << (h % 64);
+            }
+        }
+    }
+
+    let mut clusters = cluster_preferences(preferences);
+    clusters.sort_by_key(|members| std::cmp::Reverse(members.len()));
+    // Clusters are fitted from the largest one, and the fit is grown to all the matches not taken
+    // by the models found so far, so the smaller clusters a plane was split into are left empty
+    let min_inliers = options.min_inliers.max(4);
+    let mut workspace = HomographyWorkspace::new(options.homography);
+    let mut models = vec![];
+    let mut taken = vec![false; matches.len()];
+    for (ix, members) in clusters.iter().enumerate() {
+        if models.len() == options.max_models || members.len() < min_inliers {
+            break;
+        }
+        let members = members
+            .iter()
+            .filter(|&&ix| !taken[ix])
+            .map(|&ix| matches[ix])
+            .collect::<Vec<_>>();
+        if members.len() < min_inliers {
+            continue;
+        }
+        let rng = Pcg64::seed_from_u64(options.seed.wrapping_add(ix as u64));
+        let Some((model, _)) =
+            Ransac::new(options.inlier_threshold, rng).model_inliers_slice(&estimator, &members)
+        else {
+            continue;
+        };
+        // The cluster may cover a part of the plane only, so the fit is grown until it takes no
+        // more matches
+        let remaining = (0..matches.len())
+            .filter(|&ix| !taken[ix])
+            .map(|ix| matches[ix])
+            .collect::<Vec<_>>();
+        let mut model = model;
+        let mut inlier_count = 0;
+        for _ in 0..REFIT_ITERATIONS {
+            let Ok(refitted) = workspace.refit(&model, &remaining, options.inlier_threshold) else {
+                break;
+            };
+            if workspace.inliers().len() <= inlier_count {
+                break;
+            }
+            inlier_count = workspace.inliers().len();
+            model = refitted;
+        }
+        let inliers = (0..matches.len())
+            .filter(|&ix| !taken[ix] && model.residual(&matches[ix]) < options.inlier_threshold)
+            .collect::<Vec<_>>();
+        if inliers.len() >= options.min_inliers {
+            for ix in inliers {
+                taken[ix] = true;
+            }
+            models.push(model);
+        }
+    }
+    models
+}
+
+fn nearest_neighbours(matches: &[FeatureMatch<Point2>], k: usize) -> Vec<Vec<usize>> {
+    let k = k.min(matches.len() - 1);
+    matches
+        .iter()
+        .enumerate()
+        .map(|(ix, FeatureMatch(a, _))| {
+            let mut others = (0..matches.len()).filter(|&j| j != ix).collect::<Vec<_>>();
+            let distance = |j: &usize| nalgebra::distance_squared(a, &matches[*j].0);
+            others.select_nth_unstable_by(k - 1, |i, j| distance(i).total_cmp(&distance(j)));
+            others.truncate(k);
+            others
+        })
+        .collect()
+}
+
+fn jaccard_distance(a: &[u64], b: &[u64]) -> f64 {
+    let (intersection, union) = a.iter().zip(b).fold((0, 0), |(i, u), (a, b)| {
+        (i + (a & b).count_ones(), u + (a | b).count_ones())
+    });
+    if union == 0 {
+        1.0
+    } else {
+        1.0 - intersection as f64 / union as f64
+    }
+}
+
+/// Agglomerative clustering of J-Linkage. Keeps merging the two closest clusters while their
+/// preference sets overlap. The preference set of a merged cluster is the intersection of the two.
+///
+/// Each of the up to n merges scans the nearest clusters, and recomputes the nearest cluster of
+/// those whose nearest one was merged in O(n) each, so the worst case is O(n³).
+fn cluster_preferences(mut preferences: Vec<Vec<u64>>) -> Vec<Vec<usize>> {
+    let n = preferences.len();
+    let mut members = (0..n).map(|ix| Some(vec![ix])).collect::<Vec<_>>();
+    // Closest other cluster of each cluster
+    let nearest_of = |ix: usize, preferences: &[Vec<u64>], members: &[Option<Vec<usize>>]| {
+        (0..n)
+            .filter(|&j| j != ix && members[j].is_some())
+            .map(|j| (jaccard_distance(&preferences[ix], &preferences[j]), j))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .unwrap_or((1.0, ix))
+    };
+    let mut nearest = (0..n)
+        .map(|ix| nearest_of(ix, &preferences, &members))
+        .collect::<Vec<_>>();
+
+    while let Some((i, (_, j))) = (0..n)
+        .filter(|&ix| members[ix].is_some())
+        .map(|ix| (ix, nearest[ix]))
+        .filter(|(_, (distance, _))| *distance < 1.0)
+        .min_by(|a, b| a.1 .0.total_cmp(&b.1 .0))
+    {
+        let merged = members[j].take().unwrap();
+        members[i].as_mut().unwrap().extend(merged);
+        let removed = std::mem::take(&mut preferences[j]);
+        for (a, b) in preferences[i].iter_mut().zip(removed) {
+            *a &= b;
+        }
+
+        nearest[i] = nearest_of(i, &preferences, &members);
+        for k in 0..n {
+            if k == i || members[k].is_none() {
+                continue;
+            }
+            if nearest[k].1 == i || nearest[k].1 == j {
+                nearest[k] = nearest_of(k, &preferences, &members);
+            } else {
+                let distance = jaccard_distance(&preferences[k], &preferences[i]);
+                if distance < nearest[k].0 {
+                    nearest[k] = (distance, i);
+                }
+            }
+        }
+    }
+    members.into_iter().flatten().collect()
+}
+
+/// Gives every match to the model with the smallest residual, re-fits the models on their
+/// matches and drops the ones left with too few of them.
+fn assign_labels(
+    matches: &[FeatureMatch<Point2>],
+    models: Vec<HomographyMatrix>,
+    options: &MultiHomographyOptions,
+) -> MultiHomography {
+    let min_inliers = options.min_inliers.max(4);
+    let (_, members) = label_matches(matches, &models, options);
+    let refitted = members
+        .iter()
+        .zip(&models)
+        .filter(|(members, _)| members.len() >= min_inliers)
+        .map(|(members, model)| {
+            find_homography_iter(members.iter().map(|&ix| matches[ix]), options.homography)
+                .map_or(*model, HomographyMatrix)
+        })
+        .collect::<Vec<_>>();
+
+    // The re-fitted models can take the matches of each other, so the ones left with too few are
+    // dropped. That only gives their matches to the others, so one more labelling is enough.
+    let (_, members) = label_matches(matches, &refitted, options);
+    let mut kept = members
+        .into_iter()
+        .zip(refitted)
+        .filter(|(members, _)| members.len() >= min_inliers)
+        .collect::<Vec<_>>();
+    kept.sort_by_key(|(members, _)| std::cmp::Reverse(members.len()));
+    let models = kept.into_iter().map(|(_, model)| model).collect::<Vec<_>>();
+    let (labels, _) = label_matches(matches, &models, options);
+    MultiHomography { models, labels }
+}
+
+/// Label of every match, the model with the smallest residual below the threshold, and the
+/// members of every model.
+fn label_matches(
+    matches: &[FeatureMatch<Point2>],
+    models: &[HomographyMatrix],
+    options: &MultiHomographyOptions,
+) -> (Vec<Option<usize>>, Vec<Vec<usize>>) {
+    let labels = matches
+        .iter()
+        .map(|m| {
+            models
+                .iter()
+                .enumerate()
+                .map(|(ix, model)| (ix, model.residual(m)))
+                .filter(|(_, residual)| *residual < options.inlier_threshold)
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(ix, _)| ix)
+        })
+        .collect::<Vec<_>>();
+    let mut members = vec![vec![]; models.len()];
+    for (ix, label) in labels.iter().enumerate() {
+        if let Some(label) = label {
+            members[*label].push(ix);
+        }
+    }
+    (labels, members)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{find_homographies, MultiHomographyOptions, MultiModelMethod};
+    use approx::AbsDiffEq;
+    use cv_core::FeatureMatch;
+    use nalgebra::{Matrix3, Point2, Vector2};
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg64;
+    use test_utils::TestData;
+
+    #[test]
+    fn finds_two_planes() {
+        // The second plane is 200 pixels to the right of the first one on the source image
+        let shift = Vector2::new(200.0, 0.0);
+        let mut rng = Pcg64::seed_from_u64(0);
+        let first = TestData::from_rng(&mut rng, 80, 100.0, 0.0);
+        let second = TestData::from_rng(&mut rng, 60, 100.0, 0.0);
+        let h_second = second.h * Matrix3::new_translation(&-shift);
+        let mut matches = first.matches.clone();
+        matches.extend(
+            second
+                .matches
+                .iter()
+                .map(|m| FeatureMatch(m.0 + shift, m.1)),
+        );
+        matches.extend((0..20).map(|_| {
+            FeatureMatch(
+                Point2::new(rng.gen_range(0.0..300.0), rng.gen_range(0.0..100.0)),
+                Point2::new(rng.gen_range(0.0..100.0), rng.gen_range(0.0..100.0)),
+            )
+        }));
+
+        for method in [MultiModelMethod::Sequential, MultiModelMethod::JLinkage] {
+            let options = MultiHomographyOptions {
+                method,
+                inlier_threshold: 0.01,
+                ..Default::default()
+            };
+            let result = find_homographies(&matches, options);
+            assert_eq!(result.models.len(), 2, "{:?}", method);
+            assert!(first.h.abs_diff_eq(&result.models[0].0, 0.0001));
+            assert!(h_second.abs_diff_eq(&result.models[1].0, 0.0001));
+            assert_eq!(result.inliers(0), (0..80).collect::<Vec<_>>());
+            assert_eq!(result.inliers(1), (80..140).collect::<Vec<_>>());
+            assert!(result.labels[140..].iter().all(Option::is_none));
+        }
+    }
+
+    #[test]
+    fn finds_one_model_of_a_noisy_plane() {
+        // Noise splits the matches of the plane between several J-Linkage clusters, and the
+        // sequential runs are left with the matches a little past the threshold, neither of
+        // which may give a second model
+        for seed in 0..4 {
+            let mut rng = Pcg64::seed_from_u64(seed);
+            let TestData { matches, h } =
+                TestData::projective_with_noise(&mut rng, 200, 100.0, 0.1);
+            for method in [MultiModelMethod::Sequential, MultiModelMethod::JLinkage] {
+                let options = MultiHomographyOptions {
+                    method,
+                    inlier_threshold: 0.5,
+                    seed,
+                    ..Default::default()
+                };
+                let result = find_homographies(&matches, options);
+                assert_eq!(result.models.len(), 1, "{:?} {}", method, seed);
+                let model = result.models[0].0 / result.models[0].0[(2, 2)];
+                assert!(h.abs_diff_eq(&model, 0.1), "{:?} {}", method, seed);
+                assert!(result.inliers(0).len() > 190, "{:?} {}", method, seed);
+            }
+        }
+    }
+}
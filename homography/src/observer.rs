@@ -0,0 +1,210 @@
+use std::cell::{Cell, RefCell};
+
+use sample_consensus::{Estimator, Model};
+
+/// One scored hypothesis of a sample consensus run.
+#[derive(Debug)]
+pub struct Hypothesis<'a, M> {
+    /// Index of the hypothesis in the run, starting from 0
+    pub iteration: usize,
+    /// Indices of the data the model was estimated from
+    pub sample: &'a [usize],
+    pub model: &'a M,
+    /// Number of inliers of the model
+    pub score: usize,
+    /// Whether the model replaced the best one found so far
+    pub new_best: bool,
+}
+
+/// Summary of a finished sample consensus run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Termination {
+    /// Number of samples drawn
+    pub iterations: usize,
+    /// Number of inliers of the returned model, `None` if no model was found
+    pub best_score: Option<usize>,
+    /// Whether the run stopped early because the [`Budget`](crate::Budget) ran out
+    pub terminated_early: bool,
+}
+
+/// Receives the progress of a sample consensus run.
+///
+/// The built-in `Ransac` (crate feature `ransac`) reports to an observer passed to
+/// `Ransac::estimate_observed`. Sample consensus algorithms of other crates, like ARRSAC, report
+/// through an [`ObservedEstimator`].
+///
+/// All methods do nothing by default. Hypotheses are reported in the order they were generated,
+/// even when they are scored in parallel. Samples for which the estimator produced no model are
+/// reported to [`Self::failed_sample`] instead, so every estimated sample is reported once.
+pub trait ConsensusObserver<M> {
+    fn hypothesis(&mut self, _hypothesis: &Hypothesis<M>) {}
+
+    /// Sample with the given iteration index that the estimator produced no model for.
+    fn failed_sample(&mut self, _iteration: usize, _sample: &[usize]) {}
+
+    fn termination(&mut self, _termination: &Termination) {}
+}
+
+/// Observer that ignores everything.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopObserver;
+
+impl<M> ConsensusObserver<M> for NoopObserver {}
+
+/// Owned copy of a [`Hypothesis`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedHypothesis<M> {
+    pub iteration: usize,
+    pub sample: Vec<usize>,
+    pub model: M,
+    pub score: usize,
+    pub new_best: bool,
+}
+
+/// Observer that keeps everything it receives, for plotting or debugging a run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingObserver<M> {
+    pub hypotheses: Vec<RecordedHypothesis<M>>,
+    /// Iteration index and sample of the samples without a model
+    pub failed_samples: Vec<(usize, Vec<usize>)>,
+    pub termination: Option<Termination>,
+}
+
+impl<M> RecordingObserver<M> {
+    pub fn new() -> Self {
+        Self {
+            hypotheses: vec![],
+            failed_samples: vec![],
+            termination: None,
+        }
+    }
+
+    /// Best score after each recorded hypothesis.
+    pub fn best_score_history(&self) -> Vec<usize> {
+        self.hypotheses
+            .iter()
+            .scan(0, |best, hypothesis| {
+                if hypothesis.new_best {
+                    *best = hypothesis.score;
+                }
+                Some(*best)
+            })
+            .collect()
+    }
+}
+
+impl<M> Default for RecordingObserver<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Clone> ConsensusObserver<M> for RecordingObserver<M> {
+    fn hypothesis(&mut self, hypothesis: &Hypothesis<M>) {
+        self.hypotheses.push(RecordedHypothesis {
+            iteration: hypothesis.iteration,
+            sample: hypothesis.sample.to_vec(),
+            model: hypothesis.model.clone(),
+            score: hypothesis.score,
+            new_best: hypothesis.new_best,
+        });
+    }
+
+    fn failed_sample(&mut self, iteration: usize, sample: &[usize]) {
+        self.failed_samples.push((iteration, sample.to_vec()));
+    }
+
+    fn termination(&mut self, termination: &Termination) {
+        self.termination = Some(*termination);
+    }
+}
+
+/// Wraps an [`Estimator`] on the indices of the data so sample consensus algorithms of other
+/// crates, like ARRSAC, report their hypotheses to a [`ConsensusObserver`].
+///
+/// The consensus doesn't expose its scores, so the wrapper counts the inliers of every model on
+/// all the data itself. Models that cache their residuals, like a
+/// [`SoaModel`](crate::SoaModel), don't compute them twice. Every model of a sample is reported,
+/// or the sample as failed if it has none, and [`Self::finish`] reports the termination.
+pub struct ObservedEstimator<'a, E, O> {
+    estimator: &'a E,
+    data_len: usize,
+    threshold: f64,
+    observer: RefCell<&'a mut O>,
+    iterations: Cell<usize>,
+    best_score: Cell<Option<usize>>,
+}
+
+impl<'a, E, O> ObservedEstimator<'a, E, O> {
+    /// The consensus must run on `0..data_len` with the same inlier `threshold`.
+    pub fn new(estimator: &'a E, data_len: usize, threshold: f64, observer: &'a mut O) -> Self {
+        Self {
+            estimator,
+            data_len,
+            threshold,
+            observer: RefCell::new(observer),
+            iterations: Cell::new(0),
+            best_score: Cell::new(None),
+        }
+    }
+}
+
+impl<'a, E, O> ObservedEstimator<'a, E, O>
+where
+    E: Estimator<usize>,
+    O: ConsensusObserver<E::Model>,
+{
+    /// Reports the termination, with the number of inliers of the model returned by the
+    /// consensus.
+    pub fn finish(self, best_score: Option<usize>, terminated_early: bool) {
+        self.observer.into_inner().termination(&Termination {
+            iterations: self.iterations.get(),
+            best_score,
+            terminated_early,
+        });
+    }
+}
+
+impl<'a, E, O> Estimator<usize> for ObservedEstimator<'a, E, O>
+where
+    E: Estimator<usize>,
+    O: ConsensusObserver<E::Model>,
+{
+    type Model = E::Model;
+    type ModelIter = Vec<E::Model>;
+    const MIN_SAMPLES: usize = E::MIN_SAMPLES;
+
+    fn estimate<I>(&self, data: I) -> Self::ModelIter
+    where
+        I: Iterator<Item = usize> + Clone,
+    {
+        let sample = data.collect::<Vec<_>>();
+        let iteration = self.iterations.replace(self.iterations.get() + 1);
+        let models = self
+            .estimator
+            .estimate(sample.iter().copied())
+            .into_iter()
+            .collect::<Vec<_>>();
+        let mut observer = self.observer.borrow_mut();
+        if models.is_empty() {
+            observer.failed_sample(iteration, &sample);
+        }
+        for model in &models {
+            let score = (0..self.data_len)
+                .filter(|ix| model.residual(ix) < self.threshold)
+                .count();
+            let new_best = self.best_score.get().is_none_or(|best| score > best);
+            if new_best {
+                self.best_score.set(Some(score));
+            }
+            observer.hypothesis(&Hypothesis {
+                iteration,
+                sample: &sample,
+                model,
+                score,
+                new_best,
+            });
+        }
+        models
+    }
+}
@@ -0,0 +1,224 @@
+use cv_core::FeatureMatch;
+use eyre::{eyre, Result};
+use nalgebra::{Matrix2, Matrix2x3, Matrix3, Point2, Point3, Rotation3, Vector2, Vector3};
+
+use crate::{find_homography, CameraIntrinsics, Distortion, HomographyMatrix, TargetPose};
+
+/// A pose of the plane found by [`ippe`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlanarPose {
+    pub pose: TargetPose,
+    /// Root mean square reprojection error of the matches
+    pub rms_error: f64,
+}
+
+/// Infinitesimal Plane-based Pose Estimation from the homography of a plane to the normalized
+/// image plane (`z = 1`), see [Collins and Bartoli, 2014](https://doi.org/10.1007/s11263-014-0725-5).
+///
+/// `h` maps the points of the plane to normalized image coordinates, e.g. fitted with
+/// [`find_homography`] on pixels mapped with [`CameraIntrinsics::to_normalized`]. The rotation
+/// is solved in closed form from the first order approximation of `h` at the centroid of the
+/// plane points, which has two solutions: the plane is either tilted towards the camera or away
+/// from it. Both are returned with their translations fitted to `matches`, the best one first.
+/// The second one is a genuine alternative when its reprojection error is close to the first, as
+/// for small or distant planes.
+///
+/// The errors are in normalized image coordinates. Fails with fewer than 4 matches or a
+/// degenerate homography.
+pub fn ippe_from_homography(
+    h: &HomographyMatrix,
+    matches: &[FeatureMatch<Point2<f64>>],
+) -> Result<[PlanarPose; 2]> {
+    if matches.len() < 4 {
+        return Err(eyre!(
+            "At least 4 matches are needed, got {}",
+            matches.len()
+        ));
+    }
+    let centroid = matches.iter().map(|m| m.0.coords).sum::<Vector2<f64>>() / matches.len() as f64;
+    // The homography from the plane with its origin moved to the centroid
+    let h = h.0 * Matrix3::new(1.0, 0.0, centroid.x, 0.0, 1.0, centroid.y, 0.0, 0.0, 1.0);
+    if h[(2, 2)].abs() < f64::EPSILON {
+        return Err(eyre!("The centroid of the plane is mapped to infinity"));
+    }
+    let h = h / h[(2, 2)];
+    // Image of the origin, and the Jacobian of the homography there
+    let v = Vector2::new(h[(0, 2)], h[(1, 2)]);
+    let jacobian = Matrix2::new(
+        h[(0, 0)] - h[(2, 0)] * v.x,
+        h[(0, 1)] - h[(2, 1)] * v.x,
+        h[(1, 0)] - h[(2, 0)] * v.y,
+        h[(1, 1)] - h[(2, 1)] * v.y,
+    );
+
+    // Rotation of the optical axis to the ray of the origin
+    let ray = Vector3::new(v.x, v.y, 1.0);
+    let rv = Rotation3::rotation_between(&Vector3::z(), &ray).unwrap_or_else(Rotation3::identity);
+    let b = Matrix2x3::new(1.0, 0.0, -v.x, 0.0, 1.0, -v.y) * rv.matrix().fixed_columns::<2>(0);
+    let a = b
+        .try_inverse()
+        .ok_or_else(|| eyre!("Degenerate homography"))?
+        * jacobian;
+    // Largest singular value of A
+    let ata = a.transpose() * a;
+    let (trace, det) = (ata.trace(), ata.determinant());
+    let gamma = (trace / 2.0 + (trace * trace / 4.0 - det).max(0.0).sqrt()).sqrt();
+    if gamma < f64::EPSILON {
+        return Err(eyre!("Degenerate homography"));
+    }
+    let r22 = a / gamma;
+    // The last row completes the first two columns to orthonormal vectors, up to its sign
+    let m = Matrix2::identity() - r22.transpose() * r22;
+    let b1 = m[(0, 0)].max(0.0).sqrt();
+    let b2 = m[(1, 1)].max(0.0).sqrt() * if m[(0, 1)] < 0.0 { -1.0 } else { 1.0 };
+
+    let mut solutions = [1.0, -1.0].map(|sign| {
+        let c1 = Vector3::new(r22[(0, 0)], r22[(1, 0)], sign * b1);
+        let c2 = Vector3::new(r22[(0, 1)], r22[(1, 1)], sign * b2);
+        let r = rv.matrix() * Matrix3::from_columns(&[c1, c2, c1.cross(&c2)]);
+        let rotation = Rotation3::from_matrix(&r);
+        let pose = TargetPose {
+            rotation,
+            translation: translation(&rotation, matches),
+        };
+        let normalized = CameraIntrinsics::from_matrix(&Matrix3::identity());
+        PlanarPose {
+            pose,
+            rms_error: rms_error(&pose, matches, &normalized, &Distortion::default()),
+        }
+    });
+    solutions.sort_by(|a, b| a.rms_error.total_cmp(&b.rms_error));
+    Ok(solutions)
+}
+
+/// Estimates the pose of a plane from matches of its points to their pixels with
+/// [`ippe_from_homography`].
+///
+/// The pixels are undistorted and normalized with the calibration of the camera, then the
+/// homography is fitted with [`find_homography`]. The reprojection errors of the two solutions
+/// are in pixels.
+pub fn ippe(
+    matches: &[FeatureMatch<Point2<f64>>],
+    intrinsics: &CameraIntrinsics,
+    distortion: &Distortion,
+) -> Result<[PlanarPose; 2]> {
+    let normalized = matches
+        .iter()
+        .map(|FeatureMatch(plane, pixel)| {
+            FeatureMatch(
+                *plane,
+                distortion.undistort(&intrinsics.to_normalized(pixel)),
+            )
+        })
+        .collect::<Vec<_>>();
+    let h = find_homography(normalized.clone())?;
+    let mut solutions = ippe_from_homography(&HomographyMatrix(h), &normalized)?;
+    for solution in solutions.iter_mut() {
+        solution.rms_error = rms_error(&solution.pose, matches, intrinsics, distortion);
+    }
+    solutions.sort_by(|a, b| a.rms_error.total_cmp(&b.rms_error));
+    Ok(solutions)
+}
+
+/// Least squares translation of a rotated plane, minimizing the algebraic error of the
+/// projections `x = (X + t) / (X + t)_z` in normalized coordinates.
+fn translation(rotation: &Rotation3<f64>, matches: &[FeatureMatch<Point2<f64>>]) -> Vector3<f64> {
+    let mut ata = Matrix3::zeros();
+    let mut atb = Vector3::zeros();
+    for FeatureMatch(plane, image) in matches {
+        let p = rotation * Point3::new(plane.x, plane.y, 0.0);
+        for (row, rhs) in [
+            (Vector3::new(1.0, 0.0, -image.x), image.x * p.z - p.x),
+            (Vector3::new(0.0, 1.0, -image.y), image.y * p.z - p.y),
+        ] {
+            ata += row * row.transpose();
+            atb += row * rhs;
+        }
+    }
+    ata.try_inverse()
+        .map(|inverse| inverse * atb)
+        .unwrap_or_else(Vector3::zeros)
+}
+
+/// Points behind the camera make the error infinite.
+fn rms_error(
+    pose: &TargetPose,
+    matches: &[FeatureMatch<Point2<f64>>],
+    intrinsics: &CameraIntrinsics,
+    distortion: &Distortion,
+) -> f64 {
+    let squared = matches
+        .iter()
+        .map(|FeatureMatch(plane, image)| {
+            pose.project(plane, intrinsics, distortion)
+                .map_or(f64::INFINITY, |p| nalgebra::distance_squared(&p, image))
+        })
+        .sum::<f64>();
+    (squared / matches.len() as f64).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        find_homography, ippe, ippe_from_homography, CameraIntrinsics, Distortion,
+        HomographyMatrix, TargetPose,
+    };
+    use approx::assert_relative_eq;
+    use cv_core::FeatureMatch;
+    use nalgebra::{Point2, Rotation3, Vector3};
+
+    fn pose() -> TargetPose {
+        TargetPose {
+            rotation: Rotation3::from_euler_angles(0.4, -0.3, 0.2),
+            translation: Vector3::new(0.1, -0.05, 2.0),
+        }
+    }
+
+    fn matches(
+        intrinsics: &CameraIntrinsics,
+        distortion: &Distortion,
+    ) -> Vec<FeatureMatch<Point2<f64>>> {
+        (0..5)
+            .flat_map(|i| (0..4).map(move |j| Point2::new(i as f64 * 0.1, j as f64 * 0.1 + 0.3)))
+            .map(|p| FeatureMatch(p, pose().project(&p, intrinsics, distortion).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn both_solutions_from_homography() {
+        let normalized = CameraIntrinsics::from_matrix(&nalgebra::Matrix3::identity());
+        let matches = matches(&normalized, &Distortion::default());
+        let h = HomographyMatrix(find_homography(matches.clone()).unwrap());
+        let [best, other] = ippe_from_homography(&h, &matches).unwrap();
+
+        assert_relative_eq!(best.pose.rotation, pose().rotation, epsilon = 1e-9);
+        assert_relative_eq!(best.pose.translation, pose().translation, epsilon = 1e-9);
+        assert!(best.rms_error < 1e-12);
+        // The other solution is the plane mirrored around the line of sight
+        assert!(other.rms_error > best.rms_error);
+        assert!(other.pose.rotation.angle_to(&pose().rotation) > 0.1);
+
+        assert!(ippe_from_homography(&h, &matches[..3]).is_err());
+    }
+
+    #[test]
+    fn pose_from_pixels() {
+        let intrinsics = CameraIntrinsics {
+            fx: 800.0,
+            fy: 790.0,
+            cx: 320.0,
+            cy: 240.0,
+            skew: 0.0,
+        };
+        let distortion = Distortion {
+            k1: -0.2,
+            k2: 0.05,
+            ..Default::default()
+        };
+        let matches = matches(&intrinsics, &distortion);
+        let [best, _] = ippe(&matches, &intrinsics, &distortion).unwrap();
+        assert_relative_eq!(best.pose.rotation, pose().rotation, epsilon = 1e-6);
+        assert_relative_eq!(best.pose.translation, pose().translation, epsilon = 1e-6);
+        assert!(best.rms_error < 1e-6);
+    }
+}
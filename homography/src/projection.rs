@@ -0,0 +1,302 @@
+#[cfg(feature = "image")]
+use eyre::{eyre, Result};
+#[cfg(feature = "image")]
+use image::{ImageBuffer, Pixel};
+use nalgebra::{Matrix3, Point2, Rotation3, Vector3};
+
+use crate::camera_matrix;
+#[cfg(feature = "image")]
+use crate::{warp::warp_with, BorderMode, Interpolation};
+
+/// The surface the panorama is projected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Surface {
+    /// Vertical cylinder around the camera: the horizontal coordinate is the yaw angle,
+    /// the vertical one is the height on the cylinder. Straight vertical lines stay straight.
+    #[default]
+    Cylindrical,
+    /// Sphere around the camera: the coordinates are the yaw and the pitch angle.
+    /// Can cover the full field of view, but bends the lines more than the cylinder.
+    Spherical,
+}
+
+/// Maps the pixels of a rotated camera to a cylinder or a sphere around it.
+///
+/// Planar warping with a [`HomographyMatrix`](crate::HomographyMatrix) stretches the images
+/// without limit as the field of view approaches 180°. Projecting the rays of the pixels to a
+/// surface around the camera keeps the distortion bounded for wide panoramas.
+///
+/// The camera has square pixels with the given focal length and principal point.
+/// `rotation` maps the rays of the camera to the frame of the panorama, which is the camera
+/// frame of the reference image: with intrinsics `K`, the homography of the camera to
+/// the reference image is `K R K⁻¹`. Surface coordinates are in pixels: angles are multiplied
+/// with `scale`, and the optical axis of the reference camera is at the origin.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SurfaceWarper {
+    pub surface: Surface,
+    pub focal: f64,
+    pub principal_point: Point2<f64>,
+    pub rotation: Rotation3<f64>,
+    /// Radius of the surface. Usually the focal length, so the reference image keeps its
+    /// resolution around its center.
+    pub scale: f64,
+}
+
+impl SurfaceWarper {
+    /// A warper with the scale set to the focal length.
+    pub fn new(
+        surface: Surface,
+        focal: f64,
+        principal_point: Point2<f64>,
+        rotation: Rotation3<f64>,
+    ) -> Self {
+        Self {
+            surface,
+            focal,
+            principal_point,
+            rotation,
+            scale: focal,
+        }
+    }
+
+    /// Intrinsic matrix of the camera.
+    pub fn camera_matrix(&self) -> Matrix3<f64> {
+        camera_matrix(self.focal, self.principal_point)
+    }
+
+    /// Maps a pixel of the image to the surface.
+    pub fn forward(&self, p: &Point2<f64>) -> Option<Point2<f64>> {
+        let ray = Vector3::new(
+            (p.x - self.principal_point.x) / self.focal,
+            (p.y - self.principal_point.y) / self.focal,
+            1.0,
+        );
+        let ray = self.rotation * ray;
+        let (x, y, z) = (ray.x, ray.y, ray.z);
+        let yaw = x.atan2(z);
+        let horizontal = x.hypot(z);
+        let v = match self.surface {
+            Surface::Cylindrical if horizontal > f64::EPSILON => y / horizontal,
+            Surface::Cylindrical => return None,
+            Surface::Spherical => y.atan2(horizontal),
+        };
+        Some(Point2::new(yaw, v) * self.scale)
+    }
+
+    /// Maps a point of the surface to the image. `None` if the point is behind the camera.
+    pub fn backward(&self, q: &Point2<f64>) -> Option<Point2<f64>> {
+        let (yaw, v) = (q.x / self.scale, q.y / self.scale);
+        let ray = match self.surface {
+            Surface::Cylindrical => Vector3::new(yaw.sin(), v, yaw.cos()),
+            Surface::Spherical => Vector3::new(yaw.sin() * v.cos(), v.sin(), yaw.cos() * v.cos()),
+        };
+        let ray = self.rotation.inverse() * ray;
+        if ray.z <= f64::EPSILON {
+            return None;
+        }
+        Some(Point2::new(
+            self.principal_point.x + self.focal * ray.x / ray.z,
+            self.principal_point.y + self.focal * ray.y / ray.z,
+        ))
+    }
+
+    /// Bounding box of a `width`×`height` image on the surface.
+    ///
+    /// The edges of the image are curves on the surface, so they are sampled at every few pixels.
+    /// `None` if a pole of the surface is inside the image, or an edge pixel is mapped to a pole.
+    /// The image should not contain the direction opposite to the optical axis of the reference
+    /// camera, where the yaw wraps around.
+    pub fn bounds(&self, width: u32, height: u32) -> Option<(Point2<f64>, Point2<f64>)> {
+        let (right, bottom) = (width as f64 - 1.0, height as f64 - 1.0);
+        // Around a pole the image covers every yaw, and the edges don't bound it
+        for pole in [Vector3::y(), -Vector3::y()] {
+            let ray = self.rotation.inverse() * pole;
+            if ray.z > f64::EPSILON {
+                let x = self.principal_point.x + self.focal * ray.x / ray.z;
+                let y = self.principal_point.y + self.focal * ray.y / ray.z;
+                if (0.0..=right).contains(&x) && (0.0..=bottom).contains(&y) {
+                    return None;
+                }
+            }
+        }
+        let steps = (width.max(height) / 8).max(1);
+        let mut min = Point2::new(f64::INFINITY, f64::INFINITY);
+        let mut max = Point2::new(f64::NEG_INFINITY, f64::NEG_INFINITY);
+        for i in 0..=steps {
+            let t = i as f64 / steps as f64;
+            for p in [
+                Point2::new(t * right, 0.0),
+                Point2::new(t * right, bottom),
+                Point2::new(0.0, t * bottom),
+                Point2::new(right, t * bottom),
+            ] {
+                let q = self.forward(&p)?;
+                min = min.inf(&q);
+                max = max.sup(&q);
+            }
+        }
+        Some((min, max))
+    }
+
+    #[cfg(feature = "image")]
+    /// Projects an image to the surface.
+    /// *This is supported on **crate feature `image`** only.*
+    ///
+    /// The result covers the [bounds](Self::bounds) of the image. Fails if the image can't be
+    /// projected to the surface, or if the result would have more than `max_pixels` pixels,
+    /// which happens when the image reaches close to a pole of a cylinder.
+    pub fn warp<P>(
+        &self,
+        image: &ImageBuffer<P, Vec<P::Subpixel>>,
+        interpolation: Interpolation,
+        border: BorderMode<P>,
+        max_pixels: u64,
+    ) -> Result<SurfaceImage<P>>
+    where
+        P: Pixel + Sync + 'static,
+        P::Subpixel: Send + Sync,
+    {
+        let (min, max) = self
+            .bounds(image.width(), image.height())
+            .ok_or_else(|| eyre!("The image covers a pole of the surface"))?;
+        let (min, max) = (min.map(f64::floor), max.map(f64::ceil));
+        let (width, height) = (max.x - min.x + 1.0, max.y - min.y + 1.0);
+        if width * height > max_pixels as f64 {
+            return Err(eyre!(
+                "The projected image would be {}x{} pixels, more than the limit of {}",
+                width,
+                height,
+                max_pixels
+            ));
+        }
+        let image = warp_with(
+            image,
+            width as u32,
+            height as u32,
+            interpolation,
+            border,
+            |x, y| self.backward(&Point2::new(x + min.x, y + min.y)),
+        );
+        Ok(SurfaceImage {
+            image,
+            origin: min.coords,
+        })
+    }
+}
+
+#[cfg(feature = "image")]
+/// An image projected to a surface with [`SurfaceWarper::warp`].
+#[derive(Debug, Clone)]
+pub struct SurfaceImage<P: Pixel> {
+    pub image: ImageBuffer<P, Vec<P::Subpixel>>,
+    /// Surface coordinates of the top left pixel
+    pub origin: nalgebra::Vector2<f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Surface, SurfaceWarper};
+    use approx::assert_relative_eq;
+    use nalgebra::{Point2, Rotation3, Vector3};
+
+    fn warper(surface: Surface, yaw: f64, pitch: f64) -> SurfaceWarper {
+        let rotation = Rotation3::from_axis_angle(&Vector3::y_axis(), yaw)
+            * Rotation3::from_axis_angle(&Vector3::x_axis(), pitch);
+        SurfaceWarper::new(surface, 500.0, Point2::new(320.0, 240.0), rotation)
+    }
+
+    #[test]
+    fn forward_and_backward_round_trip() {
+        for surface in [Surface::Cylindrical, Surface::Spherical] {
+            let warper = warper(surface, 0.7, -0.2);
+            for (x, y) in [(0.0, 0.0), (320.0, 240.0), (639.0, 17.0), (100.0, 479.0)] {
+                let p = Point2::new(x, y);
+                let q = warper.forward(&p).unwrap();
+                assert_relative_eq!(warper.backward(&q).unwrap(), p, epsilon = 1e-8);
+            }
+        }
+    }
+
+    #[test]
+    fn yaw_shifts_along_the_surface() {
+        for surface in [Surface::Cylindrical, Surface::Spherical] {
+            let center = Point2::new(320.0, 240.0);
+            assert_relative_eq!(
+                warper(surface, 0.0, 0.0).forward(&center).unwrap(),
+                Point2::origin()
+            );
+            let p = Point2::new(100.0, 400.0);
+            let q0 = warper(surface, 0.0, 0.0).forward(&p).unwrap();
+            let q1 = warper(surface, 0.5, 0.0).forward(&p).unwrap();
+            assert_relative_eq!(q1 - q0, nalgebra::Vector2::new(250.0, 0.0), epsilon = 1e-9);
+        }
+        // Points behind the camera can't be seen
+        let warper = warper(Surface::Cylindrical, 0.0, 0.0);
+        assert!(warper.backward(&Point2::new(500.0 * 3.0, 0.0)).is_none());
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn warps_the_image() {
+        use crate::{BorderMode, Interpolation};
+        use image::{GrayImage, Luma};
+
+        let image = GrayImage::from_fn(640, 480, |x, y| Luma([((x / 8 + y / 8) % 2 * 255) as u8]));
+        for surface in [Surface::Cylindrical, Surface::Spherical] {
+            let warper = warper(surface, 0.3, 0.1);
+            let warped = warper
+                .warp(
+                    &image,
+                    Interpolation::Nearest,
+                    BorderMode::Constant(Luma([7])),
+                    u64::MAX,
+                )
+                .unwrap();
+            let (min, max) = warper.bounds(640, 480).unwrap();
+            assert_eq!(
+                warped.image.width(),
+                (max.x.ceil() - min.x.floor()) as u32 + 1
+            );
+            for (x, y) in [(4.0, 4.0), (322.0, 241.0), (600.0, 470.0)] {
+                let q = warper.forward(&Point2::new(x, y)).unwrap() - warped.origin;
+                let (qx, qy) = (q.x.round() as u32, q.y.round() as u32);
+                let p = warper.backward(&(Point2::new(qx as f64, qy as f64) + warped.origin));
+                let p = p.unwrap().map(|v| v.round() as u32);
+                assert_eq!(warped.image.get_pixel(qx, qy), image.get_pixel(p.x, p.y));
+            }
+            // The corners of the output are outside of the curved image
+            assert_eq!(warped.image.get_pixel(0, 0).0, [7]);
+        }
+    }
+
+    #[test]
+    fn pole_inside_the_image() {
+        for surface in [Surface::Cylindrical, Surface::Spherical] {
+            // Looking almost straight up, the pole is inside the image but not on its edges
+            assert!(warper(surface, 0.3, 1.4).bounds(640, 480).is_none());
+            assert!(warper(surface, 0.3, -1.4).bounds(640, 480).is_none());
+            // Tilted less, the pole is above the image
+            assert!(warper(surface, 0.3, 0.6).bounds(640, 480).is_some());
+        }
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn warp_fails_past_the_pixel_limit() {
+        use crate::{BorderMode, Interpolation};
+        use image::{GrayImage, Luma};
+
+        let image = GrayImage::new(640, 480);
+        // The top edge reaches close to the pole, far up the cylinder
+        let warper = warper(Surface::Cylindrical, 0.0, 1.1);
+        let (min, max) = warper.bounds(640, 480).unwrap();
+        assert!((max.y - min.y) * (max.x - min.x) > 10_000_000.0);
+        let result = warper.warp(
+            &image,
+            Interpolation::Nearest,
+            BorderMode::Constant(Luma([0])),
+            10_000_000,
+        );
+        assert!(result.is_err());
+    }
+}
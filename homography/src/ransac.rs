@@ -0,0 +1,646 @@
+use cv_core::FeatureMatch;
+use rand::{Rng, RngCore, SeedableRng};
+use rand_pcg::Pcg64;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use sample_consensus::{Consensus, Estimator};
+
+use crate::budget::BudgetTracker;
+use crate::{
+    BatchResidual, Budget, ConsensusData, ConsensusObserver, FeatureMatchSoa, HomographyEstimator,
+    HomographyMatrix, Hypothesis, NoopObserver, RobustEstimate, Termination, WeightedFeatureMatch,
+};
+
+type Point2 = nalgebra::Point2<f64>;
+
+/// Classic RANSAC sample consensus with adaptive termination.
+/// *This is supported on **crate feature `ransac`** only.*
+///
+/// Hypotheses are generated in batches. The random samples of a batch are drawn sequentially from
+/// the RNG, then the models are estimated and scored independently, in parallel with the `rayon`
+/// feature. Ties are broken by the order of the hypotheses, so the result only depends on the seed
+/// and never on the number of threads.
+///
+/// Runs on slices of any data, or on any [`ConsensusData`] whose models score it with a
+/// [`BatchResidual`] implementation, like homographies on a [`FeatureMatchSoa`]. The slice methods
+/// score every datum with [`Model::residual`](sample_consensus::Model::residual), they don't
+/// convert point matches to a [`FeatureMatchSoa`]. [`find_homography_with_ransac`] does.
+pub struct Ransac<R> {
+    inlier_threshold: f64,
+    max_iterations: usize,
+    confidence: f64,
+    batch_size: usize,
+    budget: Budget,
+    sampling_weights: Option<Vec<f64>>,
+    rng: R,
+}
+
+impl<R> Ransac<R>
+where
+    R: RngCore,
+{
+    /// `inlier_threshold` is compared against the residuals of the model,
+    /// for [`HomographyMatrix`] that is the squared reprojection error.
+    pub fn new(inlier_threshold: f64, rng: R) -> Self {
+        Self {
+            inlier_threshold,
+            max_iterations: 1000,
+            confidence: 0.995,
+            batch_size: 32,
+            budget: Budget::default(),
+            sampling_weights: None,
+            rng,
+        }
+    }
+
+    /// Upper limit of the generated hypotheses. Default: `1000`
+    pub fn max_iterations(self, max_iterations: usize) -> Self {
+        Self {
+            max_iterations,
+            ..self
+        }
+    }
+
+    /// Probability of drawing at least one outlier free sample before stopping. Default: `0.995`
+    pub fn confidence(self, confidence: f64) -> Self {
+        Self { confidence, ..self }
+    }
+
+    /// Number of hypotheses generated and scored together. Default: `32`
+    ///
+    /// The termination criterion is only checked between batches.
+    pub fn batch_size(self, batch_size: usize) -> Self {
+        Self {
+            batch_size: batch_size.max(1),
+            ..self
+        }
+    }
+
+    /// Limits the time and the number of hypotheses of a run. Unlimited by default.
+    ///
+    /// Setting a time limit or a cancellation token makes the result depend on the timing.
+    pub fn budget(self, budget: Budget) -> Self {
+        Self { budget, ..self }
+    }
+
+    /// Draws the samples with probabilities proportional to `weights`, one per datum, e.g. the
+    /// confidences of the matches. Uniform by default.
+    ///
+    /// Data with a non-positive weight are never sampled, but they can still be inliers. The
+    /// number of iterations is derived from the inlier ratio as for uniform sampling, which is
+    /// conservative when the weights favour the inliers. Runs on data of another length panic.
+    pub fn sampling_weights(self, weights: Vec<f64>) -> Self {
+        Self {
+            sampling_weights: Some(weights),
+            ..self
+        }
+    }
+
+    /// Finds the model with the most inliers in `data` and returns it with the inlier indices.
+    ///
+    /// The hypotheses are scored with the scalar residuals of the model. For homographies, passing
+    /// a [`FeatureMatchSoa`] to [`Self::estimate`] scores them in batches instead.
+    pub fn model_inliers_slice<E, Data>(
+        &mut self,
+        estimator: &E,
+        data: &[Data],
+    ) -> Option<(E::Model, Vec<usize>)>
+    where
+        E: Estimator<Data> + Sync,
+        E::Model: Send + Sync,
+        Data: Clone + Sync,
+    {
+        self.estimate_slice(estimator, data)
+            .map(|estimate| (estimate.model, estimate.inliers))
+    }
+
+    /// Like [`Self::model_inliers_slice`], but also reports whether the [`Budget`] ran out
+    /// before the termination criterion was met. In that case the best model found so far is returned.
+    pub fn estimate_slice<E, Data>(
+        &mut self,
+        estimator: &E,
+        data: &[Data],
+    ) -> Option<RobustEstimate<E::Model>>
+    where
+        E: Estimator<Data> + Sync,
+        E::Model: Send + Sync,
+        Data: Clone + Sync,
+    {
+        self.estimate(estimator, data)
+    }
+
+    /// Like [`Self::estimate_slice`], but reports every scored hypothesis and the termination
+    /// to `observer`.
+    pub fn estimate_slice_observed<E, Data, O>(
+        &mut self,
+        estimator: &E,
+        data: &[Data],
+        observer: &mut O,
+    ) -> Option<RobustEstimate<E::Model>>
+    where
+        E: Estimator<Data> + Sync,
+        E::Model: Send + Sync,
+        Data: Clone + Sync,
+        O: ConsensusObserver<E::Model>,
+    {
+        self.estimate_observed(estimator, data, observer)
+    }
+
+    /// Like [`Self::estimate_slice`] on any [`ConsensusData`], scored with the [`BatchResidual`]
+    /// implementation of the model, e.g. homographies on a prebuilt [`FeatureMatchSoa`].
+    pub fn estimate<E, D>(&mut self, estimator: &E, data: &D) -> Option<RobustEstimate<E::Model>>
+    where
+        D: ConsensusData + ?Sized,
+        E: Estimator<D::Item> + Sync,
+        E::Model: BatchResidual<D> + Send,
+    {
+        self.estimate_observed(estimator, data, &mut NoopObserver)
+    }
+
+    /// Like [`Self::estimate`], but reports every scored hypothesis and the termination to
+    /// `observer`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "consensus",
+            level = "debug",
+            skip_all,
+            fields(matches = data.len(), threshold = self.inlier_threshold)
+        )
+    )]
+    pub fn estimate_observed<E, D, O>(
+        &mut self,
+        estimator: &E,
+        data: &D,
+        observer: &mut O,
+    ) -> Option<RobustEstimate<E::Model>>
+    where
+        D: ConsensusData + ?Sized,
+        E: Estimator<D::Item> + Sync,
+        E::Model: BatchResidual<D> + Send,
+        O: ConsensusObserver<E::Model>,
+    {
+        let n = data.len();
+        let sample_size = E::MIN_SAMPLES;
+        // Samples are drawn by inverting the cumulative distribution of the weights
+        let cumulative_weights = self.sampling_weights.as_ref().map(|weights| {
+            assert_eq!(weights.len(), n, "one sampling weight per datum is needed");
+            weights
+                .iter()
+                .scan(0.0, |total, weight| {
+                    *total += weight.max(0.0);
+                    Some(*total)
+                })
+                .collect::<Vec<_>>()
+        });
+        let samplable = self.sampling_weights.as_ref().map_or(n, |weights| {
+            weights.iter().filter(|&&weight| weight > 0.0).count()
+        });
+        if samplable < sample_size {
+            observer.termination(&Termination {
+                iterations: 0,
+                best_score: None,
+                terminated_early: false,
+            });
+            return None;
+        }
+
+        #[cfg(feature = "tracing")]
+        let start = std::time::Instant::now();
+        let tracker = BudgetTracker::new(&self.budget);
+        let mut best: Option<(usize, E::Model)> = None;
+        let mut required_iterations = self.max_iterations;
+        let mut iterations = 0;
+        let mut samples = Vec::with_capacity(self.batch_size * sample_size);
+        while iterations < required_iterations && !tracker.exhausted() {
+            // The evaluation limit is applied when planning the batch, so it's deterministic
+            let batch_size = self
+                .batch_size
+                .min(required_iterations - iterations)
+                .min(tracker.remaining_evaluations());
+            if batch_size == 0 {
+                tracker.mark_exhausted();
+                break;
+            }
+            let first_iteration = iterations;
+            iterations += batch_size;
+            #[cfg(feature = "tracing")]
+            let _batch_span =
+                tracing::trace_span!("consensus_batch", first_iteration, batch_size).entered();
+
+            samples.clear();
+            for _ in 0..batch_size {
+                let start = samples.len();
+                while samples.len() < start + sample_size {
+                    let ix = match &cumulative_weights {
+                        Some(cumulative) => {
+                            let u = self.rng.gen_range(0.0..cumulative[n - 1]);
+                            cumulative.partition_point(|&c| c <= u)
+                        }
+                        None => self.rng.gen_range(0..n),
+                    };
+                    if !samples[start..].contains(&ix) {
+                        samples.push(ix);
+                    }
+                }
+            }
+
+            let threshold = self.inlier_threshold;
+            // `None` for the samples skipped because the budget ran out
+            let evaluate = |sample: &[usize]| {
+                if !tracker.try_evaluate() {
+                    return None;
+                }
+                let models = estimator.estimate(sample.iter().map(|&ix| data.item(ix)));
+                Some(
+                    models
+                        .into_iter()
+                        .map(|model| (model.count_inliers(data, threshold), model))
+                        .max_by_key(|(score, _)| *score),
+                )
+            };
+            #[cfg(feature = "rayon")]
+            let hypotheses: Vec<_> = samples.par_chunks(sample_size).map(evaluate).collect();
+            #[cfg(not(feature = "rayon"))]
+            let hypotheses: Vec<_> = samples.chunks(sample_size).map(evaluate).collect();
+
+            // Only a strictly better hypothesis replaces the best one, so ties keep the earliest
+            let samples = samples.chunks(sample_size);
+            for (ix, (sample, hypothesis)) in samples.zip(hypotheses).enumerate() {
+                let Some(hypothesis) = hypothesis else {
+                    continue;
+                };
+                let Some((score, model)) = hypothesis else {
+                    observer.failed_sample(first_iteration + ix, sample);
+                    continue;
+                };
+                let new_best = best
+                    .as_ref()
+                    .is_none_or(|(best_score, _)| score > *best_score);
+                observer.hypothesis(&Hypothesis {
+                    iteration: first_iteration + ix,
+                    sample,
+                    model: &model,
+                    score,
+                    new_best,
+                });
+                if new_best {
+                    #[cfg(feature = "tracing")]
+                    tracing::trace!(
+                        iteration = first_iteration + ix,
+                        score,
+                        "new best hypothesis"
+                    );
+                    best = Some((score, model));
+                }
+            }
+
+            if let Some((score, _)) = &best {
+                required_iterations = required_iterations
+                    .min(self.required_iterations(*score as f64 / n as f64, sample_size));
+            }
+        }
+
+        let terminated_early = tracker.exhausted();
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            iterations,
+            inliers = best.as_ref().map(|(score, _)| *score),
+            terminated_early,
+            elapsed = ?start.elapsed(),
+            "consensus finished"
+        );
+        observer.termination(&Termination {
+            iterations,
+            best_score: best.as_ref().map(|(score, _)| *score),
+            terminated_early,
+        });
+        best.map(|(_, model)| {
+            let threshold = self.inlier_threshold;
+            let mut inliers = vec![];
+            model.inliers(data, threshold, &mut inliers);
+            RobustEstimate {
+                model,
+                inliers,
+                terminated_early,
+            }
+        })
+    }
+
+    /// Number of iterations needed to draw an outlier free sample with the configured confidence.
+    fn required_iterations(&self, inlier_ratio: f64, sample_size: usize) -> usize {
+        let outlier_free = inlier_ratio.powi(sample_size as i32);
+        if outlier_free >= 1.0 {
+            return 0;
+        }
+        if outlier_free <= 0.0 {
+            return self.max_iterations;
+        }
+        let iterations = (1.0 - self.confidence).ln() / (1.0 - outlier_free).ln();
+        if iterations.is_finite() {
+            (iterations.ceil() as usize).min(self.max_iterations)
+        } else {
+            self.max_iterations
+        }
+    }
+}
+
+impl<E, R, Data> Consensus<E, Data> for Ransac<R>
+where
+    E: Estimator<Data> + Sync,
+    E::Model: Send + Sync,
+    R: RngCore,
+    Data: Clone + Sync,
+{
+    type Inliers = Vec<usize>;
+
+    fn model<I>(&mut self, estimator: &E, data: I) -> Option<E::Model>
+    where
+        I: Iterator<Item = Data> + Clone,
+    {
+        self.model_inliers(estimator, data).map(|(model, _)| model)
+    }
+
+    fn model_inliers<I>(&mut self, estimator: &E, data: I) -> Option<(E::Model, Self::Inliers)>
+    where
+        I: Iterator<Item = Data> + Clone,
+    {
+        let data = data.collect::<Vec<_>>();
+        self.model_inliers_slice(estimator, &data)
+    }
+}
+
+/// Find homography with the built-in [`Ransac`] sample consensus algorithm.
+/// *This is supported on **crate feature `ransac`** only.*
+pub fn find_homography_with_ransac(matches: &[FeatureMatch<Point2>]) -> Option<HomographyMatrix> {
+    let mut ransac = Ransac::new(0.1, Pcg64::from_seed([1; 32]));
+    let estimator = HomographyEstimator::default();
+    ransac
+        .estimate(&estimator, &FeatureMatchSoa::from(matches))
+        .map(|estimate| estimate.model)
+}
+
+/// Find homography with the built-in [`Ransac`], sampling the matches proportionally to their
+/// weights.  
+/// *This is supported on **crate feature `ransac`** only.*
+///
+/// The weights also weight the least squares fit of every sample, but the inlier threshold is
+/// the same for every match, see [`WeightedFeatureMatch`].
+pub fn find_homography_with_ransac_weighted(
+    matches: &[WeightedFeatureMatch],
+) -> Option<HomographyMatrix> {
+    let mut ransac = Ransac::new(0.1, Pcg64::from_seed([1; 32]))
+        .sampling_weights(matches.iter().map(|m| m.1).collect());
+    let estimator = HomographyEstimator::default();
+    ransac
+        .estimate(&estimator, matches)
+        .map(|estimate| estimate.model)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{HomographyEstimator, Ransac};
+    use approx::AbsDiffEq;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+    use test_utils::TestData;
+
+    #[test]
+    fn finds_model_among_outliers() {
+        for seed in 0..8 {
+            let mut rng = Pcg64::seed_from_u64(100 + seed);
+            let TestData { matches, h: h_src } =
+                TestData::with_outliers_from_rng(&mut rng, 200, 0.4);
+            let mut ransac = Ransac::new(0.01, Pcg64::seed_from_u64(seed));
+            let (h, inliers) = ransac
+                .model_inliers_slice(&HomographyEstimator::default(), &matches)
+                .unwrap();
+            assert!(h_src.abs_diff_eq(&h.0, 0.0001));
+            assert_eq!(inliers, (0..120).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn same_result_on_a_prebuilt_soa() {
+        use crate::FeatureMatchSoa;
+
+        let mut rng = Pcg64::seed_from_u64(1);
+        let TestData { matches, .. } = TestData::with_outliers_from_rng(&mut rng, 300, 0.5);
+        let soa = FeatureMatchSoa::from(&matches[..]);
+        let run = || Ransac::new(0.01, Pcg64::seed_from_u64(11));
+        let from_slice = run()
+            .estimate_slice(&HomographyEstimator::default(), &matches)
+            .unwrap();
+        let from_soa = run()
+            .estimate(&HomographyEstimator::default(), &soa)
+            .unwrap();
+        assert_eq!(from_slice.inliers, from_soa.inliers);
+        assert!(from_slice.model.0.abs_diff_eq(&from_soa.model.0, 1e-9));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn same_seed_same_result_on_any_thread_count() {
+        let mut rng = Pcg64::seed_from_u64(7);
+        let TestData { matches, .. } = TestData::with_outliers_from_rng(&mut rng, 500, 0.7);
+        let run = |threads| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+                .install(|| {
+                    Ransac::new(0.01, Pcg64::seed_from_u64(7))
+                        .batch_size(5)
+                        .model_inliers_slice(&HomographyEstimator::default(), &matches)
+                        .unwrap()
+                })
+        };
+        let (h1, inliers1) = run(1);
+        for threads in [2, 8] {
+            let (h, inliers) = run(threads);
+            assert_eq!(h1, h, "{} threads", threads);
+            assert_eq!(inliers1, inliers, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn samples_proportionally_to_the_weights() {
+        use crate::{
+            find_homography_with_ransac_weighted, RecordingObserver, WeightedFeatureMatch,
+        };
+
+        let mut rng = Pcg64::seed_from_u64(2);
+        let TestData { matches, h: h_src } = TestData::with_outliers_from_rng(&mut rng, 200, 0.5);
+        // The first 50 matches are 10 times more likely to be drawn, the last 50 never are
+        let weights = (0..200)
+            .map(|ix| match ix {
+                0..=49 => 10.0,
+                150.. => 0.0,
+                _ => 1.0,
+            })
+            .collect::<Vec<_>>();
+        let mut observer = RecordingObserver::new();
+        Ransac::new(0.01, Pcg64::seed_from_u64(2))
+            .max_iterations(200)
+            .confidence(1.0)
+            .sampling_weights(weights.clone())
+            .estimate_slice_observed(&HomographyEstimator::default(), &matches, &mut observer)
+            .unwrap();
+        let drawn = observer
+            .hypotheses
+            .iter()
+            .flat_map(|h| h.sample.iter().copied())
+            .collect::<Vec<_>>();
+        assert!(drawn.iter().all(|&ix| ix < 150));
+        // 500 out of a total weight of 600, 25% with uniform sampling
+        let heavy = drawn.iter().filter(|&&ix| ix < 50).count() as f64 / drawn.len() as f64;
+        assert!(heavy > 0.7, "{heavy}");
+
+        // The heavy matches are all inliers
+        let weighted = matches
+            .iter()
+            .zip(weights)
+            .map(|(&m, weight)| WeightedFeatureMatch(m, weight))
+            .collect::<Vec<_>>();
+        let h = find_homography_with_ransac_weighted(&weighted).unwrap();
+        assert!(h_src.abs_diff_eq(&h.0, 0.0001));
+    }
+
+    #[test]
+    fn stops_when_the_budget_runs_out() {
+        use crate::Budget;
+
+        let mut rng = Pcg64::seed_from_u64(3);
+        let TestData { matches, .. } = TestData::with_outliers_from_rng(&mut rng, 200, 0.4);
+        let estimate = Ransac::new(0.01, Pcg64::seed_from_u64(3))
+            .budget(Budget::new().max_evaluations(5))
+            .estimate_slice(&HomographyEstimator::default(), &matches)
+            .unwrap();
+        assert!(estimate.terminated_early);
+
+        let estimate = Ransac::new(0.01, Pcg64::seed_from_u64(3))
+            .budget(Budget::new().max_evaluations(10_000))
+            .estimate_slice(&HomographyEstimator::default(), &matches)
+            .unwrap();
+        assert!(!estimate.terminated_early);
+    }
+
+    #[test]
+    fn stops_when_the_time_runs_out() {
+        use crate::Budget;
+        use std::time::{Duration, Instant};
+
+        // Too many outliers to ever reach the confidence
+        let mut rng = Pcg64::seed_from_u64(4);
+        let TestData { matches, .. } = TestData::with_outliers_from_rng(&mut rng, 20_000, 0.99);
+        let run = |time_limit| {
+            Ransac::new(0.01, Pcg64::seed_from_u64(3))
+                .max_iterations(usize::MAX)
+                .budget(Budget::new().time_limit(time_limit))
+                .estimate_slice(&HomographyEstimator::default(), &matches)
+        };
+        // Nothing can be evaluated without time
+        assert!(run(Duration::ZERO).is_none());
+
+        let start = Instant::now();
+        let estimate = run(Duration::from_millis(20)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(estimate.terminated_early);
+        // The best model so far is returned with its inliers
+        assert!(!estimate.inliers.is_empty());
+    }
+
+    #[test]
+    fn cancel_from_another_thread() {
+        use crate::{Budget, CancellationToken};
+        use std::time::Duration;
+
+        // Too many outliers to ever reach the confidence
+        let mut rng = Pcg64::seed_from_u64(4);
+        let TestData { matches, .. } = TestData::with_outliers_from_rng(&mut rng, 20_000, 0.99);
+        let token = CancellationToken::new();
+        let canceller = {
+            let token = token.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                token.cancel();
+            })
+        };
+        let estimate = Ransac::new(0.01, Pcg64::seed_from_u64(3))
+            .max_iterations(usize::MAX)
+            .budget(Budget::new().cancellation(token))
+            .estimate_slice(&HomographyEstimator::default(), &matches)
+            .unwrap();
+        canceller.join().unwrap();
+        assert!(estimate.terminated_early);
+    }
+
+    #[test]
+    fn observer_sees_every_hypothesis() {
+        use super::Point2;
+        use crate::{HomographyMatrix, RecordingObserver};
+        use cv_core::FeatureMatch;
+        use sample_consensus::Estimator;
+
+        /// Produces no model for the samples starting in the left half of the image
+        struct LeftFails;
+
+        impl Estimator<FeatureMatch<Point2>> for LeftFails {
+            type Model = HomographyMatrix;
+            type ModelIter = Option<HomographyMatrix>;
+            const MIN_SAMPLES: usize = 4;
+
+            fn estimate<I>(&self, data: I) -> Self::ModelIter
+            where
+                I: Iterator<Item = FeatureMatch<Point2>> + Clone,
+            {
+                let first = data.clone().next()?;
+                if first.0.x < 50.0 {
+                    return None;
+                }
+                HomographyEstimator::default().estimate(data)
+            }
+        }
+
+        let mut rng = Pcg64::seed_from_u64(5);
+        let TestData { matches, .. } = TestData::with_outliers_from_rng(&mut rng, 200, 0.4);
+        let mut observer = RecordingObserver::new();
+        let estimate = Ransac::new(0.01, Pcg64::seed_from_u64(5))
+            .estimate_slice_observed(&LeftFails, &matches, &mut observer)
+            .unwrap();
+
+        // Every sample is reported once, either as a hypothesis or as a failed sample
+        let termination = observer.termination.unwrap();
+        assert!(!observer.failed_samples.is_empty());
+        let mut iterations = observer
+            .hypotheses
+            .iter()
+            .map(|h| h.iteration)
+            .chain(
+                observer
+                    .failed_samples
+                    .iter()
+                    .map(|(iteration, _)| *iteration),
+            )
+            .collect::<Vec<_>>();
+        iterations.sort_unstable();
+        assert_eq!(iterations, (0..termination.iterations).collect::<Vec<_>>());
+        assert!(observer
+            .failed_samples
+            .iter()
+            .all(|(_, sample)| matches[sample[0]].0.x < 50.0));
+
+        assert_eq!(termination.best_score, Some(estimate.inliers.len()));
+        let history = observer.best_score_history();
+        assert!(history.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(history.last(), Some(&estimate.inliers.len()));
+        let best = observer
+            .hypotheses
+            .iter()
+            .rev()
+            .find(|h| h.new_best)
+            .unwrap();
+        assert_eq!(best.model, estimate.model);
+        assert_eq!(best.sample.len(), 4);
+    }
+}
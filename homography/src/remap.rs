@@ -0,0 +1,534 @@
+use eyre::{eyre, Result};
+use image::{ImageBuffer, Pixel};
+use nalgebra::{Matrix3, Point2};
+use num_traits::Zero;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use crate::warp::{cubic_weights, project, Sampler};
+use crate::{BorderMode, HomographyMatrix, Interpolation};
+
+/// Number of fractional bits of the fixed-point coordinates, the same as OpenCV's `INTER_BITS`
+pub const REMAP_FRACTION_BITS: u32 = 5;
+const FRACTION_SCALE: f32 = (1 << REMAP_FRACTION_BITS) as f32;
+/// Number of fractional positions along each axis, OpenCV's `INTER_TAB_SIZE`
+const TABLE_SIZE: usize = 1 << REMAP_FRACTION_BITS;
+/// Precision of the integer interpolation weights, OpenCV's `INTER_REMAP_COEF_BITS`
+const WEIGHT_BITS: u32 = 15;
+
+/// Storage of the source coordinates of a [`RemapTable`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RemapPrecision {
+    /// 32 bit floats, 8 bytes per pixel
+    #[default]
+    Float,
+    /// 16 bit integer and 8 bit fractional parts with [`REMAP_FRACTION_BITS`] bits of precision,
+    /// 6 bytes per pixel. Source coordinates beyond ±32767 are treated as outside the image.
+    ///
+    /// The pixels are interpolated with integer weights looked up from the fractional parts, like
+    /// OpenCV's `remap` with fixed-point maps, which is faster than [`RemapPrecision::Float`].
+    Fixed,
+}
+
+/// Options of [`RemapTable::new`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RemapOptions {
+    pub precision: RemapPrecision,
+    /// If set, the homography is only evaluated at the corners of blocks of this size, and the
+    /// coordinates inside the blocks are interpolated linearly. Much faster to build, but only
+    /// exact for affine transforms.
+    pub block_size: Option<u32>,
+    /// If true, the homography maps the destination pixels to the source image,
+    /// otherwise it maps the source pixels to the destination.
+    pub inverse_map: bool,
+}
+
+/// Fixed-point source coordinate. `x == i16::MIN` marks a point that can't be projected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FixedCoordinate {
+    x: i16,
+    y: i16,
+    fx: u8,
+    fy: u8,
+}
+
+impl FixedCoordinate {
+    const INVALID: Self = Self {
+        x: i16::MIN,
+        y: i16::MIN,
+        fx: 0,
+        fy: 0,
+    };
+
+    fn new(p: Option<Point2<f32>>) -> Self {
+        let Some(p) = p else {
+            return Self::INVALID;
+        };
+        let (x, y) = (
+            (p.x * FRACTION_SCALE).round(),
+            (p.y * FRACTION_SCALE).round(),
+        );
+        let limit = i16::MAX as f32 * FRACTION_SCALE;
+        if x.abs() > limit || y.abs() > limit {
+            return Self::INVALID;
+        }
+        let (x, y) = (x as i32, y as i32);
+        let mask = (1 << REMAP_FRACTION_BITS) - 1;
+        Self {
+            x: (x >> REMAP_FRACTION_BITS) as i16,
+            y: (y >> REMAP_FRACTION_BITS) as i16,
+            fx: (x & mask) as u8,
+            fy: (y & mask) as u8,
+        }
+    }
+
+    fn point(&self) -> Option<Point2<f64>> {
+        (self.x != i16::MIN).then(|| {
+            Point2::new(
+                self.x as f64 + self.fx as f64 / FRACTION_SCALE as f64,
+                self.y as f64 + self.fy as f64 / FRACTION_SCALE as f64,
+            )
+        })
+    }
+}
+
+/// Integer weights of the pixels around every fractional position of a [`FixedCoordinate`], like
+/// OpenCV's `INTER_TAB_SIZE` tables. They are rounded so they sum to exactly `1 << WEIGHT_BITS`.
+struct WeightTable {
+    interpolation: Interpolation,
+    weights: Vec<i32>,
+}
+
+impl WeightTable {
+    fn new(interpolation: Interpolation) -> Self {
+        let weights_1d = |fraction: usize| {
+            let t = fraction as f64 / TABLE_SIZE as f64;
+            match interpolation {
+                Interpolation::Nearest => [1.0, 0.0, 0.0, 0.0],
+                Interpolation::Bilinear => [1.0 - t, t, 0.0, 0.0],
+                Interpolation::Bicubic => cubic_weights(t),
+            }
+        };
+        let taps = Self::taps(interpolation);
+        let one = 1 << WEIGHT_BITS;
+        let mut weights = Vec::with_capacity(TABLE_SIZE * TABLE_SIZE * taps * taps);
+        for fy in 0..TABLE_SIZE {
+            for fx in 0..TABLE_SIZE {
+                let (wx, wy) = (weights_1d(fx), weights_1d(fy));
+                let start = weights.len();
+                weights.extend(
+                    (0..taps * taps)
+                        .map(|ix| (wx[ix % taps] * wy[ix / taps] * one as f64).round() as i32),
+                );
+                // The rounding error goes to the largest weight
+                let cell = &mut weights[start..];
+                let error = one - cell.iter().sum::<i32>();
+                let largest = (0..cell.len()).max_by_key(|&ix| cell[ix]).unwrap_or(0);
+                cell[largest] += error;
+            }
+        }
+        Self {
+            interpolation,
+            weights,
+        }
+    }
+
+    /// Number of pixels along each axis
+    fn taps(interpolation: Interpolation) -> usize {
+        match interpolation {
+            Interpolation::Nearest => 1,
+            Interpolation::Bilinear => 2,
+            Interpolation::Bicubic => 4,
+        }
+    }
+
+    /// Position of the first pixel
+    fn origin(&self, c: &FixedCoordinate) -> (i64, i64) {
+        let (x, y) = (c.x as i64, c.y as i64);
+        match self.interpolation {
+            Interpolation::Nearest => {
+                let half = (TABLE_SIZE / 2) as u8;
+                (x + (c.fx >= half) as i64, y + (c.fy >= half) as i64)
+            }
+            Interpolation::Bilinear => (x, y),
+            Interpolation::Bicubic => (x - 1, y - 1),
+        }
+    }
+
+    fn weights(&self, c: &FixedCoordinate) -> &[i32] {
+        let taps = Self::taps(self.interpolation);
+        let len = taps * taps;
+        let start = (c.fy as usize * TABLE_SIZE + c.fx as usize) * len;
+        &self.weights[start..start + len]
+    }
+
+    /// Samples the source pixel of every pixel of a row.
+    fn remap_row<const TAPS: usize, P: Pixel + 'static>(
+        &self,
+        sampler: &Sampler<P>,
+        points: &[FixedCoordinate],
+        row: &mut [P::Subpixel],
+    ) {
+        let channels = P::CHANNEL_COUNT as usize;
+        for (c, pixel) in points.iter().zip(row.chunks_exact_mut(channels)) {
+            if c.x == i16::MIN {
+                sampler.sample(None, pixel);
+            } else {
+                let weights = self.weights(c);
+                sampler.sample_weighted::<TAPS>(self.origin(c), weights, WEIGHT_BITS, pixel);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Coordinates {
+    /// NaN marks a point that can't be projected
+    Float(Vec<Point2<f32>>),
+    Fixed(Vec<FixedCoordinate>),
+}
+
+/// Source image coordinates of every pixel of a warped image.
+/// *This is supported on **crate feature `image`** only.*
+///
+/// Computing the projection of every pixel is a large part of [`warp_perspective`](crate::warp_perspective).
+/// When many images are warped with the same homography, like the frames of a fixed camera,
+/// the coordinates can be computed once and reused with [`RemapTable::remap`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemapTable {
+    width: u32,
+    height: u32,
+    coordinates: Coordinates,
+}
+
+impl RemapTable {
+    /// Builds the table of a `width`×`height` output image.
+    /// Fails if the homography has to be inverted but it's singular.
+    pub fn new(
+        h: &HomographyMatrix,
+        width: u32,
+        height: u32,
+        options: RemapOptions,
+    ) -> Result<Self> {
+        let inverse = if options.inverse_map {
+            h.0
+        } else {
+            h.0.try_inverse()
+                .ok_or_else(|| eyre!("The homography is not invertible"))?
+        };
+        let points = match options.block_size {
+            Some(block_size) => block_interpolated(&inverse, width, height, block_size.max(1)),
+            None => (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .map(|(x, y)| project(&inverse, x as f64, y as f64).map(|p| p.cast::<f32>()))
+                .collect(),
+        };
+        let coordinates = match options.precision {
+            RemapPrecision::Float => Coordinates::Float(
+                points
+                    .into_iter()
+                    .map(|p| p.unwrap_or_else(|| Point2::new(f32::NAN, f32::NAN)))
+                    .collect(),
+            ),
+            RemapPrecision::Fixed => {
+                Coordinates::Fixed(points.into_iter().map(FixedCoordinate::new).collect())
+            }
+        };
+        Ok(Self {
+            width,
+            height,
+            coordinates,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Source coordinates of the output pixel at `x`, `y`.
+    /// `None` if it's outside of the table, or the homography maps it to infinity.
+    pub fn source(&self, x: u32, y: u32) -> Option<Point2<f64>> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        self.source_at(y as usize * self.width as usize + x as usize)
+    }
+
+    fn source_at(&self, ix: usize) -> Option<Point2<f64>> {
+        match &self.coordinates {
+            Coordinates::Float(points) => {
+                let p = points[ix];
+                (!p.x.is_nan()).then(|| p.cast::<f64>())
+            }
+            Coordinates::Fixed(points) => points[ix].point(),
+        }
+    }
+
+    /// Warps an image with the table.
+    pub fn remap<P>(
+        &self,
+        image: &ImageBuffer<P, Vec<P::Subpixel>>,
+        interpolation: Interpolation,
+        border: BorderMode<P>,
+    ) -> ImageBuffer<P, Vec<P::Subpixel>>
+    where
+        P: Pixel + Sync + 'static,
+        P::Subpixel: Send + Sync,
+    {
+        let mut output = ImageBuffer::from_pixel(self.width, self.height, zero_pixel());
+        self.remap_into(image, interpolation, border, &mut output)
+            .expect("the output has the size of the table");
+        output
+    }
+
+    /// Like [`Self::remap`], but writes into an existing image to avoid allocating a new one
+    /// for every frame. Fails if `output` doesn't have the size of the table.
+    pub fn remap_into<P>(
+        &self,
+        image: &ImageBuffer<P, Vec<P::Subpixel>>,
+        interpolation: Interpolation,
+        border: BorderMode<P>,
+        output: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    ) -> Result<()>
+    where
+        P: Pixel + Sync + 'static,
+        P::Subpixel: Send + Sync,
+    {
+        if output.dimensions() != (self.width, self.height) {
+            return Err(eyre!(
+                "The output is {:?}, but the table is {}x{}",
+                output.dimensions(),
+                self.width,
+                self.height
+            ));
+        }
+        let sampler = Sampler::new(image, interpolation, border);
+        let table = matches!(self.coordinates, Coordinates::Fixed(_))
+            .then(|| WeightTable::new(interpolation));
+        let channels = P::CHANNEL_COUNT as usize;
+        let width = self.width as usize;
+        let row_len = width * channels;
+        let remap_row = |(y, row): (usize, &mut [P::Subpixel])| {
+            let offset = y * width;
+            match (&self.coordinates, &table) {
+                (Coordinates::Fixed(points), Some(table)) => {
+                    let points = &points[offset..offset + width];
+                    match interpolation {
+                        Interpolation::Nearest => table.remap_row::<1, P>(&sampler, points, row),
+                        Interpolation::Bilinear => table.remap_row::<2, P>(&sampler, points, row),
+                        Interpolation::Bicubic => table.remap_row::<4, P>(&sampler, points, row),
+                    }
+                }
+                _ => {
+                    for (x, pixel) in row.chunks_exact_mut(channels).enumerate() {
+                        sampler.sample(self.source_at(offset + x), pixel);
+                    }
+                }
+            }
+        };
+        let buffer: &mut [P::Subpixel] = output;
+        if row_len > 0 {
+            #[cfg(feature = "rayon")]
+            buffer
+                .par_chunks_mut(row_len)
+                .enumerate()
+                .for_each(remap_row);
+            #[cfg(not(feature = "rayon"))]
+            buffer.chunks_mut(row_len).enumerate().for_each(remap_row);
+        }
+        Ok(())
+    }
+}
+
+/// Projects the corners of the blocks and interpolates the coordinates between them.
+fn block_interpolated(
+    h: &Matrix3<f64>,
+    width: u32,
+    height: u32,
+    block_size: u32,
+) -> Vec<Option<Point2<f32>>> {
+    let corners_x = width.div_ceil(block_size) as usize + 1;
+    let corners_y = height.div_ceil(block_size) as usize + 1;
+    let corners = (0..corners_y)
+        .flat_map(|j| (0..corners_x).map(move |i| (i, j)))
+        .map(|(i, j)| {
+            let (x, y) = (i as u32 * block_size, j as u32 * block_size);
+            project(h, x as f64, y as f64)
+        })
+        .collect::<Vec<_>>();
+    let corner = |i: usize, j: usize| corners[j * corners_x + i];
+
+    (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let (i, j) = ((x / block_size) as usize, (y / block_size) as usize);
+            let fx = (x % block_size) as f64 / block_size as f64;
+            let fy = (y % block_size) as f64 / block_size as f64;
+            let p00 = corner(i, j)?;
+            let p10 = corner(i + 1, j)?;
+            let p01 = corner(i, j + 1)?;
+            let p11 = corner(i + 1, j + 1)?;
+            let top = p00.coords.lerp(&p10.coords, fx);
+            let bottom = p01.coords.lerp(&p11.coords, fx);
+            Some(Point2::from(top.lerp(&bottom, fy)).cast::<f32>())
+        })
+        .collect()
+}
+
+fn zero_pixel<P: Pixel>() -> P {
+    let zero = vec![P::Subpixel::zero(); P::CHANNEL_COUNT as usize];
+    *P::from_slice(&zero)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::warp::Sampler;
+    use crate::{
+        warp_perspective, BorderMode, HomographyMatrix, Interpolation, RemapOptions,
+        RemapPrecision, RemapTable, WarpOptions,
+    };
+    use image::{GrayImage, Luma};
+    use nalgebra::Matrix3;
+
+    fn test_image() -> GrayImage {
+        GrayImage::from_fn(96, 64, |x, y| {
+            let (x, y) = (x as f64, y as f64);
+            Luma([(127.0 + 60.0 * (x / 7.0).sin() + 60.0 * (y / 5.0).cos()) as u8])
+        })
+    }
+
+    fn max_difference(a: &GrayImage, b: &GrayImage) -> u8 {
+        a.pixels()
+            .zip(b.pixels())
+            .map(|(a, b)| a.0[0].abs_diff(b.0[0]))
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn same_as_warp_perspective() {
+        #[rustfmt::skip]
+        let h = HomographyMatrix(Matrix3::new(
+            0.9, -0.2, 12.0,
+            0.15, 1.1, -3.0,
+            0.0005, 0.0008, 1.0,
+        ));
+        let image = test_image();
+        let border = BorderMode::Replicate;
+        let options = WarpOptions {
+            interpolation: Interpolation::Bilinear,
+            border,
+            inverse_map: false,
+        };
+        let warped = warp_perspective(&image, &h, 80, 70, options).unwrap();
+
+        for (remap_options, tolerance) in [
+            (RemapOptions::default(), 1),
+            (
+                RemapOptions {
+                    precision: RemapPrecision::Fixed,
+                    ..Default::default()
+                },
+                4,
+            ),
+            (
+                RemapOptions {
+                    block_size: Some(8),
+                    ..Default::default()
+                },
+                4,
+            ),
+        ] {
+            let table = RemapTable::new(&h, 80, 70, remap_options).unwrap();
+            let remapped = table.remap(&image, Interpolation::Bilinear, border);
+            let difference = max_difference(&warped, &remapped);
+            assert!(
+                difference <= tolerance,
+                "{:?}: {}",
+                remap_options,
+                difference
+            );
+        }
+    }
+
+    #[test]
+    fn fixed_point_interpolation() {
+        #[rustfmt::skip]
+        let h = HomographyMatrix(Matrix3::new(
+            0.9, -0.2, 12.0,
+            0.15, 1.1, -3.0,
+            0.0005, 0.0008, 1.0,
+        ));
+        let image = test_image();
+        let fixed = RemapTable::new(
+            &h,
+            80,
+            70,
+            RemapOptions {
+                precision: RemapPrecision::Fixed,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // Ties of negative coordinates like -0.5 are rounded down with floats and up with the
+        // table, which lands on the same pixel with a replicated border
+        for (interpolation, border) in [
+            (Interpolation::Nearest, BorderMode::Replicate),
+            (Interpolation::Bilinear, BorderMode::Reflect),
+            (Interpolation::Bilinear, BorderMode::Constant(Luma([7]))),
+            (Interpolation::Bicubic, BorderMode::Reflect),
+            (Interpolation::Bicubic, BorderMode::Constant(Luma([7]))),
+        ] {
+            // The float interpolation at the same coordinates
+            let sampler = Sampler::new(&image, interpolation, border);
+            let expected = GrayImage::from_fn(80, 70, |x, y| {
+                let mut value = [0];
+                sampler.sample(fixed.source(x, y), &mut value);
+                Luma(value)
+            });
+            let remapped = fixed.remap(&image, interpolation, border);
+            let difference = max_difference(&expected, &remapped);
+            assert!(difference <= 1, "{:?}: {}", interpolation, difference);
+        }
+
+        // The weights sum to one, so a constant image stays constant
+        let constant = GrayImage::from_pixel(96, 64, Luma([201]));
+        for interpolation in [Interpolation::Bilinear, Interpolation::Bicubic] {
+            let remapped = fixed.remap(&constant, interpolation, BorderMode::Replicate);
+            assert!(remapped.pixels().all(|p| p.0[0] == 201));
+        }
+    }
+
+    #[test]
+    fn reuses_the_output() {
+        let h = HomographyMatrix(Matrix3::identity());
+        let image = test_image();
+        let table = RemapTable::new(&h, 96, 64, RemapOptions::default()).unwrap();
+        let mut output = GrayImage::new(96, 64);
+        table
+            .remap_into(
+                &image,
+                Interpolation::Nearest,
+                BorderMode::Replicate,
+                &mut output,
+            )
+            .unwrap();
+        assert_eq!(output, image);
+        assert_eq!(table.source(95, 63).unwrap().x, 95.0);
+        assert!(table.source(96, 0).is_none());
+
+        let mut small = GrayImage::new(10, 10);
+        assert!(table
+            .remap_into(
+                &image,
+                Interpolation::Nearest,
+                BorderMode::Replicate,
+                &mut small
+            )
+            .is_err());
+    }
+}
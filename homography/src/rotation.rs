@@ -0,0 +1,273 @@
+use cv_core::FeatureMatch;
+use eyre::{eyre, Result};
+use nalgebra::{Matrix3, Point2, Rotation3, Vector3};
+use sample_consensus::Estimator;
+
+use crate::HomographyMatrix;
+
+/// Focal lengths of the two cameras of a rotational homography, see [`focals_from_homography`].
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FocalLengths {
+    /// Focal length of the camera the homography maps from
+    pub source: Option<f64>,
+    /// Focal length of the camera the homography maps to
+    pub destination: Option<f64>,
+}
+
+impl FocalLengths {
+    /// Geometric mean of the two focal lengths, or the one that could be estimated.
+    pub fn combined(&self) -> Option<f64> {
+        match (self.source, self.destination) {
+            (Some(f0), Some(f1)) => Some((f0 * f1).sqrt()),
+            (f0, f1) => f0.or(f1),
+        }
+    }
+}
+
+/// Intrinsic matrix of a camera with square pixels.
+pub fn camera_matrix(focal: f64, principal_point: Point2<f64>) -> Matrix3<f64> {
+    Matrix3::new(
+        focal,
+        0.0,
+        principal_point.x,
+        0.0,
+        focal,
+        principal_point.y,
+        0.0,
+        0.0,
+        1.0,
+    )
+}
+
+/// Estimates the focal lengths of two cameras from the homography between them, assuming the
+/// cameras only rotated, have square pixels and known principal points.
+///
+/// Same as OpenCV's `focalsFromHomography`, but `h` maps the pixels of the source image and the
+/// principal points are not required to be at the origin. Both focal lengths follow from the
+/// orthogonality of the rotation: the source one from its rows, the destination one from its
+/// columns. One of them is `None` if its equations have no positive solution, which happens when
+/// the rotation is around a single axis of the image.
+pub fn focals_from_homography(
+    h: &HomographyMatrix,
+    principal_point1: Point2<f64>,
+    principal_point2: Point2<f64>,
+) -> FocalLengths {
+    let h = camera_matrix(1.0, principal_point2)
+        .try_inverse()
+        .expect("translation is invertible")
+        * h.0
+        * camera_matrix(1.0, principal_point1);
+    let h = h.transpose();
+    let h = h.as_slice();
+
+    let solve = |d1: f64, d2: f64, v1: f64, v2: f64| {
+        let (v1, v2) = if v1 < v2 { (v2, v1) } else { (v1, v2) };
+        if v1 > 0.0 && v2 > 0.0 {
+            Some(if d1.abs() > d2.abs() { v1 } else { v2 }.sqrt())
+        } else if v1 > 0.0 {
+            Some(v1.sqrt())
+        } else {
+            None
+        }
+    };
+
+    let d1 = h[6] * h[7];
+    let d2 = (h[7] - h[6]) * (h[7] + h[6]);
+    let destination = solve(
+        d1,
+        d2,
+        -(h[0] * h[1] + h[3] * h[4]) / d1,
+        (h[0] * h[0] + h[3] * h[3] - h[1] * h[1] - h[4] * h[4]) / d2,
+    );
+    let d1 = h[0] * h[3] + h[1] * h[4];
+    let d2 = h[0] * h[0] + h[1] * h[1] - h[3] * h[3] - h[4] * h[4];
+    let source = solve(d1, d2, -h[2] * h[5] / d1, (h[5] * h[5] - h[2] * h[2]) / d2);
+    FocalLengths {
+        source: source.filter(|f| f.is_finite()),
+        destination: destination.filter(|f| f.is_finite()),
+    }
+}
+
+/// Recovers the rotation `R = K₂⁻¹ H K₁` between two cameras with intrinsics `k1` and `k2` from
+/// the homography `h` that maps the pixels of the first camera to the second one.
+///
+/// The result rotates the rays of the first camera into the frame of the second one. The scale of
+/// `h` doesn't matter. As `h` is estimated with noise, the product is only approximately a rotation,
+/// so the closest rotation is returned. Fails if a matrix is singular.
+pub fn rotation_from_homography(
+    h: &HomographyMatrix,
+    k1: &Matrix3<f64>,
+    k2: &Matrix3<f64>,
+) -> Result<Rotation3<f64>> {
+    let k2_inverse = k2
+        .try_inverse()
+        .ok_or_else(|| eyre!("The camera matrix is not invertible"))?;
+    let m = k2_inverse * h.0 * k1;
+    let det = m.determinant();
+    if det.abs() < f64::EPSILON {
+        return Err(eyre!("The homography is singular"));
+    }
+    // Scales to a unit determinant, which also fixes the sign
+    let m = m / det.cbrt();
+    Ok(Rotation3::from_matrix(&m))
+}
+
+/// The homography `K₂ R K₁⁻¹` of a camera rotation, the inverse of [`rotation_from_homography`].
+pub fn homography_from_rotation(
+    rotation: &Rotation3<f64>,
+    k1: &Matrix3<f64>,
+    k2: &Matrix3<f64>,
+) -> Result<HomographyMatrix> {
+    let k1_inverse = k1
+        .try_inverse()
+        .ok_or_else(|| eyre!("The camera matrix is not invertible"))?;
+    Ok(HomographyMatrix(k2 * rotation.matrix() * k1_inverse))
+}
+
+/// Minimal solver of the rotation that maps the rays `a1` and `a2` to `b1` and `b2`.
+///
+/// The rays don't have to be normalized. The angle between the two pairs is usually a bit
+/// different because of noise, the error is shared equally between them. `None` if the rays of
+/// a pair are parallel.
+pub fn rotation_from_two_rays(
+    (a1, b1): (Vector3<f64>, Vector3<f64>),
+    (a2, b2): (Vector3<f64>, Vector3<f64>),
+) -> Option<Rotation3<f64>> {
+    // Orthonormal frame of the bisector and the difference of the two rays
+    let frame = |v1: Vector3<f64>, v2: Vector3<f64>| {
+        let (v1, v2) = (
+            v1.try_normalize(f64::EPSILON)?,
+            v2.try_normalize(f64::EPSILON)?,
+        );
+        let x = (v1 + v2).try_normalize(f64::EPSILON)?;
+        let y = (v1 - v2).try_normalize(f64::EPSILON)?;
+        Some(Matrix3::from_columns(&[x, y, x.cross(&y)]))
+    };
+    let (a, b) = (frame(a1, a2)?, frame(b1, b2)?);
+    Some(Rotation3::from_matrix_unchecked(b * a.transpose()))
+}
+
+/// Estimates the homography of a purely rotating camera with known intrinsics from two matches.
+///
+/// Implements [`cv::Estimator`](https://docs.rs/cv/0.6.0/cv/trait.Estimator.html), so it can be
+/// used with [`Ransac`](crate::Ransac) like [`HomographyEstimator`](crate::HomographyEstimator)
+/// for the rotation-only case. The models are the homographies `K₂ R K₁⁻¹` with the pixel
+/// reprojection error as residual; recover the rotation with [`rotation_from_homography`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RotationEstimator {
+    k1: Matrix3<f64>,
+    k2: Matrix3<f64>,
+    k1_inverse: Matrix3<f64>,
+    k2_inverse: Matrix3<f64>,
+}
+
+impl RotationEstimator {
+    /// Fails if a camera matrix is not invertible.
+    pub fn new(k1: Matrix3<f64>, k2: Matrix3<f64>) -> Result<Self> {
+        let inverse = |k: &Matrix3<f64>| {
+            k.try_inverse()
+                .ok_or_else(|| eyre!("The camera matrix is not invertible"))
+        };
+        Ok(Self {
+            k1_inverse: inverse(&k1)?,
+            k2_inverse: inverse(&k2)?,
+            k1,
+            k2,
+        })
+    }
+
+    /// The rotation that maps the rays of the first points of two matches to the second ones.
+    pub fn rotation(
+        &self,
+        m1: &FeatureMatch<Point2<f64>>,
+        m2: &FeatureMatch<Point2<f64>>,
+    ) -> Option<Rotation3<f64>> {
+        let rays = |FeatureMatch(a, b): &FeatureMatch<Point2<f64>>| {
+            (
+                self.k1_inverse * a.to_homogeneous(),
+                self.k2_inverse * b.to_homogeneous(),
+            )
+        };
+        rotation_from_two_rays(rays(m1), rays(m2))
+    }
+}
+
+impl Estimator<FeatureMatch<Point2<f64>>> for RotationEstimator {
+    type Model = HomographyMatrix;
+    type ModelIter = Option<HomographyMatrix>;
+    const MIN_SAMPLES: usize = 2;
+
+    fn estimate<I>(&self, mut data: I) -> Self::ModelIter
+    where
+        I: Iterator<Item = FeatureMatch<Point2<f64>>> + Clone,
+    {
+        let (m1, m2) = (data.next()?, data.next()?);
+        let rotation = self.rotation(&m1, &m2)?;
+        Some(HomographyMatrix(
+            self.k2 * rotation.matrix() * self.k1_inverse,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        camera_matrix, focals_from_homography, homography_from_rotation, rotation_from_homography,
+        RotationEstimator,
+    };
+    use approx::assert_relative_eq;
+    use cv_core::FeatureMatch;
+    use nalgebra::{Point2, Rotation3, Vector3};
+    use sample_consensus::{Estimator, Model};
+
+    fn rotation() -> Rotation3<f64> {
+        Rotation3::from_axis_angle(&Vector3::y_axis(), 0.3)
+            * Rotation3::from_axis_angle(&Vector3::x_axis(), -0.1)
+            * Rotation3::from_axis_angle(&Vector3::z_axis(), 0.05)
+    }
+
+    #[test]
+    fn focals_and_rotation_from_homography() {
+        let k1 = camera_matrix(700.0, Point2::new(320.0, 240.0));
+        let k2 = camera_matrix(900.0, Point2::new(300.0, 250.0));
+        let h = homography_from_rotation(&rotation(), &k1, &k2).unwrap();
+        let h = crate::HomographyMatrix(h.0 * 3.5);
+
+        let focals =
+            focals_from_homography(&h, Point2::new(320.0, 240.0), Point2::new(300.0, 250.0));
+        assert_relative_eq!(focals.source.unwrap(), 700.0, epsilon = 1e-6);
+        assert_relative_eq!(focals.destination.unwrap(), 900.0, epsilon = 1e-6);
+        assert_relative_eq!(
+            focals.combined().unwrap(),
+            (700.0f64 * 900.0).sqrt(),
+            epsilon = 1e-6
+        );
+
+        let recovered = rotation_from_homography(&h, &k1, &k2).unwrap();
+        assert_relative_eq!(recovered, rotation(), epsilon = 1e-9);
+    }
+
+    #[test]
+    fn two_point_solver() {
+        let k = camera_matrix(800.0, Point2::new(320.0, 240.0));
+        let h = homography_from_rotation(&rotation(), &k, &k).unwrap();
+        let matches = [(10.0, 20.0), (600.0, 400.0), (300.0, 100.0)]
+            .iter()
+            .map(|&(x, y)| {
+                let a = Point2::new(x, y);
+                FeatureMatch(
+                    a,
+                    Point2::from_homogeneous(h.0 * a.to_homogeneous()).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let estimator = RotationEstimator::new(k, k).unwrap();
+        let model = estimator.estimate(matches.iter().copied()).unwrap();
+        assert_relative_eq!(model.0 / model.0[(2, 2)], h.0 / h.0[(2, 2)], epsilon = 1e-9);
+        // The match that wasn't in the sample fits as well
+        assert!(model.residual(&matches[2]) < 1e-12);
+        // Parallel rays don't define a rotation
+        assert!(estimator.rotation(&matches[0], &matches[0]).is_none());
+    }
+}
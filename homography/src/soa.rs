@@ -0,0 +1,547 @@
+use cv_core::FeatureMatch;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use sample_consensus::{Estimator, Model};
+#[cfg(feature = "simd")]
+use wide::{f64x4, CmpEq, CmpLt};
+
+use crate::homography::UNPROJECTABLE_RESIDUAL;
+use crate::HomographyMatrix;
+
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+
+type Point2 = nalgebra::Point2<f64>;
+
+/// Number of data scored by one task when the `rayon` feature is enabled
+#[cfg(feature = "rayon")]
+const SCORING_CHUNK_SIZE: usize = 4096;
+
+/// Number of matches whose residuals a [`SoaModel`] computes at once
+const RESIDUAL_BLOCK: usize = 64;
+
+/// Structure-of-arrays storage of point matches for batched residual evaluation.
+///
+/// The residuals are the same as the ones of [`HomographyMatrix`]'s
+/// [`Model`](sample_consensus::Model) implementation, but they are computed for 4 matches at once
+/// with the `simd` feature. Without the feature a scalar loop is used.
+///
+/// The built-in `Ransac` (crate feature `ransac`) scores homographies with it when it runs on a
+/// [`FeatureMatchSoa`] instead of a slice, see [`BatchResidual`]. Sample consensus algorithms of
+/// other crates use it through a [`SoaEstimator`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeatureMatchSoa {
+    x1: Vec<f64>,
+    y1: Vec<f64>,
+    x2: Vec<f64>,
+    y2: Vec<f64>,
+}
+
+impl FeatureMatchSoa {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            x1: Vec::with_capacity(capacity),
+            y1: Vec::with_capacity(capacity),
+            x2: Vec::with_capacity(capacity),
+            y2: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, FeatureMatch(a, b): FeatureMatch<Point2>) {
+        self.x1.push(a.x);
+        self.y1.push(a.y);
+        self.x2.push(b.x);
+        self.y2.push(b.y);
+    }
+
+    pub fn get(&self, ix: usize) -> Option<FeatureMatch<Point2>> {
+        (ix < self.len()).then(|| {
+            FeatureMatch(
+                Point2::new(self.x1[ix], self.y1[ix]),
+                Point2::new(self.x2[ix], self.y2[ix]),
+            )
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.x1.clear();
+        self.y1.clear();
+        self.x2.clear();
+        self.y2.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.x1.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.x1.is_empty()
+    }
+
+    /// Writes the residual of every match into `residuals`, replacing its previous content.
+    pub fn residuals(&self, h: &HomographyMatrix, residuals: &mut Vec<f64>) {
+        residuals.clear();
+        residuals.resize(self.len(), 0.0);
+        self.residuals_into(h, 0, residuals);
+    }
+
+    /// Number of matches with a residual below `threshold`.
+    pub fn count_inliers(&self, h: &HomographyMatrix, threshold: f64) -> usize {
+        #[cfg(feature = "simd")]
+        {
+            let full = self.len() / 4 * 4;
+            let h_x4 = HomographyX4::from(h);
+            let threshold_x4 = f64x4::splat(threshold);
+            let mut count = 0;
+            for ix in (0..full).step_by(4) {
+                let mask = self
+                    .residuals_x4(&h_x4, ix)
+                    .cmp_lt(threshold_x4)
+                    .move_mask();
+                count += mask.count_ones() as usize;
+            }
+            count
+                + (full..self.len())
+                    .filter(|&ix| self.residual(h, ix) < threshold)
+                    .count()
+        }
+        #[cfg(not(feature = "simd"))]
+        (0..self.len())
+            .filter(|&ix| self.residual(h, ix) < threshold)
+            .count()
+    }
+
+    /// Writes the indices of the matches with a residual below `threshold` into `inliers`,
+    /// replacing its previous content.
+    pub fn inliers(&self, h: &HomographyMatrix, threshold: f64, inliers: &mut Vec<usize>) {
+        inliers.clear();
+        #[cfg(feature = "simd")]
+        {
+            let full = self.len() / 4 * 4;
+            let h_x4 = HomographyX4::from(h);
+            let threshold_x4 = f64x4::splat(threshold);
+            for ix in (0..full).step_by(4) {
+                let mask = self
+                    .residuals_x4(&h_x4, ix)
+                    .cmp_lt(threshold_x4)
+                    .move_mask();
+                inliers.extend(
+                    (0..4)
+                        .filter(|lane| mask & (1 << lane) != 0)
+                        .map(|lane| ix + lane),
+                );
+            }
+            inliers.extend((full..self.len()).filter(|&ix| self.residual(h, ix) < threshold));
+        }
+        #[cfg(not(feature = "simd"))]
+        inliers.extend((0..self.len()).filter(|&ix| self.residual(h, ix) < threshold));
+    }
+
+    /// Writes the residuals of the matches from `start` on into `out`.
+    fn residuals_into(&self, h: &HomographyMatrix, start: usize, out: &mut [f64]) {
+        #[cfg(feature = "simd")]
+        let done = {
+            let full = out.len() / 4 * 4;
+            let h_x4 = HomographyX4::from(h);
+            for ix in (0..full).step_by(4) {
+                out[ix..ix + 4].copy_from_slice(&self.residuals_x4(&h_x4, start + ix).to_array());
+            }
+            full
+        };
+        #[cfg(not(feature = "simd"))]
+        let done = 0;
+        for (ix, residual) in out.iter_mut().enumerate().skip(done) {
+            *residual = self.residual(h, start + ix);
+        }
+    }
+
+    fn residual(&self, h: &HomographyMatrix, ix: usize) -> f64 {
+        let h = &h.0;
+        let (x, y) = (self.x1[ix], self.y1[ix]);
+        let w = h[(2, 0)] * x + h[(2, 1)] * y + h[(2, 2)];
+        if w == 0.0 {
+            return UNPROJECTABLE_RESIDUAL;
+        }
+        let dx = (h[(0, 0)] * x + h[(0, 1)] * y + h[(0, 2)]) / w - self.x2[ix];
+        let dy = (h[(1, 0)] * x + h[(1, 1)] * y + h[(1, 2)]) / w - self.y2[ix];
+        dx * dx + dy * dy
+    }
+
+    /// Residuals of the 4 matches starting at `ix`.
+    #[cfg(feature = "simd")]
+    fn residuals_x4(&self, h: &HomographyX4, ix: usize) -> f64x4 {
+        let load = |v: &[f64]| f64x4::new(v[ix..ix + 4].try_into().unwrap());
+        let (x, y) = (load(&self.x1), load(&self.y1));
+        let w = h.0[6] * x + h.0[7] * y + h.0[8];
+        let inv_w = f64x4::splat(1.0) / w;
+        let dx = (h.0[0] * x + h.0[1] * y + h.0[2]) * inv_w - load(&self.x2);
+        let dy = (h.0[3] * x + h.0[4] * y + h.0[5]) * inv_w - load(&self.y2);
+        let residuals = dx * dx + dy * dy;
+        w.cmp_eq(f64x4::splat(0.0))
+            .blend(f64x4::splat(UNPROJECTABLE_RESIDUAL), residuals)
+    }
+}
+
+/// Row-major elements of a homography, each broadcasted to all lanes.
+#[cfg(feature = "simd")]
+struct HomographyX4([f64x4; 9]);
+
+#[cfg(feature = "simd")]
+impl From<&HomographyMatrix> for HomographyX4 {
+    fn from(h: &HomographyMatrix) -> Self {
+        Self(std::array::from_fn(|i| f64x4::splat(h.0[(i / 3, i % 3)])))
+    }
+}
+
+impl FromIterator<FeatureMatch<Point2>> for FeatureMatchSoa {
+    fn from_iter<T: IntoIterator<Item = FeatureMatch<Point2>>>(iter: T) -> Self {
+        let mut soa = Self::new();
+        soa.extend(iter);
+        soa
+    }
+}
+
+impl Extend<FeatureMatch<Point2>> for FeatureMatchSoa {
+    fn extend<T: IntoIterator<Item = FeatureMatch<Point2>>>(&mut self, iter: T) {
+        for feature_match in iter {
+            self.push(feature_match);
+        }
+    }
+}
+
+impl From<&[FeatureMatch<Point2>]> for FeatureMatchSoa {
+    fn from(matches: &[FeatureMatch<Point2>]) -> Self {
+        let mut soa = Self::with_capacity(matches.len());
+        soa.extend(matches.iter().copied());
+        soa
+    }
+}
+
+/// A data set that sample consensus draws its samples from.
+pub trait ConsensusData: Sync {
+    type Item: Clone;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The datum at `ix`. Panics if `ix` is out of bounds.
+    fn item(&self, ix: usize) -> Self::Item;
+}
+
+impl<T: Clone + Sync> ConsensusData for [T] {
+    type Item = T;
+
+    fn len(&self) -> usize {
+        <[T]>::len(self)
+    }
+
+    fn item(&self, ix: usize) -> T {
+        self[ix].clone()
+    }
+}
+
+impl ConsensusData for FeatureMatchSoa {
+    type Item = FeatureMatch<Point2>;
+
+    fn len(&self) -> usize {
+        FeatureMatchSoa::len(self)
+    }
+
+    fn item(&self, ix: usize) -> FeatureMatch<Point2> {
+        self.get(ix).expect("index out of the matches")
+    }
+}
+
+/// Models that score a whole [`ConsensusData`] set at once.
+///
+/// Every [`Model`] scores slices of its data one datum at a time, in parallel with the `rayon`
+/// feature. [`HomographyMatrix`] also scores a [`FeatureMatchSoa`] with its batched routines.
+pub trait BatchResidual<D: ?Sized> {
+    /// Number of data with a residual below `threshold`.
+    fn count_inliers(&self, data: &D, threshold: f64) -> usize;
+
+    /// Writes the indices of the data with a residual below `threshold` into `inliers`, replacing
+    /// its previous content.
+    fn inliers(&self, data: &D, threshold: f64, inliers: &mut Vec<usize>);
+}
+
+impl<M, T> BatchResidual<[T]> for M
+where
+    M: Model<T> + Sync,
+    T: Sync,
+{
+    fn count_inliers(&self, data: &[T], threshold: f64) -> usize {
+        #[cfg(feature = "rayon")]
+        return data
+            .par_chunks(SCORING_CHUNK_SIZE)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .filter(|d| self.residual(d) < threshold)
+                    .count()
+            })
+            .sum();
+        #[cfg(not(feature = "rayon"))]
+        return data.iter().filter(|d| self.residual(d) < threshold).count();
+    }
+
+    fn inliers(&self, data: &[T], threshold: f64, inliers: &mut Vec<usize>) {
+        inliers.clear();
+        inliers.extend((0..data.len()).filter(|&ix| self.residual(&data[ix]) < threshold));
+    }
+}
+
+impl BatchResidual<FeatureMatchSoa> for HomographyMatrix {
+    fn count_inliers(&self, data: &FeatureMatchSoa, threshold: f64) -> usize {
+        data.count_inliers(self, threshold)
+    }
+
+    fn inliers(&self, data: &FeatureMatchSoa, threshold: f64, inliers: &mut Vec<usize>) {
+        data.inliers(self, threshold, inliers)
+    }
+}
+
+/// Wraps a homography estimator so sample consensus algorithms of other crates, like ARRSAC, score
+/// the hypotheses with the batched routines of a [`FeatureMatchSoa`].
+///
+/// The consensus runs on the [`indices`](Self::indices) of the matches instead of the matches
+/// themselves, so its inliers are indices of the matches as usual. The residuals of a hypothesis
+/// are computed for a block of matches the first time one of them is needed, so hypotheses that
+/// are rejected after a few blocks, as in ARRSAC, don't pay for all the matches. The buffers of
+/// the residuals are reused by the later hypotheses once a hypothesis is dropped.
+pub struct SoaEstimator<'a, E> {
+    estimator: &'a E,
+    matches: &'a FeatureMatchSoa,
+    caches: CachePool,
+}
+
+impl<'a, E> SoaEstimator<'a, E> {
+    pub fn new(estimator: &'a E, matches: &'a FeatureMatchSoa) -> Self {
+        Self {
+            estimator,
+            matches,
+            caches: CachePool::default(),
+        }
+    }
+
+    /// The data to run the consensus on
+    pub fn indices(&self) -> std::ops::Range<usize> {
+        0..self.matches.len()
+    }
+}
+
+impl<'a, E> Estimator<usize> for SoaEstimator<'a, E>
+where
+    E: Estimator<FeatureMatch<Point2>, Model = HomographyMatrix>,
+{
+    type Model = SoaModel<'a>;
+    type ModelIter = SoaModels<'a, <E::ModelIter as IntoIterator>::IntoIter>;
+    const MIN_SAMPLES: usize = E::MIN_SAMPLES;
+
+    fn estimate<I>(&self, data: I) -> Self::ModelIter
+    where
+        I: Iterator<Item = usize> + Clone,
+    {
+        let matches = self.matches;
+        SoaModels {
+            models: self
+                .estimator
+                .estimate(data.map(move |ix| matches.item(ix)))
+                .into_iter(),
+            matches,
+            caches: self.caches.clone(),
+        }
+    }
+}
+
+/// Residuals of the blocks of matches computed so far for a [`SoaModel`]
+#[derive(Debug, Clone, Default)]
+struct ResidualCache {
+    residuals: Vec<f64>,
+    /// One bit per block of [`RESIDUAL_BLOCK`] matches
+    computed: Vec<u64>,
+}
+
+/// Caches of the dropped models of a [`SoaEstimator`]
+type CachePool = Arc<Mutex<Vec<ResidualCache>>>;
+
+/// Models of a [`SoaEstimator`].
+pub struct SoaModels<'a, I> {
+    models: I,
+    matches: &'a FeatureMatchSoa,
+    caches: CachePool,
+}
+
+impl<'a, I> Iterator for SoaModels<'a, I>
+where
+    I: Iterator<Item = HomographyMatrix>,
+{
+    type Item = SoaModel<'a>;
+
+    fn next(&mut self) -> Option<SoaModel<'a>> {
+        let homography = self.models.next()?;
+        let mut cache = self
+            .caches
+            .lock()
+            .ok()
+            .and_then(|mut caches| caches.pop())
+            .unwrap_or_default();
+        let blocks = self.matches.len().div_ceil(RESIDUAL_BLOCK);
+        cache.residuals.resize(self.matches.len(), 0.0);
+        cache.computed.clear();
+        cache.computed.resize(blocks.div_ceil(64), 0);
+        Some(SoaModel {
+            homography,
+            matches: self.matches,
+            cache: RefCell::new(cache),
+            caches: self.caches.clone(),
+        })
+    }
+}
+
+/// A homography hypothesis of a [`SoaEstimator`] with the residuals computed so far.
+#[derive(Debug, Clone)]
+pub struct SoaModel<'a> {
+    homography: HomographyMatrix,
+    matches: &'a FeatureMatchSoa,
+    cache: RefCell<ResidualCache>,
+    caches: CachePool,
+}
+
+impl SoaModel<'_> {
+    pub fn homography(&self) -> &HomographyMatrix {
+        &self.homography
+    }
+
+    pub fn into_homography(self) -> HomographyMatrix {
+        self.homography
+    }
+}
+
+impl Model<usize> for SoaModel<'_> {
+    /// The residual of the match at the index, as for [`HomographyMatrix`].
+    fn residual(&self, ix: &usize) -> f64 {
+        let mut cache = self.cache.borrow_mut();
+        let block = ix / RESIDUAL_BLOCK;
+        let (word, bit) = (block / 64, 1 << (block % 64));
+        if cache.computed[word] & bit == 0 {
+            let start = block * RESIDUAL_BLOCK;
+            let end = (start + RESIDUAL_BLOCK).min(self.matches.len());
+            self.matches
+                .residuals_into(&self.homography, start, &mut cache.residuals[start..end]);
+            cache.computed[word] |= bit;
+        }
+        cache.residuals[*ix]
+    }
+}
+
+impl Drop for SoaModel<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut caches) = self.caches.lock() {
+            caches.push(std::mem::take(self.cache.get_mut()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FeatureMatchSoa, HomographyMatrix};
+    use approx::assert_relative_eq;
+    use cv_core::FeatureMatch;
+    use nalgebra::{Matrix3, Point2};
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+    use sample_consensus::Model;
+    use test_utils::TestData;
+
+    #[test]
+    fn same_as_scalar_residuals() {
+        let mut rng = Pcg64::seed_from_u64(0);
+        for match_count in [0, 3, 4, 57] {
+            let TestData { matches, h } =
+                TestData::with_outliers_from_rng(&mut rng, match_count, 0.3);
+            let h = HomographyMatrix(h);
+            let soa = FeatureMatchSoa::from(&matches[..]);
+
+            let mut residuals = vec![];
+            soa.residuals(&h, &mut residuals);
+            for (residual, m) in residuals.iter().zip(&matches) {
+                assert_relative_eq!(
+                    *residual,
+                    h.residual(m),
+                    epsilon = 1e-9,
+                    max_relative = 1e-9
+                );
+            }
+
+            let mut inliers = vec![];
+            soa.inliers(&h, 0.01, &mut inliers);
+            let expected = (0..matches.len())
+                .filter(|&ix| h.residual(&matches[ix]) < 0.01)
+                .collect::<Vec<_>>();
+            assert_eq!(inliers, expected);
+            assert_eq!(soa.count_inliers(&h, 0.01), expected.len());
+        }
+    }
+
+    #[test]
+    fn points_at_infinity() {
+        #[rustfmt::skip]
+        let h = HomographyMatrix(Matrix3::new(
+            1.0, 0.0, 0.0,
+            0.0, 1.0, 0.0,
+            1.0, 0.0, 0.0,
+        ));
+        let matches = (0..5)
+            .map(|i| FeatureMatch(Point2::new(0.0, i as f64), Point2::new(0.0, 0.0)))
+            .collect::<Vec<_>>();
+        let soa = matches.iter().copied().collect::<FeatureMatchSoa>();
+        let mut residuals = vec![];
+        soa.residuals(&h, &mut residuals);
+        for (residual, m) in residuals.iter().zip(&matches) {
+            assert_eq!(*residual, h.residual(m));
+        }
+        assert_eq!(soa.count_inliers(&h, 1.0), 0);
+    }
+
+    #[test]
+    fn soa_model_scores_by_blocks() {
+        use crate::{BatchResidual, HomographyEstimator, SoaEstimator};
+        use sample_consensus::Estimator;
+
+        let mut rng = Pcg64::seed_from_u64(1);
+        let TestData { matches, h } = TestData::with_outliers_from_rng(&mut rng, 150, 0.3);
+        let soa = FeatureMatchSoa::from(&matches[..]);
+        let estimator = HomographyEstimator::default();
+        let estimator = SoaEstimator::new(&estimator, &soa);
+        // The second model reuses the residual buffer of the first one
+        for sample in [[0, 1, 2, 3], [140, 2, 77, 9]] {
+            let model = estimator.estimate(sample.into_iter()).next().unwrap();
+            for ix in estimator.indices().rev() {
+                assert_relative_eq!(
+                    model.residual(&ix),
+                    model.homography().residual(&matches[ix]),
+                    epsilon = 1e-9,
+                    max_relative = 1e-9
+                );
+            }
+        }
+        let h = HomographyMatrix(h);
+
+        let (mut from_slice, mut from_soa) = (vec![], vec![]);
+        BatchResidual::inliers(&h, &matches[..], 0.01, &mut from_slice);
+        BatchResidual::inliers(&h, &soa, 0.01, &mut from_soa);
+        assert_eq!(from_slice, from_soa);
+        assert_eq!(
+            BatchResidual::count_inliers(&h, &matches[..], 0.01),
+            BatchResidual::count_inliers(&h, &soa, 0.01)
+        );
+    }
+}
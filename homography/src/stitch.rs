@@ -0,0 +1,625 @@
+use eyre::{eyre, Result};
+#[cfg(feature = "akaze")]
+use image::DynamicImage;
+use image::{ImageBuffer, Luma, Rgb, RgbImage, Rgba, RgbaImage};
+use nalgebra::{Matrix3, Point2, Vector2};
+
+#[cfg(feature = "akaze")]
+use crate::{find_homography_from_images, ImagePairOptions};
+use crate::{warp_perspective, BorderMode, HomographyMatrix, Interpolation, WarpOptions};
+
+type RgbF32Image = ImageBuffer<Rgb<f32>, Vec<f32>>;
+type GrayF32Image = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// How the overlapping images are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Blending {
+    /// Weighted average with weights falling off linearly towards the edges of the images
+    Feather,
+    /// Burt-Adelson multi-band blending: every image contributes where it has the largest feather
+    /// weight, and the seams are smoothed over a wider area for the lower frequencies.
+    MultiBand {
+        /// Number of pyramid levels
+        bands: usize,
+    },
+}
+
+impl Default for Blending {
+    fn default() -> Self {
+        Self::MultiBand { bands: 5 }
+    }
+}
+
+/// Options of [`compose_panorama`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompositionOptions {
+    pub blending: Blending,
+    pub interpolation: Interpolation,
+    /// The composition fails if the canvas would have more pixels, which happens when an image
+    /// is projected almost to infinity.
+    pub max_pixels: u64,
+}
+
+impl Default for CompositionOptions {
+    fn default() -> Self {
+        Self {
+            blending: Blending::default(),
+            interpolation: Interpolation::Bilinear,
+            max_pixels: 100_000_000,
+        }
+    }
+}
+
+/// A stitched panorama.
+#[derive(Debug, Clone)]
+pub struct Panorama {
+    /// The composed image. Pixels not covered by any of the images are transparent.
+    pub image: RgbaImage,
+    /// Transforms the pixels of each input image to the panorama
+    pub transforms: Vec<HomographyMatrix>,
+}
+
+/// Chains homographies between consecutive images to a common reference image.
+///
+/// `pairwise[i]` maps the points of image `i` to image `i + 1`. The result maps the points
+/// of each image to image `reference`. Fails if a homography that has to be inverted is singular.
+pub fn chain_homographies(
+    pairwise: &[HomographyMatrix],
+    reference: usize,
+) -> Result<Vec<HomographyMatrix>> {
+    let count = pairwise.len() + 1;
+    if reference >= count {
+        return Err(eyre!(
+            "Reference image {} is out of the {} images",
+            reference,
+            count
+        ));
+    }
+    let mut transforms = vec![Matrix3::<f64>::identity(); count];
+    for i in (0..reference).rev() {
+        transforms[i] = transforms[i + 1] * pairwise[i].0;
+    }
+    for i in reference + 1..count {
+        let inverse = pairwise[i - 1]
+            .0
+            .try_inverse()
+            .ok_or_else(|| eyre!("Homography between image {} and {} is singular", i - 1, i))?;
+        transforms[i] = transforms[i - 1] * inverse;
+    }
+    Ok(transforms
+        .into_iter()
+        .map(|h| HomographyMatrix(h / h[(2, 2)]))
+        .collect())
+}
+
+/// Size and origin of the image containing all the transformed images.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+    /// Position of the origin of the reference frame on the canvas
+    pub offset: Vector2<f64>,
+}
+
+impl Canvas {
+    /// Bounding box of the images of the given sizes transformed with `transforms`.
+    ///
+    /// Fails if a corner of an image is projected to infinity or behind the camera,
+    /// or if the canvas would have more than `max_pixels` pixels.
+    pub fn new(
+        sizes: &[(u32, u32)],
+        transforms: &[HomographyMatrix],
+        max_pixels: u64,
+    ) -> Result<Self> {
+        let mut min = Point2::new(f64::INFINITY, f64::INFINITY);
+        let mut max = Point2::new(f64::NEG_INFINITY, f64::NEG_INFINITY);
+        for (ix, (&(width, height), h)) in sizes.iter().zip(transforms).enumerate() {
+            let (right, bottom) = (width as f64 - 1.0, height as f64 - 1.0);
+            for (x, y) in [(0.0, 0.0), (right, 0.0), (0.0, bottom), (right, bottom)] {
+                let p = h.0 * nalgebra::Vector3::new(x, y, 1.0);
+                if p.z <= f64::EPSILON {
+                    return Err(eyre!("Image {} is projected to infinity", ix));
+                }
+                let p = Point2::new(p.x / p.z, p.y / p.z);
+                min = min.inf(&p);
+                max = max.sup(&p);
+            }
+        }
+        if !min.x.is_finite() {
+            return Err(eyre!("There are no images"));
+        }
+        let (min, max) = (min.map(f64::floor), max.map(f64::ceil));
+        let (width, height) = (max.x - min.x + 1.0, max.y - min.y + 1.0);
+        if width * height > max_pixels as f64 {
+            return Err(eyre!(
+                "The canvas would be {}x{} pixels, more than the limit of {}",
+                width,
+                height,
+                max_pixels
+            ));
+        }
+        Ok(Self {
+            width: width as u32,
+            height: height as u32,
+            offset: -min.coords,
+        })
+    }
+
+    /// Maps the reference frame to the canvas.
+    pub fn matrix(&self) -> Matrix3<f64> {
+        Matrix3::new_translation(&self.offset)
+    }
+}
+
+/// Warps the images onto a common canvas and blends them.
+/// *This is supported on **crate feature `image`** only.*
+///
+/// `transforms` map the pixels of each image to a common reference frame,
+/// like the ones returned by [`chain_homographies`]. The images are warped and blended one at a
+/// time, so the memory use doesn't grow with their number. Multi-band blending warps the feather
+/// weights twice, once to place the seams and once to blend.
+pub fn compose_panorama(
+    images: &[RgbImage],
+    transforms: &[HomographyMatrix],
+    options: CompositionOptions,
+) -> Result<Panorama> {
+    if images.len() != transforms.len() {
+        return Err(eyre!(
+            "Got {} images but {} transforms",
+            images.len(),
+            transforms.len()
+        ));
+    }
+    let sizes = images.iter().map(|i| i.dimensions()).collect::<Vec<_>>();
+    let canvas = Canvas::new(&sizes, transforms, options.max_pixels)?;
+    let transforms = transforms
+        .iter()
+        .map(|h| HomographyMatrix(canvas.matrix() * h.0))
+        .collect::<Vec<_>>();
+
+    let (width, height) = (canvas.width, canvas.height);
+    let warp_color = |image: &RgbImage, h| {
+        warp_perspective(
+            &to_f32(image),
+            h,
+            width,
+            height,
+            WarpOptions {
+                interpolation: options.interpolation,
+                // Extends the image beyond its borders, so the pyramids of the multi-band
+                // blending are not darkened around them
+                border: BorderMode::Replicate,
+                inverse_map: false,
+            },
+        )
+    };
+    let warp_weight = |image: &RgbImage, h| {
+        warp_perspective(
+            &feather_weights(image.width(), image.height()),
+            h,
+            width,
+            height,
+            WarpOptions {
+                interpolation: Interpolation::Bilinear,
+                border: BorderMode::Constant(Luma([0.0])),
+                inverse_map: false,
+            },
+        )
+    };
+
+    // The images are accumulated one by one, only the running sums have the size of the canvas
+    let (color, coverage) = match options.blending {
+        Blending::Feather => {
+            let mut feather = Feather::new(width, height);
+            for (image, h) in images.iter().zip(&transforms) {
+                feather.add(&warp_color(image, h)?, &warp_weight(image, h)?);
+            }
+            feather.finish()
+        }
+        Blending::MultiBand { bands } => {
+            let mut multi_band = MultiBand::new(width, height, bands.max(1));
+            // The seams have to be known before the first image is blended
+            for (ix, (image, h)) in images.iter().zip(&transforms).enumerate() {
+                multi_band.add_weight(ix, &warp_weight(image, h)?);
+            }
+            for (ix, (image, h)) in images.iter().zip(&transforms).enumerate() {
+                multi_band.add(ix, &warp_color(image, h)?, &warp_weight(image, h)?);
+            }
+            multi_band.finish()
+        }
+    };
+    let image = RgbaImage::from_fn(width, height, |x, y| {
+        let Rgb(c) = *color.get_pixel(x, y);
+        let to_u8 = |v: f32| v.round().clamp(0.0, 255.0) as u8;
+        let alpha = if coverage.get_pixel(x, y).0[0] > 0.0 {
+            255
+        } else {
+            0
+        };
+        Rgba([to_u8(c[0]), to_u8(c[1]), to_u8(c[2]), alpha])
+    });
+    Ok(Panorama { image, transforms })
+}
+
+/// Options of [`stitch_images`].
+#[cfg(feature = "akaze")]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct StitchOptions {
+    /// Options of the homography estimation between consecutive images
+    pub pair: ImagePairOptions,
+    pub composition: CompositionOptions,
+    /// Index of the image whose plane the others are projected to. Default: the middle one
+    pub reference: Option<usize>,
+}
+
+/// Stitches a sequence of overlapping images into a panorama.
+/// *This is supported on **crate feature `akaze`** only.*
+///
+/// The homographies between consecutive images are estimated with
+/// [`find_homography_from_images`], chained to the reference image with [`chain_homographies`],
+/// then the images are combined with [`compose_panorama`].
+#[cfg(feature = "akaze")]
+pub fn stitch_images(images: &[DynamicImage], options: StitchOptions) -> Result<Panorama> {
+    if images.is_empty() {
+        return Err(eyre!("There are no images"));
+    }
+    let pairwise = images
+        .windows(2)
+        .enumerate()
+        .map(|(ix, pair)| {
+            find_homography_from_images(&pair[0], &pair[1], options.pair)
+                .map(|result| result.homography)
+                .map_err(|e| e.wrap_err(format!("Failed to match image {} and {}", ix, ix + 1)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let reference = options.reference.unwrap_or(images.len() / 2);
+    let transforms = chain_homographies(&pairwise, reference)?;
+    let images = images.iter().map(|i| i.to_rgb8()).collect::<Vec<_>>();
+    compose_panorama(&images, &transforms, options.composition)
+}
+
+fn to_f32(image: &RgbImage) -> RgbF32Image {
+    RgbF32Image::from_fn(image.width(), image.height(), |x, y| {
+        let Rgb(c) = *image.get_pixel(x, y);
+        Rgb(c.map(f32::from))
+    })
+}
+
+/// Product of the horizontal and vertical distances of the pixels from the outside of the image.
+/// Unlike the distance to the closest edge, it doesn't tie along the common edges of the images.
+fn feather_weights(width: u32, height: u32) -> GrayF32Image {
+    GrayF32Image::from_fn(width, height, |x, y| {
+        let horizontal = (x + 1).min(width - x);
+        let vertical = (y + 1).min(height - y);
+        Luma([horizontal as f32 * vertical as f32])
+    })
+}
+
+/// Running weighted sum of the colors of the images.
+struct Feather {
+    color: RgbF32Image,
+    weight_sum: GrayF32Image,
+}
+
+impl Feather {
+    fn new(width: u32, height: u32) -> Self {
+        Self {
+            color: RgbF32Image::new(width, height),
+            weight_sum: GrayF32Image::new(width, height),
+        }
+    }
+
+    fn add(&mut self, layer: &RgbF32Image, weight: &GrayF32Image) {
+        for ((sum, w_sum), (c, w)) in self
+            .color
+            .pixels_mut()
+            .zip(self.weight_sum.pixels_mut())
+            .zip(layer.pixels().zip(weight.pixels()))
+        {
+            for (s, c) in sum.0.iter_mut().zip(c.0) {
+                *s += w.0[0] * c;
+            }
+            w_sum.0[0] += w.0[0];
+        }
+    }
+
+    fn finish(mut self) -> (RgbF32Image, GrayF32Image) {
+        for (sum, w_sum) in self.color.pixels_mut().zip(self.weight_sum.pixels()) {
+            if w_sum.0[0] > 0.0 {
+                sum.0.iter_mut().for_each(|s| *s /= w_sum.0[0]);
+            }
+        }
+        (self.color, self.weight_sum)
+    }
+}
+
+/// Running sums of the Laplacian pyramids of the images, weighted by the smoothed masks of the
+/// pixels they own.
+struct MultiBand {
+    width: u32,
+    height: u32,
+    bands: usize,
+    /// Largest feather weight of every pixel
+    coverage: GrayF32Image,
+    /// Image with the largest weight at every pixel
+    owner: Vec<usize>,
+    blended: Vec<Planes>,
+    weight_sums: Vec<Planes>,
+}
+
+impl MultiBand {
+    fn new(width: u32, height: u32, bands: usize) -> Self {
+        Self {
+            width,
+            height,
+            bands,
+            coverage: GrayF32Image::new(width, height),
+            owner: vec![usize::MAX; width as usize * height as usize],
+            blended: vec![],
+            weight_sums: vec![],
+        }
+    }
+
+    /// Gives the pixels where image `ix` has the largest weight so far to it. Every image has to
+    /// be added here before [`Self::add`].
+    fn add_weight(&mut self, ix: usize, weight: &GrayF32Image) {
+        for ((best, w), owner) in self
+            .coverage
+            .pixels_mut()
+            .zip(weight.pixels())
+            .zip(&mut self.owner)
+        {
+            if w.0[0] > best.0[0] {
+                best.0[0] = w.0[0];
+                *owner = ix;
+            }
+        }
+    }
+
+    fn add(&mut self, ix: usize, color: &RgbF32Image, weight: &GrayF32Image) {
+        let (width, height, bands) = (self.width, self.height, self.bands);
+        // The smoothed masks are cut at the border of the image at every level
+        let inside = Planes {
+            width,
+            height,
+            channels: 1,
+            data: weight
+                .pixels()
+                .map(|w| (w.0[0] > 0.0) as u8 as f32)
+                .collect(),
+        };
+        let mask = Planes {
+            width,
+            height,
+            channels: 1,
+            data: self.owner.iter().map(|o| (*o == ix) as u8 as f32).collect(),
+        };
+        let laplacian = Planes::from(color).laplacian_pyramid(bands);
+        let masks = mask
+            .gaussian_pyramid(bands)
+            .into_iter()
+            .zip(inside.gaussian_pyramid(bands))
+            .map(|(mut mask, inside)| {
+                for (m, i) in mask.data.iter_mut().zip(inside.data) {
+                    *m *= i;
+                }
+                mask
+            })
+            .collect::<Vec<_>>();
+        if self.blended.is_empty() {
+            self.blended = laplacian.iter().map(Planes::zeros_like).collect();
+            self.weight_sums = masks.iter().map(Planes::zeros_like).collect();
+        }
+        for (((sum, weight_sum), level), mask) in self
+            .blended
+            .iter_mut()
+            .zip(&mut self.weight_sums)
+            .zip(&laplacian)
+            .zip(&masks)
+        {
+            for (p, &m) in mask.data.iter().enumerate() {
+                weight_sum.data[p] += m;
+                for c in 0..3 {
+                    sum.data[p * 3 + c] += m * level.data[p * 3 + c];
+                }
+            }
+        }
+    }
+
+    fn finish(mut self) -> (RgbF32Image, GrayF32Image) {
+        if self.blended.is_empty() {
+            return (RgbF32Image::new(self.width, self.height), self.coverage);
+        }
+        for (sum, weight_sum) in self.blended.iter_mut().zip(&self.weight_sums) {
+            for (p, &w) in weight_sum.data.iter().enumerate() {
+                if w > 0.0 {
+                    sum.data[p * 3..p * 3 + 3].iter_mut().for_each(|s| *s /= w);
+                }
+            }
+        }
+        let color = Planes::collapse(self.blended);
+        let color =
+            RgbF32Image::from_raw(self.width, self.height, color.data).expect("size of the canvas");
+        (color, self.coverage)
+    }
+}
+
+/// Interleaved floating point image for the pyramids of the multi-band blending.
+#[derive(Debug, Clone)]
+struct Planes {
+    width: u32,
+    height: u32,
+    channels: usize,
+    data: Vec<f32>,
+}
+
+impl From<&RgbF32Image> for Planes {
+    fn from(image: &RgbF32Image) -> Self {
+        Self {
+            width: image.width(),
+            height: image.height(),
+            channels: 3,
+            data: image.as_raw().clone(),
+        }
+    }
+}
+
+impl Planes {
+    fn zeros_like(other: &Planes) -> Self {
+        Self {
+            data: vec![0.0; other.data.len()],
+            ..*other
+        }
+    }
+
+    fn get(&self, x: i64, y: i64, c: usize) -> f32 {
+        let x = x.clamp(0, self.width as i64 - 1) as usize;
+        let y = y.clamp(0, self.height as i64 - 1) as usize;
+        self.data[(y * self.width as usize + x) * self.channels + c]
+    }
+
+    /// Blurs with a 5×5 binomial kernel and keeps every second pixel.
+    fn down(&self) -> Self {
+        const KERNEL: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+        let (width, height) = (self.width.div_ceil(2), self.height.div_ceil(2));
+        let mut data = Vec::with_capacity(width as usize * height as usize * self.channels);
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                for c in 0..self.channels {
+                    let mut value = 0.0;
+                    for (j, ky) in KERNEL.iter().enumerate() {
+                        for (i, kx) in KERNEL.iter().enumerate() {
+                            value +=
+                                ky * kx * self.get(2 * x + i as i64 - 2, 2 * y + j as i64 - 2, c);
+                        }
+                    }
+                    data.push(value);
+                }
+            }
+        }
+        Self {
+            width,
+            height,
+            channels: self.channels,
+            data,
+        }
+    }
+
+    /// Bilinear upsampling to the given size, the inverse of the decimation of [`Self::down`].
+    fn up(&self, width: u32, height: u32) -> Self {
+        let mut data = Vec::with_capacity(width as usize * height as usize * self.channels);
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let (x0, y0) = (x.div_euclid(2), y.div_euclid(2));
+                let (fx, fy) = ((x % 2) as f32 * 0.5, (y % 2) as f32 * 0.5);
+                for c in 0..self.channels {
+                    let top = self.get(x0, y0, c) * (1.0 - fx) + self.get(x0 + 1, y0, c) * fx;
+                    let bottom =
+                        self.get(x0, y0 + 1, c) * (1.0 - fx) + self.get(x0 + 1, y0 + 1, c) * fx;
+                    data.push(top * (1.0 - fy) + bottom * fy);
+                }
+            }
+        }
+        Self {
+            width,
+            height,
+            channels: self.channels,
+            data,
+        }
+    }
+
+    fn gaussian_pyramid(self, levels: usize) -> Vec<Planes> {
+        let mut pyramid = vec![self];
+        while pyramid.len() < levels {
+            let next = pyramid.last().unwrap().down();
+            pyramid.push(next);
+        }
+        pyramid
+    }
+
+    /// Band-pass levels, with the remaining low-pass image as the last level.
+    fn laplacian_pyramid(self, levels: usize) -> Vec<Planes> {
+        let mut pyramid = self.gaussian_pyramid(levels);
+        for level in 0..pyramid.len() - 1 {
+            let (width, height) = (pyramid[level].width, pyramid[level].height);
+            let up = pyramid[level + 1].up(width, height);
+            for (v, u) in pyramid[level].data.iter_mut().zip(up.data) {
+                *v -= u;
+            }
+        }
+        pyramid
+    }
+
+    /// Inverse of [`Self::laplacian_pyramid`].
+    fn collapse(mut pyramid: Vec<Planes>) -> Planes {
+        let mut image = pyramid.pop().expect("at least one level");
+        while let Some(mut level) = pyramid.pop() {
+            let up = image.up(level.width, level.height);
+            for (v, u) in level.data.iter_mut().zip(up.data) {
+                *v += u;
+            }
+            image = level;
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        chain_homographies, compose_panorama, Blending, CompositionOptions, HomographyMatrix,
+    };
+    use approx::assert_relative_eq;
+    use image::{imageops, RgbImage};
+    use nalgebra::{Matrix3, Vector2};
+
+    #[test]
+    fn chains_to_the_reference() {
+        let shift = |x: f64| HomographyMatrix(Matrix3::new_translation(&Vector2::new(x, 0.0)));
+        let transforms =
+            chain_homographies(&[shift(-10.0), shift(-20.0), shift(-30.0)], 1).unwrap();
+        let offsets = transforms.iter().map(|h| h.0[(0, 2)]).collect::<Vec<_>>();
+        assert_relative_eq!(offsets[..], [-10.0, 0.0, 20.0, 50.0][..]);
+        assert!(chain_homographies(&[shift(1.0)], 2).is_err());
+    }
+
+    #[test]
+    fn reassembles_overlapping_crops() {
+        let scene = RgbImage::from_fn(200, 80, |x, y| {
+            let (x, y) = (x as f64, y as f64);
+            let v = |f: f64| (127.0 + 100.0 * f.sin()) as u8;
+            image::Rgb([v(x / 9.0), v(y / 7.0), v((x + y) / 13.0)])
+        });
+        // Three crops overlapping by 40 pixels, each crop maps to the first one by a shift
+        let crops = [0, 60, 120]
+            .iter()
+            .map(|&x| imageops::crop_imm(&scene, x, 0, 80, 80).to_image())
+            .collect::<Vec<_>>();
+        let transforms = [0.0, 60.0, 120.0]
+            .iter()
+            .map(|&x| HomographyMatrix(Matrix3::new_translation(&Vector2::new(x, 0.0))))
+            .collect::<Vec<_>>();
+
+        for blending in [Blending::Feather, Blending::MultiBand { bands: 4 }] {
+            let options = CompositionOptions {
+                blending,
+                ..Default::default()
+            };
+            let panorama = compose_panorama(&crops, &transforms, options).unwrap();
+            assert_eq!(panorama.image.dimensions(), (200, 80));
+            for (x, y, pixel) in panorama.image.enumerate_pixels() {
+                let expected = scene.get_pixel(x, y);
+                assert_eq!(pixel.0[3], 255);
+                for c in 0..3 {
+                    assert!(
+                        (pixel.0[c] as i32 - expected.0[c] as i32).abs() <= 1,
+                        "{:?} at {} {}",
+                        blending,
+                        x,
+                        y
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,609 @@
+use std::collections::{HashMap, VecDeque};
+
+use cv_core::FeatureMatch;
+use eyre::{eyre, Result};
+use image::GrayImage;
+use nalgebra::{Matrix2, Point2, Vector2};
+
+/// Options of [`find_chessboard_corners`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChessboardOptions {
+    /// Number of inner corners along a row and along a column of the board
+    pub pattern: (usize, usize),
+    /// Side length of the squares, in the unit of the target coordinates
+    pub square_size: f64,
+    /// Standard deviation of the Gaussian blur before the corner detection, in pixels.
+    /// Should be well below the size of the squares on the image.
+    pub blur: f32,
+    /// Corners with a weaker saddle response than this fraction of the strongest one are ignored
+    pub threshold: f32,
+    /// Half size of the window of the sub-pixel refinement
+    pub refinement_window: u32,
+}
+
+impl Default for ChessboardOptions {
+    fn default() -> Self {
+        Self {
+            pattern: (9, 6),
+            square_size: 1.0,
+            blur: 1.5,
+            threshold: 0.3,
+            refinement_window: 5,
+        }
+    }
+}
+
+/// Finds the inner corners of a chessboard calibration target.
+/// *This is supported on **crate feature `image`** only.*
+///
+/// The corners are the saddle points of the blurred image, they are ordered by walking the grid
+/// between neighbours, then refined to sub-pixel accuracy with [`refine_corners`]. The result
+/// matches the target coordinates `(i, j) * square_size` of the corners to their pixels, in
+/// row-major order. The first corner is the one closest to the top left of the image, and
+/// the target axes keep their orientation on the image, so the board is never mirrored.
+///
+/// Fails if the board is not fully visible.
+pub fn find_chessboard_corners(
+    image: &GrayImage,
+    options: ChessboardOptions,
+) -> Result<Vec<FeatureMatch<Point2<f64>>>> {
+    let (width, height) = image.dimensions();
+    let pixels = image.pixels().map(|p| p.0[0] as f32).collect::<Vec<_>>();
+    let blurred = gaussian_blur(&pixels, width as usize, height as usize, options.blur);
+    let candidates = saddle_points(&blurred, width as usize, height as usize, options.threshold);
+    let mut corners = order_grid(&candidates, options.pattern).ok_or_else(|| {
+        eyre!(
+            "Chessboard of {}×{} inner corners not found among {} corner candidates",
+            options.pattern.0,
+            options.pattern.1,
+            candidates.len()
+        )
+    })?;
+    refine_corners(image, &mut corners, options.refinement_window);
+    Ok(target_matches(
+        &corners,
+        options.pattern,
+        options.square_size,
+    ))
+}
+
+/// Options of [`find_circle_grid`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CircleGridOptions {
+    /// Number of circles along a row and along a column of the grid
+    pub pattern: (usize, usize),
+    /// Distance of the neighbouring circle centers, in the unit of the target coordinates
+    pub spacing: f64,
+    /// Pixels darker than this belong to the circles. Otsu's method is used when `None`.
+    pub threshold: Option<u8>,
+    /// Smallest area of a circle in pixels
+    pub min_area: usize,
+    /// Largest area of a circle in pixels
+    pub max_area: usize,
+}
+
+impl Default for CircleGridOptions {
+    fn default() -> Self {
+        Self {
+            pattern: (4, 11),
+            spacing: 1.0,
+            threshold: None,
+            min_area: 10,
+            max_area: 10000,
+        }
+    }
+}
+
+/// Finds the centers of a symmetric grid of dark circles on a light background.
+/// *This is supported on **crate feature `image`** only.*
+///
+/// The circles are the compact connected components of the thresholded image, and their centers
+/// are the centroids of the components. They are ordered like the corners of
+/// [`find_chessboard_corners`], matching the target coordinates `(i, j) * spacing` in row-major
+/// order. Fails if the grid is not fully visible.
+pub fn find_circle_grid(
+    image: &GrayImage,
+    options: CircleGridOptions,
+) -> Result<Vec<FeatureMatch<Point2<f64>>>> {
+    let threshold = options.threshold.unwrap_or_else(|| otsu_threshold(image));
+    let centers = dark_blobs(image, threshold, options.min_area, options.max_area);
+    let centers = order_grid(&centers, options.pattern).ok_or_else(|| {
+        eyre!(
+            "Circle grid of {}×{} not found among {} blobs",
+            options.pattern.0,
+            options.pattern.1,
+            centers.len()
+        )
+    })?;
+    Ok(target_matches(&centers, options.pattern, options.spacing))
+}
+
+/// Refines the positions of corners to sub-pixel accuracy, like OpenCV's `cornerSubPix`.
+/// *This is supported on **crate feature `image`** only.*
+///
+/// The image gradient is orthogonal to the vector from the corner at every pixel around it,
+/// the corner is moved to the least squares solution of these constraints in a
+/// `(2 window + 1)²` neighbourhood until it settles.
+pub fn refine_corners(image: &GrayImage, corners: &mut [Point2<f64>], window: u32) {
+    let (width, height) = (image.width() as i64, image.height() as i64);
+    let value = |x: i64, y: i64| {
+        image
+            .get_pixel(x.clamp(0, width - 1) as u32, y.clamp(0, height - 1) as u32)
+            .0[0] as f64
+    };
+    let window = window as i64;
+    let sigma = window as f64 / 2.0;
+    for corner in corners.iter_mut() {
+        for _ in 0..20 {
+            let (cx, cy) = (corner.x.round() as i64, corner.y.round() as i64);
+            let mut a = Matrix2::zeros();
+            let mut b = Vector2::zeros();
+            for y in cy - window..=cy + window {
+                for x in cx - window..=cx + window {
+                    let gradient = Vector2::new(
+                        value(x + 1, y) - value(x - 1, y),
+                        value(x, y + 1) - value(x, y - 1),
+                    );
+                    let offset = Vector2::new((x - cx) as f64, (y - cy) as f64);
+                    let weight = (-offset.norm_squared() / (2.0 * sigma * sigma)).exp();
+                    let ggt = gradient * gradient.transpose() * weight;
+                    a += ggt;
+                    b += ggt * Vector2::new(x as f64, y as f64);
+                }
+            }
+            let Some(refined) = a.try_inverse().map(|a| Point2::from(a * b)) else {
+                break;
+            };
+            // Corners that would leave the window are kept in place
+            if (refined - Point2::new(cx as f64, cy as f64)).abs().max() > window as f64 {
+                break;
+            }
+            let moved = nalgebra::distance(&refined, corner);
+            *corner = refined;
+            if moved < 1e-3 {
+                break;
+            }
+        }
+    }
+}
+
+fn target_matches(
+    points: &[Point2<f64>],
+    pattern: (usize, usize),
+    size: f64,
+) -> Vec<FeatureMatch<Point2<f64>>> {
+    points
+        .iter()
+        .enumerate()
+        .map(|(ix, p)| {
+            let (i, j) = (ix % pattern.0, ix / pattern.0);
+            FeatureMatch(Point2::new(i as f64 * size, j as f64 * size), *p)
+        })
+        .collect()
+}
+
+/// Separable Gaussian blur with replicated borders.
+fn gaussian_blur(pixels: &[f32], width: usize, height: usize, sigma: f32) -> Vec<f32> {
+    if sigma <= 0.0 {
+        return pixels.to_vec();
+    }
+    let radius = (3.0 * sigma).ceil() as i64;
+    let kernel = (-radius..=radius)
+        .map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect::<Vec<_>>();
+    let sum = kernel.iter().sum::<f32>();
+    let kernel = kernel.iter().map(|k| k / sum).collect::<Vec<_>>();
+    let convolve = |input: &[f32], stride: usize, steps: usize, count: usize, len: usize| {
+        let mut output = vec![0.0; input.len()];
+        for line in 0..count {
+            for i in 0..len as i64 {
+                let mut value = 0.0;
+                for (k, weight) in kernel.iter().enumerate() {
+                    let at = (i + k as i64 - radius).clamp(0, len as i64 - 1) as usize;
+                    value += weight * input[line * steps + at * stride];
+                }
+                output[line * steps + i as usize * stride] = value;
+            }
+        }
+        output
+    };
+    let horizontal = convolve(pixels, 1, width, height, width);
+    convolve(&horizontal, width, 1, width, height)
+}
+
+/// Local maxima of the negative Hessian determinant, the saddle points of the image.
+fn saddle_points(pixels: &[f32], width: usize, height: usize, threshold: f32) -> Vec<Point2<f64>> {
+    let at = |x: usize, y: usize| pixels[y * width + x];
+    let mut response = vec![0.0f32; pixels.len()];
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            let dxx = at(x + 1, y) - 2.0 * at(x, y) + at(x - 1, y);
+            let dyy = at(x, y + 1) - 2.0 * at(x, y) + at(x, y - 1);
+            let dxy =
+                (at(x + 1, y + 1) - at(x - 1, y + 1) - at(x + 1, y - 1) + at(x - 1, y - 1)) / 4.0;
+            response[y * width + x] = (dxy * dxy - dxx * dyy).max(0.0);
+        }
+    }
+    let max = response.iter().cloned().fold(0.0, f32::max);
+    if max <= 0.0 {
+        return vec![];
+    }
+    let radius = 3;
+    let mut points = vec![];
+    for y in radius..height.saturating_sub(radius) {
+        for x in radius..width.saturating_sub(radius) {
+            let r = response[y * width + x];
+            if r < threshold * max {
+                continue;
+            }
+            let is_max = (y - radius..=y + radius).all(|ny| {
+                (x - radius..=x + radius).all(|nx| {
+                    let other = response[ny * width + nx];
+                    // Ties are broken by the position, so plateaus give one point
+                    other < r || (other == r && (ny, nx) >= (y, x))
+                })
+            });
+            if is_max {
+                points.push(Point2::new(x as f64, y as f64));
+            }
+        }
+    }
+    points
+}
+
+/// Threshold that minimizes the intra-class variance of the dark and the light pixels.
+fn otsu_threshold(image: &GrayImage) -> u8 {
+    let mut histogram = [0usize; 256];
+    for p in image.pixels() {
+        histogram[p.0[0] as usize] += 1;
+    }
+    let total = image.pixels().len() as f64;
+    let sum = histogram
+        .iter()
+        .enumerate()
+        .map(|(v, &count)| v as f64 * count as f64)
+        .sum::<f64>();
+    let (mut dark_count, mut dark_sum) = (0.0, 0.0);
+    let (mut best, mut best_variance) = (0, 0.0);
+    for (value, &count) in histogram.iter().enumerate() {
+        dark_count += count as f64;
+        dark_sum += value as f64 * count as f64;
+        let light_count = total - dark_count;
+        if dark_count == 0.0 || light_count == 0.0 {
+            continue;
+        }
+        let difference = dark_sum / dark_count - (sum - dark_sum) / light_count;
+        let variance = dark_count * light_count * difference * difference;
+        if variance > best_variance {
+            best_variance = variance;
+            best = value;
+        }
+    }
+    best as u8
+}
+
+/// Centroids of the roughly circular 8-connected components of the pixels not brighter than
+/// `threshold`. Components touching the border of the image are left out.
+fn dark_blobs(
+    image: &GrayImage,
+    threshold: u8,
+    min_area: usize,
+    max_area: usize,
+) -> Vec<Point2<f64>> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let dark = image
+        .pixels()
+        .map(|p| p.0[0] <= threshold)
+        .collect::<Vec<_>>();
+    let mut visited = vec![false; dark.len()];
+    let mut centers = vec![];
+    let mut stack = vec![];
+    for start in 0..dark.len() {
+        if !dark[start] || visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        stack.push(start);
+        let (mut area, mut sum) = (0usize, Vector2::zeros());
+        let (mut min, mut max) = ((usize::MAX, usize::MAX), (0, 0));
+        let mut touches_border = false;
+        while let Some(ix) = stack.pop() {
+            let (x, y) = (ix % width, ix / width);
+            area += 1;
+            sum += Vector2::new(x as f64, y as f64);
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x), max.1.max(y));
+            touches_border |= x == 0 || y == 0 || x == width - 1 || y == height - 1;
+            for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+                for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                    let neighbour = ny * width + nx;
+                    if dark[neighbour] && !visited[neighbour] {
+                        visited[neighbour] = true;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+        }
+        // Area of the ellipse inscribed in the bounding box, an ellipse fills it
+        let (w, h) = ((max.0 - min.0 + 1) as f64, (max.1 - min.1 + 1) as f64);
+        let fill = area as f64 / (std::f64::consts::FRAC_PI_4 * w * h);
+        if !touches_border && (min_area..=max_area).contains(&area) && (0.8..1.2).contains(&fill) {
+            centers.push(Point2::from(sum / area as f64));
+        }
+    }
+    centers
+}
+
+/// Orders the points of a `columns`×`rows` grid in row-major order.
+///
+/// The grid is grown from a point near the center: the next point in each direction is
+/// predicted from the previous step in that direction, so it follows the perspective distortion.
+/// Points next to the target that line up with it, like clutter in the background, can extend the
+/// grown grid, so it's cropped to the one window of the pattern's size whose rows and columns are
+/// complete. `None` if there is no such window, or more than one.
+fn order_grid(points: &[Point2<f64>], pattern: (usize, usize)) -> Option<Vec<Point2<f64>>> {
+    let (columns, rows) = pattern;
+    if columns < 2 || rows < 2 || points.len() < columns * rows {
+        return None;
+    }
+    let centroid =
+        Point2::from(points.iter().map(|p| p.coords).sum::<Vector2<f64>>() / points.len() as f64);
+    let mut seeds = (0..points.len()).collect::<Vec<_>>();
+    seeds.sort_by(|&a, &b| {
+        let distance = |ix: usize| nalgebra::distance_squared(&points[ix], &centroid);
+        distance(a).total_cmp(&distance(b))
+    });
+    seeds
+        .into_iter()
+        .take(5)
+        .find_map(|seed| grow_grid(points, seed, pattern))
+}
+
+fn grow_grid(
+    points: &[Point2<f64>],
+    seed: usize,
+    (columns, rows): (usize, usize),
+) -> Option<Vec<Point2<f64>>> {
+    // The two directions of the grid at the seed: its nearest neighbour,
+    // and the closest one that is not along the same line
+    let mut neighbours = (0..points.len())
+        .filter(|&ix| ix != seed)
+        .collect::<Vec<_>>();
+    neighbours.sort_by(|&a, &b| {
+        let distance = |ix: usize| nalgebra::distance_squared(&points[ix], &points[seed]);
+        distance(a).total_cmp(&distance(b))
+    });
+    let u = points[*neighbours.first()?] - points[seed];
+    let v = neighbours
+        .iter()
+        .take(8)
+        .map(|&ix| points[ix] - points[seed])
+        .find(|w| {
+            let sine = (u.x * w.y - u.y * w.x).abs() / (u.norm() * w.norm());
+            sine > 0.5 && (0.5..2.0).contains(&(w.norm() / u.norm()))
+        })?;
+
+    let mut grid: HashMap<(i64, i64), usize> = HashMap::new();
+    let mut used = vec![false; points.len()];
+    // The step to the neighbours along the two directions at each grid point
+    let mut steps: HashMap<(i64, i64), (Vector2<f64>, Vector2<f64>)> = HashMap::new();
+    grid.insert((0, 0), seed);
+    steps.insert((0, 0), (u, v));
+    used[seed] = true;
+    let mut queue = VecDeque::from([(0i64, 0i64)]);
+    while let Some((i, j)) = queue.pop_front() {
+        let p = points[grid[&(i, j)]];
+        let (step_u, step_v) = steps[&(i, j)];
+        for (di, dj) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let next = (i + di, j + dj);
+            if grid.contains_key(&next) {
+                continue;
+            }
+            let step = step_u * di as f64 + step_v * dj as f64;
+            let predicted = p + step;
+            let tolerance = 0.3 * step.norm();
+            let found = (0..points.len())
+                .filter(|&ix| !used[ix])
+                .map(|ix| (ix, nalgebra::distance(&points[ix], &predicted)))
+                .filter(|(_, distance)| *distance < tolerance)
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+            if let Some((ix, _)) = found {
+                used[ix] = true;
+                grid.insert(next, ix);
+                let actual = points[ix] - p;
+                let next_steps = if di != 0 {
+                    (actual * di as f64, step_v)
+                } else {
+                    (step_u, actual * dj as f64)
+                };
+                steps.insert(next, next_steps);
+                queue.push_back(next);
+            }
+        }
+    }
+
+    let (min_i, max_i) = (
+        grid.keys().map(|k| k.0).min()?,
+        grid.keys().map(|k| k.0).max()?,
+    );
+    let (min_j, max_j) = (
+        grid.keys().map(|k| k.1).min()?,
+        grid.keys().map(|k| k.1).max()?,
+    );
+    // Complete windows of the pattern in both orientations: (corner, size, transpose)
+    let windows = [((columns, rows), false), ((rows, columns), true)]
+        .into_iter()
+        .flat_map(|((size_i, size_j), transpose)| {
+            let (size_i, size_j) = (size_i as i64, size_j as i64);
+            (min_i..=max_i - size_i + 1)
+                .flat_map(move |i| (min_j..=max_j - size_j + 1).map(move |j| (i, j)))
+                .map(move |corner| (corner, (size_i, size_j), transpose))
+        })
+        .filter(|&((i0, j0), (size_i, size_j), _)| {
+            (i0..i0 + size_i).all(|i| (j0..j0 + size_j).all(|j| grid.contains_key(&(i, j))))
+        })
+        .collect::<Vec<_>>();
+    // A square pattern fits the same window in both orientations
+    let &(corner, size, _) = windows.first()?;
+    if windows
+        .iter()
+        .any(|window| (window.0, window.1) != (corner, size))
+    {
+        return None;
+    }
+    let transposes = windows.iter().map(|window| window.2).collect::<Vec<_>>();
+    let at = |i: i64, j: i64| points[grid[&(corner.0 + i, corner.1 + j)]];
+    // Position of the corner in column `c` and row `r` with an ordering of the grid,
+    // given as (transpose, flip columns, flip rows)
+    let position = |(transpose, flip_c, flip_r): (bool, bool, bool), c: usize, r: usize| {
+        let c = if flip_c { columns - 1 - c } else { c } as i64;
+        let r = if flip_r { rows - 1 - r } else { r } as i64;
+        if transpose {
+            at(r, c)
+        } else {
+            at(c, r)
+        }
+    };
+    // Keep the orientation of the target axes, then start from the top left
+    let best = transposes
+        .iter()
+        .flat_map(|&transpose| {
+            [(false, false), (true, false), (false, true), (true, true)]
+                .map(|(flip_c, flip_r)| (transpose, flip_c, flip_r))
+        })
+        .filter(|&ordering| {
+            let origin = position(ordering, 0, 0);
+            let along_row = position(ordering, columns - 1, 0) - origin;
+            let along_column = position(ordering, 0, rows - 1) - origin;
+            along_row.x * along_column.y - along_row.y * along_column.x > 0.0
+        })
+        .min_by(|&a, &b| {
+            let (a, b) = (position(a, 0, 0), position(b, 0, 0));
+            (a.x + a.y).total_cmp(&(b.x + b.y))
+        })?;
+    Some(
+        (0..rows)
+            .flat_map(|r| (0..columns).map(move |c| (c, r)))
+            .map(|(c, r)| position(best, c, r))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{find_chessboard_corners, find_circle_grid, ChessboardOptions, CircleGridOptions};
+    use image::{GrayImage, Luma};
+    use nalgebra::{Matrix3, Point2};
+
+    /// Renders the target with 4×4 supersampling. `h` maps the target plane to the image,
+    /// `dark` tells if a point of the target is dark.
+    fn render(h: &Matrix3<f64>, dark: impl Fn(f64, f64) -> bool) -> GrayImage {
+        let inverse = h.try_inverse().unwrap();
+        GrayImage::from_fn(640, 480, |x, y| {
+            let mut value = 0.0;
+            for sy in 0..4 {
+                for sx in 0..4 {
+                    let p = Point2::new(
+                        x as f64 + (sx as f64 + 0.5) / 4.0 - 0.5,
+                        y as f64 + (sy as f64 + 0.5) / 4.0 - 0.5,
+                    );
+                    let t = Point2::from_homogeneous(inverse * p.to_homogeneous()).unwrap();
+                    value += if dark(t.x, t.y) { 30.0 } else { 220.0 };
+                }
+            }
+            Luma([(value / 16.0) as u8])
+        })
+    }
+
+    fn homography() -> Matrix3<f64> {
+        Matrix3::new(0.95, 0.12, 140.0, -0.08, 0.9, 110.0, 0.0002, -0.0003, 1.0)
+    }
+
+    fn project(h: &Matrix3<f64>, p: &Point2<f64>) -> Point2<f64> {
+        Point2::from_homogeneous(h * p.to_homogeneous()).unwrap()
+    }
+
+    #[test]
+    fn chessboard() {
+        let h = homography();
+        let size = 30.0;
+        let (columns, rows) = (9, 6);
+        let image = render(&h, |x, y| {
+            let (i, j) = ((x / size).floor() as i64, (y / size).floor() as i64);
+            (-1..columns as i64).contains(&i)
+                && (-1..rows as i64).contains(&j)
+                && (i + j).rem_euclid(2) == 0
+        });
+        let options = ChessboardOptions {
+            pattern: (columns, rows),
+            square_size: size,
+            ..Default::default()
+        };
+        let corners = find_chessboard_corners(&image, options).unwrap();
+        assert_eq!(corners.len(), columns * rows);
+        let mut max_error: f64 = 0.0;
+        for m in &corners {
+            max_error = max_error.max(nalgebra::distance(&project(&h, &m.0), &m.1));
+        }
+        assert!(max_error < 0.15, "{}", max_error);
+
+        let wrong_pattern = find_chessboard_corners(
+            &image,
+            ChessboardOptions {
+                pattern: (columns + 1, rows),
+                ..options
+            },
+        );
+        assert!(wrong_pattern.is_err());
+    }
+
+    #[test]
+    fn circle_grid_with_clutter() {
+        let h = homography();
+        let spacing = 40.0;
+        let (columns, rows) = (7, 5);
+        // Circles that line up with the grid past the end of a row, above a column and off a
+        // corner, and one off the grid lines
+        let clutter = [(7.0, 2.0), (3.0, -1.0), (-1.0, -1.0), (8.6, 5.3)];
+        let image = render(&h, |x, y| {
+            let (i, j) = ((x / spacing).round(), (y / spacing).round());
+            let inside = (0.0..columns as f64).contains(&i) && (0.0..rows as f64).contains(&j);
+            let on_circle = |(i, j): (f64, f64)| (x - i * spacing).hypot(y - j * spacing) < 12.0;
+            (inside && on_circle((i, j))) || clutter.into_iter().any(on_circle)
+        });
+        let options = CircleGridOptions {
+            pattern: (columns, rows),
+            spacing,
+            ..Default::default()
+        };
+        let centers = find_circle_grid(&image, options).unwrap();
+        assert_eq!(centers.len(), columns * rows);
+        for m in &centers {
+            let error = nalgebra::distance(&project(&h, &m.0), &m.1);
+            assert!(error < 0.5, "{} at {:?}", error, m.0);
+        }
+    }
+
+    #[test]
+    fn circle_grid() {
+        let h = homography();
+        let spacing = 40.0;
+        let (columns, rows) = (7, 5);
+        let image = render(&h, |x, y| {
+            let (i, j) = ((x / spacing).round(), (y / spacing).round());
+            let inside = (0.0..columns as f64).contains(&i) && (0.0..rows as f64).contains(&j);
+            inside && (x - i * spacing).hypot(y - j * spacing) < 12.0
+        });
+        let options = CircleGridOptions {
+            pattern: (columns, rows),
+            spacing,
+            ..Default::default()
+        };
+        let centers = find_circle_grid(&image, options).unwrap();
+        assert_eq!(centers.len(), columns * rows);
+        for m in &centers {
+            let error = nalgebra::distance(&project(&h, &m.0), &m.1);
+            assert!(error < 0.5, "{} at {:?}", error, m.0);
+        }
+    }
+}
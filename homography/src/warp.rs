@@ -0,0 +1,487 @@
+use eyre::{eyre, Result};
+use image::{ImageBuffer, Pixel};
+use nalgebra::{Matrix3, Point2};
+use num_traits::{Bounded, NumCast, ToPrimitive, Zero};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use crate::HomographyMatrix;
+
+/// How the pixel values are sampled at non-integer positions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Interpolation {
+    /// Value of the closest pixel
+    Nearest,
+    /// Linear interpolation of the 2×2 closest pixels
+    #[default]
+    Bilinear,
+    /// Cubic convolution of the 4×4 closest pixels with the same kernel as OpenCV (a = -0.75)
+    Bicubic,
+}
+
+/// Values of the pixels outside the source image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BorderMode<P> {
+    /// The given pixel value: `iiiiii|abcdefgh|iiiiiii`
+    Constant(P),
+    /// The closest edge pixel: `aaaaaa|abcdefgh|hhhhhhh`
+    Replicate,
+    /// Mirrored at the edges: `fedcba|abcdefgh|hgfedcb`
+    Reflect,
+}
+
+/// Options of [`warp_perspective`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WarpOptions<P> {
+    pub interpolation: Interpolation,
+    pub border: BorderMode<P>,
+    /// If true, the homography maps the destination pixels to the source image (like OpenCV's
+    /// `WARP_INVERSE_MAP`), otherwise it maps the source pixels to the destination.
+    pub inverse_map: bool,
+}
+
+impl<P: Pixel> Default for WarpOptions<P> {
+    /// Bilinear interpolation, zero border and forward mapping
+    fn default() -> Self {
+        let zero = vec![P::Subpixel::zero(); P::CHANNEL_COUNT as usize];
+        Self {
+            interpolation: Interpolation::default(),
+            border: BorderMode::Constant(*P::from_slice(&zero)),
+            inverse_map: false,
+        }
+    }
+}
+
+/// Applies a perspective transformation to an image.
+/// *This is supported on **crate feature `image`** only.*
+///
+/// The output has the given size. Pixel centers are at integer coordinates, as in OpenCV's
+/// `warpPerspective`. Rows are processed in parallel with the `rayon` feature. Fails if the
+/// homography has to be inverted but it's singular.
+pub fn warp_perspective<P>(
+    image: &ImageBuffer<P, Vec<P::Subpixel>>,
+    h: &HomographyMatrix,
+    width: u32,
+    height: u32,
+    options: WarpOptions<P>,
+) -> Result<ImageBuffer<P, Vec<P::Subpixel>>>
+where
+    P: Pixel + Sync + 'static,
+    P::Subpixel: Send + Sync,
+{
+    let inverse = if options.inverse_map {
+        h.0
+    } else {
+        h.0.try_inverse()
+            .ok_or_else(|| eyre!("The homography is not invertible"))?
+    };
+    Ok(warp_with(
+        image,
+        width,
+        height,
+        options.interpolation,
+        options.border,
+        |x, y| project(&inverse, x, y),
+    ))
+}
+
+/// Samples `image` at `source(x, y)` for every destination pixel.
+/// Rows are processed in parallel with the `rayon` feature.
+pub(crate) fn warp_with<P, F>(
+    image: &ImageBuffer<P, Vec<P::Subpixel>>,
+    width: u32,
+    height: u32,
+    interpolation: Interpolation,
+    border: BorderMode<P>,
+    source: F,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + Sync + 'static,
+    P::Subpixel: Send + Sync,
+    F: Fn(f64, f64) -> Option<Point2<f64>> + Sync,
+{
+    let sampler = Sampler::new(image, interpolation, border);
+    let channels = P::CHANNEL_COUNT as usize;
+    let row_len = width as usize * channels;
+    let mut buffer = vec![P::Subpixel::zero(); row_len * height as usize];
+
+    let warp_row = |(y, row): (usize, &mut [P::Subpixel])| {
+        for (x, pixel) in row.chunks_exact_mut(channels).enumerate() {
+            sampler.sample(source(x as f64, y as f64), pixel);
+        }
+    };
+    if row_len > 0 {
+        #[cfg(feature = "rayon")]
+        buffer
+            .par_chunks_mut(row_len)
+            .enumerate()
+            .for_each(warp_row);
+        #[cfg(not(feature = "rayon"))]
+        buffer.chunks_mut(row_len).enumerate().for_each(warp_row);
+    }
+
+    ImageBuffer::from_raw(width, height, buffer).expect("buffer has the size of the image")
+}
+
+pub(crate) fn project(h: &Matrix3<f64>, x: f64, y: f64) -> Option<Point2<f64>> {
+    Point2::from_homogeneous(h * nalgebra::Vector3::new(x, y, 1.0))
+        .filter(|p| p.x.is_finite() && p.y.is_finite())
+}
+
+/// Reads interpolated values of an image.
+pub(crate) struct Sampler<'a, P: Pixel> {
+    image: &'a ImageBuffer<P, Vec<P::Subpixel>>,
+    interpolation: Interpolation,
+    border: BorderMode<P>,
+    min: f32,
+    max: f32,
+    /// Whether the values are rounded when they are converted back to integer subpixels
+    round: bool,
+}
+
+impl<'a, P: Pixel + 'static> Sampler<'a, P> {
+    pub(crate) fn new(
+        image: &'a ImageBuffer<P, Vec<P::Subpixel>>,
+        interpolation: Interpolation,
+        border: BorderMode<P>,
+    ) -> Self {
+        let to_f32 = |v: P::Subpixel| v.to_f32().unwrap_or(0.0);
+        let half = <P::Subpixel as NumCast>::from(0.5).and_then(|v| v.to_f32());
+        Self {
+            image,
+            interpolation,
+            border,
+            min: to_f32(P::Subpixel::min_value()),
+            max: to_f32(P::Subpixel::max_value()),
+            round: half != Some(0.5),
+        }
+    }
+
+    /// Writes the value at `source` into `out`.
+    pub(crate) fn sample(&self, source: Option<Point2<f64>>, out: &mut [P::Subpixel]) {
+        let Some(source) = source else {
+            self.write_border(out);
+            return;
+        };
+        let (width, height) = self.image.dimensions();
+        let (Some(x), Some(y)) = (self.limit(source.x, width), self.limit(source.y, height)) else {
+            self.write_border(out);
+            return;
+        };
+        let mut sum = [0f32; 4];
+        let mut accumulate = |ix: i64, iy: i64, weight: f64| {
+            if weight == 0.0 {
+                return;
+            }
+            if let Some(pixel) = self.fetch(ix, iy) {
+                for (s, c) in sum.iter_mut().zip(pixel.channels()) {
+                    *s += weight as f32 * c.to_f32().unwrap_or(0.0);
+                }
+            }
+        };
+        match self.interpolation {
+            Interpolation::Nearest => accumulate(x.round() as i64, y.round() as i64, 1.0),
+            Interpolation::Bilinear => {
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as i64, y0 as i64);
+                accumulate(x0, y0, (1.0 - fx) * (1.0 - fy));
+                accumulate(x0 + 1, y0, fx * (1.0 - fy));
+                accumulate(x0, y0 + 1, (1.0 - fx) * fy);
+                accumulate(x0 + 1, y0 + 1, fx * fy);
+            }
+            Interpolation::Bicubic => {
+                let (x0, y0) = (x.floor(), y.floor());
+                let (wx, wy) = (cubic_weights(x - x0), cubic_weights(y - y0));
+                let (x0, y0) = (x0 as i64, y0 as i64);
+                for (j, wy) in wy.iter().enumerate() {
+                    for (i, wx) in wx.iter().enumerate() {
+                        accumulate(x0 - 1 + i as i64, y0 - 1 + j as i64, wx * wy);
+                    }
+                }
+            }
+        }
+        for (o, s) in out.iter_mut().zip(sum) {
+            *o = self.convert(s);
+        }
+    }
+
+    /// Moves a coordinate far outside the image to one that samples the same pixels, so it can be
+    /// converted to an integer and offset by the taps without overflowing. `None` if it only
+    /// samples a constant border.
+    fn limit(&self, v: f64, len: u32) -> Option<f64> {
+        // Beyond the farthest tap of bicubic interpolation
+        let (low, high) = (-3.0, len as f64 + 3.0);
+        if (low..=high).contains(&v) {
+            return Some(v);
+        }
+        match self.border {
+            BorderMode::Constant(_) => None,
+            BorderMode::Replicate => Some(v.clamp(low, high)),
+            // Reflection repeats every two image sizes
+            BorderMode::Reflect => Some(v.rem_euclid(2.0 * len as f64)),
+        }
+    }
+
+    /// Writes the sum of the `TAPS`×`TAPS` pixels from `x0`, `y0` on into `out`, weighted with
+    /// integer `weights` in row-major order that sum to `1 << weight_bits`.
+    pub(crate) fn sample_weighted<const TAPS: usize>(
+        &self,
+        origin: (i64, i64),
+        weights: &[i32],
+        weight_bits: u32,
+        out: &mut [P::Subpixel],
+    ) {
+        // Integer subpixels are accumulated exactly, as in OpenCV
+        if self.round {
+            let mut sum = [0i64; 4];
+            self.for_each_tap::<TAPS>(origin, weights, |weight, pixel| {
+                for (s, c) in sum.iter_mut().zip(pixel) {
+                    *s += weight as i64 * c.to_i64().unwrap_or(0);
+                }
+            });
+            let half = 1 << (weight_bits - 1);
+            for (o, s) in out.iter_mut().zip(sum) {
+                *o = self.convert(((s + half) >> weight_bits) as f32);
+            }
+        } else {
+            let mut sum = [0f32; 4];
+            self.for_each_tap::<TAPS>(origin, weights, |weight, pixel| {
+                for (s, c) in sum.iter_mut().zip(pixel) {
+                    *s += weight as f32 * c.to_f32().unwrap_or(0.0);
+                }
+            });
+            let scale = 1.0 / (1 << weight_bits) as f32;
+            for (o, s) in out.iter_mut().zip(sum) {
+                *o = self.convert(s * scale);
+            }
+        }
+    }
+
+    /// Calls `f` with the weight and the channels of every pixel with a non-zero weight. Reads
+    /// the raw buffer directly when all of them are inside the image.
+    #[inline(always)]
+    fn for_each_tap<const TAPS: usize>(
+        &self,
+        (x0, y0): (i64, i64),
+        weights: &[i32],
+        mut f: impl FnMut(i32, &[P::Subpixel]),
+    ) {
+        let weights = &weights[..TAPS * TAPS];
+        let (width, height) = (self.image.width() as i64, self.image.height() as i64);
+        let channels = P::CHANNEL_COUNT as usize;
+        if x0 >= 0 && y0 >= 0 && x0 + TAPS as i64 <= width && y0 + TAPS as i64 <= height {
+            let raw: &[P::Subpixel] = self.image;
+            let stride = width as usize * channels;
+            let start = y0 as usize * stride + x0 as usize * channels;
+            for (j, row_weights) in weights.chunks_exact(TAPS).enumerate() {
+                let row = &raw[start + j * stride..][..TAPS * channels];
+                for (&weight, pixel) in row_weights.iter().zip(row.chunks_exact(channels)) {
+                    f(weight, pixel);
+                }
+            }
+        } else {
+            for (ix, &weight) in weights.iter().enumerate() {
+                if weight == 0 {
+                    continue;
+                }
+                let (i, j) = ((ix % TAPS) as i64, (ix / TAPS) as i64);
+                if let Some(pixel) = self.fetch(x0 + i, y0 + j) {
+                    f(weight, pixel.channels());
+                }
+            }
+        }
+    }
+
+    /// Pixel at the given position, with the border mode applied outside the image.
+    fn fetch(&self, x: i64, y: i64) -> Option<&P> {
+        let (width, height) = (self.image.width() as i64, self.image.height() as i64);
+        let inside = (0..width).contains(&x) && (0..height).contains(&y);
+        let (x, y) = match &self.border {
+            _ if inside => (x, y),
+            BorderMode::Constant(pixel) => return Some(pixel),
+            _ if width == 0 || height == 0 => return None,
+            BorderMode::Replicate => (x.clamp(0, width - 1), y.clamp(0, height - 1)),
+            BorderMode::Reflect => (reflect(x, width), reflect(y, height)),
+        };
+        Some(self.image.get_pixel(x as u32, y as u32))
+    }
+
+    fn convert(&self, value: f32) -> P::Subpixel {
+        let value = if self.round { value.round() } else { value };
+        <P::Subpixel as NumCast>::from(value.clamp(self.min, self.max))
+            .unwrap_or_else(P::Subpixel::zero)
+    }
+
+    fn write_border(&self, out: &mut [P::Subpixel]) {
+        match &self.border {
+            BorderMode::Constant(pixel) => out.copy_from_slice(pixel.channels()),
+            _ => out.fill(P::Subpixel::zero()),
+        }
+    }
+}
+
+/// Maps an index outside `0..len` to a mirrored one inside.
+fn reflect(ix: i64, len: i64) -> i64 {
+    let ix = ix.rem_euclid(2 * len);
+    if ix >= len {
+        2 * len - ix - 1
+    } else {
+        ix
+    }
+}
+
+/// Weights of the 4 pixels around a position with fractional part `t`.
+pub(crate) fn cubic_weights(t: f64) -> [f64; 4] {
+    const A: f64 = -0.75;
+    let near = |d: f64| ((A + 2.0) * d - (A + 3.0)) * d * d + 1.0;
+    let far = |d: f64| ((A * d - 5.0 * A) * d + 8.0 * A) * d - 4.0 * A;
+    [far(1.0 + t), near(t), near(1.0 - t), far(2.0 - t)]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{warp_perspective, BorderMode, HomographyMatrix, Interpolation, WarpOptions};
+    use image::{imageops, GenericImageView, Rgb, RgbImage};
+    use nalgebra::{Matrix3, Vector2};
+
+    fn test_images() -> Vec<RgbImage> {
+        ["../test-data/image1.png", "../test-data/image2.png"]
+            .iter()
+            .map(|path| {
+                let image = image::open(path).unwrap().to_rgb8();
+                let (width, height) = image.dimensions();
+                let crop = imageops::crop_imm(&image, width / 2 - 64, height / 2 - 64, 128, 128);
+                // Smoothed, so interpolating twice doesn't change it much
+                imageops::blur(&crop.to_image(), 1.5)
+            })
+            .collect()
+    }
+
+    fn mean_abs_difference(a: &RgbImage, b: &RgbImage, margin: u32) -> f64 {
+        let (width, height) = a.dimensions();
+        let inner = a.view(margin, margin, width - 2 * margin, height - 2 * margin);
+        let mut sum = 0.0;
+        let mut count = 0.0;
+        for (x, y, pixel) in inner.pixels() {
+            let other = b.get_pixel(x + margin, y + margin);
+            for (p, o) in pixel.0.iter().zip(other.0) {
+                sum += (*p as f64 - o as f64).abs();
+                count += 1.0;
+            }
+        }
+        sum / count
+    }
+
+    #[test]
+    fn integer_shift_is_exact() {
+        let h = HomographyMatrix(Matrix3::new_translation(&Vector2::new(3.0, -5.0)));
+        for image in test_images() {
+            for interpolation in [
+                Interpolation::Nearest,
+                Interpolation::Bilinear,
+                Interpolation::Bicubic,
+            ] {
+                let options = WarpOptions {
+                    interpolation,
+                    ..Default::default()
+                };
+                let warped = warp_perspective(&image, &h, 128, 128, options).unwrap();
+                assert_eq!(warped.get_pixel(3, 0), image.get_pixel(0, 5));
+                assert_eq!(warped.get_pixel(127, 122), image.get_pixel(124, 127));
+                assert_eq!(warped.get_pixel(0, 0), &Rgb([0, 0, 0]));
+            }
+        }
+    }
+
+    #[test]
+    fn forward_and_inverse_map_round_trip() {
+        #[rustfmt::skip]
+        let h = HomographyMatrix(Matrix3::new(
+            0.95, -0.1, 8.0,
+            0.12, 1.02, -4.0,
+            0.0002, -0.0001, 1.0,
+        ));
+        for image in test_images() {
+            for interpolation in [Interpolation::Bilinear, Interpolation::Bicubic] {
+                let options = WarpOptions {
+                    interpolation,
+                    border: BorderMode::Replicate,
+                    inverse_map: false,
+                };
+                let warped = warp_perspective(&image, &h, 128, 128, options).unwrap();
+                let options = WarpOptions {
+                    inverse_map: true,
+                    ..options
+                };
+                let restored = warp_perspective(&warped, &h, 128, 128, options).unwrap();
+                let error = mean_abs_difference(&image, &restored, 24);
+                assert!(error < 2.0, "{:?}: {}", interpolation, error);
+            }
+        }
+    }
+
+    #[test]
+    fn border_modes() {
+        let image = &test_images()[0];
+        let h = HomographyMatrix(Matrix3::new_translation(&Vector2::new(-130.0, 0.0)));
+        let warp = |border| {
+            let options = WarpOptions {
+                interpolation: Interpolation::Nearest,
+                border,
+                inverse_map: true,
+            };
+            warp_perspective(image, &h, 2, 1, options).unwrap()
+        };
+        let constant = warp(BorderMode::Constant(Rgb([1, 2, 3])));
+        assert_eq!(constant.get_pixel(0, 0), &Rgb([1, 2, 3]));
+        let replicate = warp(BorderMode::Replicate);
+        assert_eq!(replicate.get_pixel(0, 0), image.get_pixel(0, 0));
+        let reflect = warp(BorderMode::Reflect);
+        // -130 is beyond the mirrored copy on the left, so it's mirrored once more to 126
+        assert_eq!(reflect.get_pixel(0, 0), image.get_pixel(126, 0));
+        assert_eq!(reflect.get_pixel(1, 0), image.get_pixel(127, 0));
+    }
+
+    #[test]
+    fn horizon_crosses_the_output() {
+        let image = RgbImage::from_fn(32, 32, |x, y| Rgb([x as u8, y as u8, 7]));
+        // The horizon is just below the row 64 of the output, where the source coordinates are
+        // beyond the range of i64
+        #[rustfmt::skip]
+        let h = HomographyMatrix(Matrix3::new(
+            1e4, 0.0, 0.0,
+            0.0, 1.0, 0.0,
+            0.0, -1.0 / 64.0, 1.0 + f64::EPSILON,
+        ));
+        for interpolation in [
+            Interpolation::Nearest,
+            Interpolation::Bilinear,
+            Interpolation::Bicubic,
+        ] {
+            for border in [
+                BorderMode::Constant(Rgb([1, 2, 3])),
+                BorderMode::Replicate,
+                BorderMode::Reflect,
+            ] {
+                let options = WarpOptions {
+                    interpolation,
+                    border,
+                    inverse_map: true,
+                };
+                let warped = warp_perspective(&image, &h, 128, 128, options).unwrap();
+                assert_eq!(warped.get_pixel(0, 0), image.get_pixel(0, 0));
+                let expected = match border {
+                    BorderMode::Constant(pixel) => pixel,
+                    BorderMode::Replicate => *image.get_pixel(31, 0),
+                    // The reflections repeat every 64 pixels, and 10000 = 156 × 64 + 16
+                    BorderMode::Reflect => *image.get_pixel(16, 0),
+                };
+                assert_eq!(warped.get_pixel(1, 0), &expected);
+                if let BorderMode::Constant(pixel) = border {
+                    assert_eq!(warped.get_pixel(1, 64), &pixel);
+                }
+            }
+        }
+    }
+}
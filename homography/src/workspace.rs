@@ -0,0 +1,108 @@
+use eyre::{eyre, Result};
+use sample_consensus::Model;
+
+use crate::{find_homography_iter, HomographyMatrix, HomographyOptions, WeightedFeatureMatch};
+
+/// Reusable scratch memory for re-fitting homographies in a hot loop.
+///
+/// The buffers grow to the largest input seen and are reused afterwards,
+/// so once warmed up the re-fit runs without heap allocations.
+#[derive(Debug, Clone, Default)]
+pub struct HomographyWorkspace {
+    /// Options of the least squares fit
+    pub options: HomographyOptions,
+    inliers: Vec<usize>,
+}
+
+impl HomographyWorkspace {
+    pub fn new(options: HomographyOptions) -> Self {
+        Self {
+            options,
+            inliers: Vec::new(),
+        }
+    }
+
+    /// Re-estimates the homography from all the matches with a residual below `threshold`.
+    ///
+    /// The indices of the inliers are kept and are available with [`Self::inliers`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "refine",
+            level = "debug",
+            skip_all,
+            fields(matches = matches.len(), inliers)
+        )
+    )]
+    pub fn refit<M>(
+        &mut self,
+        model: &HomographyMatrix,
+        matches: &[M],
+        threshold: f64,
+    ) -> Result<HomographyMatrix>
+    where
+        M: Copy + Into<WeightedFeatureMatch>,
+        HomographyMatrix: Model<M>,
+    {
+        self.inliers.clear();
+        self.inliers.extend(
+            matches
+                .iter()
+                .enumerate()
+                .filter(|(_, m)| Model::<M>::residual(model, m) < threshold)
+                .map(|(i, _)| i),
+        );
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("inliers", self.inliers.len());
+        if self.inliers.len() < 4 {
+            return Err(eyre!(
+                "Not enough inliers to refit the homography ({})",
+                self.inliers.len()
+            ));
+        }
+        find_homography_iter(self.inliers.iter().map(|&i| matches[i]), self.options)
+            .map(HomographyMatrix)
+    }
+
+    /// Indices of the inliers found by the last call of [`Self::refit`].
+    pub fn inliers(&self) -> &[usize] {
+        &self.inliers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{find_homography_slice, HomographyMatrix, HomographyWorkspace};
+    use approx::AbsDiffEq;
+    use cv_core::FeatureMatch;
+    use nalgebra::Point2;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+    use test_utils::TestData;
+
+    #[test]
+    fn refit_reuses_buffers() {
+        let mut workspace = HomographyWorkspace::default();
+        let mut rng = Pcg64::seed_from_u64(0);
+        let TestData { matches, h: h_src } = TestData::from_rng(&mut rng, 64, 100.0, 0.0);
+        let mut matches = matches;
+        // Some gross outliers
+        for i in 0..8 {
+            let p = Point2::new(i as f64 * 10.0, 5.0);
+            matches.push(FeatureMatch(p, Point2::new(500.0, -300.0 + i as f64)));
+        }
+        let rough = HomographyMatrix(find_homography_slice(&matches[..16]).unwrap());
+
+        let h = workspace.refit(&rough, &matches, 1.0).unwrap();
+        assert!(h_src.abs_diff_eq(&h.0, 0.000001));
+        assert_eq!(workspace.inliers(), (0..64).collect::<Vec<_>>());
+
+        let buffer = workspace.inliers().as_ptr();
+        workspace.refit(&h, &matches[..32], 1.0).unwrap();
+        assert_eq!(
+            workspace.inliers().as_ptr(),
+            buffer,
+            "buffer was reallocated"
+        );
+    }
+}
@@ -2,6 +2,7 @@ use cv_core::FeatureMatch;
 use itertools::{zip, Itertools};
 use nalgebra::{Matrix3, Point2};
 use rand::Rng;
+use rand_distr::{Distribution, Normal};
 use std::f64::consts::PI;
 
 pub struct TestData {
@@ -11,8 +12,22 @@ pub struct TestData {
 
 impl TestData {
     pub fn new(match_count: usize) -> Self {
-        let img_size = 100.0;
-        let mut rng = rand::thread_rng();
+        Self::with_noise(match_count, 100.0, 0.0)
+    }
+
+    /// Points are spread over an `img_size`×`img_size` image and the destination points are
+    /// perturbed with gaussian noise of `noise_std` standard deviation. `h` is the noiseless transform.
+    pub fn with_noise(match_count: usize, img_size: f64, noise_std: f64) -> Self {
+        Self::from_rng(&mut rand::thread_rng(), match_count, img_size, noise_std)
+    }
+
+    /// Like [`Self::with_noise`], but drawn from `rng`.
+    pub fn from_rng<R: Rng>(
+        rng: &mut R,
+        match_count: usize,
+        img_size: f64,
+        noise_std: f64,
+    ) -> Self {
         let src = (0..match_count)
             .map(|_| {
                 Point2::new(rng.gen_range(0.0..img_size), rng.gen_range(0.0..img_size))
@@ -28,7 +43,16 @@ impl TestData {
             f64::sin(fi), f64::cos(fi), ty,
             0.0, 0.0, 1.0
         );
-        let dst = src.iter().map(|p| h * p).collect_vec();
+        let noise = Normal::new(0.0, noise_std).unwrap();
+        let dst = src
+            .iter()
+            .map(|p| {
+                let mut p = h * p;
+                p.x += noise.sample(rng);
+                p.y += noise.sample(rng);
+                p
+            })
+            .collect_vec();
         let matches = zip(src, dst)
             .map(|(a, b)| {
                 FeatureMatch(
@@ -39,4 +63,56 @@ impl TestData {
             .collect_vec();
         Self { matches, h }
     }
+
+    /// Like [`Self::with_noise`], but drawn from `rng` and with a projective `h`, whose perspective
+    /// terms scale with the image so the images of the points are foreshortened by up to ~30%.
+    pub fn projective_with_noise<R: Rng>(
+        rng: &mut R,
+        match_count: usize,
+        img_size: f64,
+        noise_std: f64,
+    ) -> Self {
+        let fi = rng.gen_range(0.0..PI * 2.0);
+        let (s, c) = fi.sin_cos();
+        let tx = rng.gen_range(0.0..img_size * 0.1);
+        let ty = rng.gen_range(0.0..img_size * 0.1);
+        let px = rng.gen_range(0.1..0.3) / img_size;
+        let py = rng.gen_range(-0.3..-0.1) / img_size;
+        #[rustfmt::skip]
+        let h = Matrix3::new(
+            1.1 * c, -s, tx,
+            s, 0.9 * c, ty,
+            px, py, 1.0
+        );
+        let noise = Normal::new(0.0, noise_std).unwrap();
+        let matches = (0..match_count)
+            .map(|_| {
+                let a = Point2::new(rng.gen_range(0.0..img_size), rng.gen_range(0.0..img_size));
+                let mut b = Point2::from_homogeneous(h * a.to_homogeneous()).unwrap();
+                b.x += noise.sample(rng);
+                b.y += noise.sample(rng);
+                FeatureMatch(a, b)
+            })
+            .collect_vec();
+        Self { matches, h }
+    }
+
+    /// The last `outlier_ratio` part of the matches have random destination points.
+    pub fn with_outliers(match_count: usize, outlier_ratio: f64) -> Self {
+        Self::with_outliers_from_rng(&mut rand::thread_rng(), match_count, outlier_ratio)
+    }
+
+    /// Like [`Self::with_outliers`], but drawn from `rng`.
+    pub fn with_outliers_from_rng<R: Rng>(
+        rng: &mut R,
+        match_count: usize,
+        outlier_ratio: f64,
+    ) -> Self {
+        let mut data = Self::from_rng(rng, match_count, 100.0, 0.0);
+        let outlier_count = (match_count as f64 * outlier_ratio).round() as usize;
+        for FeatureMatch(_, b) in data.matches.iter_mut().rev().take(outlier_count) {
+            *b = Point2::new(rng.gen_range(0.0..100.0), rng.gen_range(0.0..100.0));
+        }
+        data
+    }
 }